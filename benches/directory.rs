@@ -0,0 +1,152 @@
+//! Peer lookups and broadcasts while the connection table churns. Each churn step adds a
+//! connection, registers it and removes it again, the way a short-lived peer would. Reader
+//! and broadcast tasks run concurrently with the churn on a multi-threaded runtime, so the
+//! latencies include waiting on the directory lock. Run with `cargo bench --bench directory`.
+
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+
+use tcp_rust::{
+    bans::{BanConfig, Bans},
+    membership::{Membership, MembershipConfig},
+    network::DialConfig,
+    peer_manager::{ConnLimits, Eviction, Overlay, PeerEntry, PeerManagerHandle, PeerSummary, generate_unique_id},
+    rate_limit::RateLimits,
+    send_queue::SendPolicy,
+};
+use tokio::{io::{AsyncReadExt, duplex}, sync::mpsc, task::JoinHandle, time};
+
+/// Broadcasts per second the sender aims for.
+const BROADCAST_RATE: u64 = 2000;
+const CHURN: usize = 500;
+
+fn node(peers: usize) -> Arc<PeerManagerHandle> {
+    let summary = PeerSummary { remote_addr: None, listen_addr: Some("127.0.0.1:1".into()), node_id: Some(generate_unique_id()), uname: None };
+    let (frontend_tx, mut frontend_rx) = mpsc::channel(1000);
+    tokio::spawn(async move { while frontend_rx.recv().await.is_some() {} });
+    let dial = DialConfig { concurrency: 8, connect_timeout: Duration::from_secs(3), tls_handshake_timeout: Duration::from_secs(3) };
+    let limits = ConnLimits {
+        max_inbound: peers * 2, max_outbound: peers * 2, max_peers: peers * 2, eviction: Eviction::None,
+        max_frame_size: 64 * 1024, send_queue: 1024, send_policy: SendPolicy::DropOldest,
+    };
+    let rate_limits = RateLimits { msg_rate: 1e6, msg_burst: 1e6, byte_rate: 1e9, byte_burst: 1e9, conn_rate: 1e6, conn_burst: 1e6 };
+    let bans = Bans::load(BanConfig { threshold: -100, duration: Duration::from_secs(60), path: std::env::temp_dir().join(format!("bench-bans-{}.json", generate_unique_id())) });
+    // HyParView keeps the peer manager out of PEERS exchanges, which cost O(n) sends per JOIN by design.
+    let overlay = Overlay::HyParView(Membership::new(MembershipConfig::new(5, 30, Duration::from_secs(3600))));
+    PeerManagerHandle::new(summary, frontend_tx, false, None, dial, limits, rate_limits, bans, overlay, None)
+}
+
+/// Adds and registers a peer over an in-memory stream whose far end is drained.
+async fn connect(pm: &Arc<PeerManagerHandle>, i: usize) -> (String, String) {
+    let (ours, mut theirs) = duplex(64 * 1024);
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = theirs.read(&mut buf).await && n > 0 {}
+    });
+    let conn_id = generate_unique_id();
+    let node_id = generate_unique_id();
+    let summary = PeerSummary { remote_addr: None, listen_addr: Some(format!("10.0.{}.{}:4000", i / 256, i % 256)), node_id: None, uname: None };
+    let entry = PeerEntry::new(conn_id.clone(), summary.clone(), ours, false, pm.peer_limiter(), pm.send_queue(), pm.events_tx());
+    pm.add_entry(conn_id.clone(), entry).await.unwrap();
+    pm.register_node(conn_id.clone(), PeerSummary { node_id: Some(node_id.clone()), ..summary }).await.unwrap();
+    (conn_id, node_id)
+}
+
+/// Latency samples in microseconds.
+struct Latencies(Vec<f64>);
+
+impl Latencies {
+    fn percentile(&mut self, p: f64) -> f64 {
+        if self.0.is_empty() {
+            return 0.0;
+        }
+        self.0.sort_by(f64::total_cmp);
+        self.0[((self.0.len() - 1) as f64 * p) as usize]
+    }
+}
+
+fn timed(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1e6
+}
+
+/// Looks up random registered peers until `stop` is set.
+fn reader(pm: Arc<PeerManagerHandle>, ids: Arc<Vec<String>>, stop: Arc<AtomicBool>) -> JoinHandle<Latencies> {
+    tokio::spawn(async move {
+        let mut samples = Vec::new();
+        let mut i = 0;
+        while !stop.load(Ordering::Relaxed) {
+            let start = Instant::now();
+            std::hint::black_box(pm.get_peer(ids[i % ids.len()].clone()));
+            samples.push(timed(start));
+            i += 1;
+            // Yield now and then so the reader doesn't starve the churn task of a worker.
+            if i % 64 == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+        Latencies(samples)
+    })
+}
+
+/// Broadcasts a chat-sized frame at `BROADCAST_RATE` until `stop` is set.
+fn broadcaster(pm: Arc<PeerManagerHandle>, stop: Arc<AtomicBool>) -> JoinHandle<Latencies> {
+    tokio::spawn(async move {
+        let mut samples = Vec::new();
+        let per_tick = BROADCAST_RATE / 1000;
+        let mut ticker = time::interval(Duration::from_millis(1));
+        ticker.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+        while !stop.load(Ordering::Relaxed) {
+            ticker.tick().await;
+            for _ in 0..per_tick {
+                let start = Instant::now();
+                pm.broadcast(format!("MSG|{}", "x".repeat(100))).await;
+                samples.push(timed(start));
+            }
+        }
+        Latencies(samples)
+    })
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
+async fn main() {
+    println!("{:>6} {:>8} {:>8} | {:>9} {:>9} {:>9} | {:>9} {:>9} {:>9} {:>7}",
+        "peers", "churn", "churn", "get_peer", "get_peer", "get_peer", "bcast", "bcast", "bcast", "bcast");
+    println!("{:>6} {:>8} {:>8} | {:>9} {:>9} {:>9} | {:>9} {:>9} {:>9} {:>7}",
+        "", "us p50", "us p99", "us p50", "us p99", "us max", "us p50", "us p99", "us max", "msgs/s");
+    for peers in [100, 300, 1000] {
+        let pm = node(peers);
+        let mut ids = Vec::with_capacity(peers);
+        for i in 0..peers {
+            ids.push(connect(&pm, i).await.1);
+        }
+        let ids = Arc::new(ids);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..2).map(|_| reader(pm.clone(), ids.clone(), stop.clone())).collect();
+        let sender = broadcaster(pm.clone(), stop.clone());
+
+        let start = Instant::now();
+        let mut churn = Vec::with_capacity(CHURN);
+        for i in 0..CHURN {
+            let step = Instant::now();
+            let (conn_id, _) = connect(&pm, peers + i).await;
+            pm.remove_conn(conn_id).await;
+            churn.push(timed(step));
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        stop.store(true, Ordering::Relaxed);
+
+        let mut churn = Latencies(churn);
+        let mut lookups = Latencies(Vec::new());
+        for reader in readers {
+            lookups.0.extend(reader.await.unwrap().0);
+        }
+        let mut sends = sender.await.unwrap();
+        let rate = sends.0.len() as f64 / elapsed;
+
+        println!("{:>6} {:>8.1} {:>8.1} | {:>9.2} {:>9.2} {:>9.0} | {:>9.1} {:>9.1} {:>9.0} {:>7.0}",
+            peers, churn.percentile(0.5), churn.percentile(0.99),
+            lookups.percentile(0.5), lookups.percentile(0.99), lookups.percentile(1.0),
+            sends.percentile(0.5), sends.percentile(0.99), sends.percentile(1.0), rate);
+        pm.shutdown(Duration::from_secs(1)).await;
+    }
+}
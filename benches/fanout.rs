@@ -0,0 +1,55 @@
+//! Broadcast throughput: one sender pushes shared frames into N peer queues, each drained
+//! by the real writer task into an in-memory socket. Run with `cargo bench --bench fanout`.
+
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use tcp_rust::{peer_manager::PeerEntry, send_queue::{Frame, SendPolicy, SendQueue}, stats::Stats};
+use tokio::{io::{AsyncReadExt, duplex}, sync::Notify};
+
+const QUEUE: usize = 1024;
+
+/// Broadcasts `msgs` frames of `size` bytes to `peers` queues and returns how long it took
+/// until every reader had seen every byte.
+async fn fan_out(peers: usize, msgs: usize, size: usize) -> Duration {
+    let stats = Arc::new(Stats::default());
+    let mut queues = Vec::with_capacity(peers);
+    let mut readers = Vec::with_capacity(peers);
+    for _ in 0..peers {
+        let (ours, mut theirs) = duplex(64 * 1024);
+        let queue = SendQueue::new(QUEUE, SendPolicy::Block, stats.clone());
+        PeerEntry::spawn_writer(ours, queue.clone(), Arc::new(Notify::new()), size);
+        queues.push(queue);
+        readers.push(tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut total = 0;
+            while let Ok(n) = theirs.read(&mut buf).await && n > 0 {
+                total += n;
+            }
+            total
+        }));
+    }
+
+    let frame: Frame = "x".repeat(size).into();
+    let start = Instant::now();
+    for _ in 0..msgs {
+        for queue in &queues {
+            queue.push(frame.clone()).await;
+        }
+    }
+    for queue in &queues {
+        queue.finish();
+    }
+    for reader in readers {
+        assert_eq!(reader.await.unwrap(), msgs * (size + 1));
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    println!("{:>6} {:>6} {:>8} {:>14} {:>16}", "bytes", "peers", "msgs", "msgs/s", "frames out/s");
+    for (size, peers, msgs) in [(100, 1, 50_000), (100, 8, 50_000), (100, 32, 50_000), (100, 128, 10_000), (2048, 8, 20_000)] {
+        let elapsed = fan_out(peers, msgs, size).await.as_secs_f64();
+        println!("{:>6} {:>6} {:>8} {:>14.0} {:>16.0}", size, peers, msgs, msgs as f64 / elapsed, (msgs * peers) as f64 / elapsed);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::Context;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// What an API token may do. `Full` includes everything `Read` allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// GET requests and receiving WebSocket events.
+    Read,
+    /// Also sending messages and changing peers and bans.
+    Full,
+}
+
+/// Bearer tokens for the HTTP API, kept in a file next to the ban list.
+#[derive(Serialize, Deserialize)]
+pub struct ApiKeys {
+    pub read: String,
+    pub full: String,
+}
+
+fn generate_token() -> String {
+    let bytes: [u8; 24] = rand::rng().random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares without stopping at the first differing byte, so timing doesn't leak a prefix.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl ApiKeys {
+    /// Reads the keys from `path`, or generates both and writes them there readable only by us.
+    pub fn load_or_create(path: &Path) -> anyhow::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let keys: Self = serde_json::from_slice(&bytes)
+                    .with_context(|| format!("Invalid API key file {}", path.display()))?;
+                if keys.read.is_empty() || keys.full.is_empty() {
+                    anyhow::bail!("API key file {} has an empty key", path.display());
+                }
+                Ok(keys)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let keys = Self { read: generate_token(), full: generate_token() };
+                let mut file = fs::OpenOptions::new();
+                file.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+                file.open(path)
+                    .and_then(|mut f| f.write_all(&serde_json::to_vec_pretty(&keys)?))
+                    .with_context(|| format!("Failed to write API keys to {}", path.display()))?;
+                info!("Generated API keys in {}", path.display());
+                Ok(keys)
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn scope_of(&self, token: &str) -> Option<Scope> {
+        if same_token(token, &self.full) {
+            Some(Scope::Full)
+        } else if same_token(token, &self.read) {
+            Some(Scope::Read)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("test-api-keys-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn tokens_compare_by_content_and_length() {
+        assert!(same_token("abc", "abc"));
+        assert!(!same_token("abc", "abd"));
+        assert!(!same_token("abc", "xbc"));
+        assert!(!same_token("abc", "abcd"));
+        assert!(!same_token("", "a"));
+        assert!(same_token("", ""));
+    }
+
+    #[test]
+    fn scope_follows_the_matching_key() {
+        let keys = ApiKeys { read: "r".repeat(48), full: "f".repeat(48) };
+        assert_eq!(keys.scope_of(&"f".repeat(48)), Some(Scope::Full));
+        assert_eq!(keys.scope_of(&"r".repeat(48)), Some(Scope::Read));
+        assert_eq!(keys.scope_of(&"r".repeat(47)), None);
+        assert_eq!(keys.scope_of(""), None);
+        assert!(Scope::Read < Scope::Full);
+    }
+
+    #[test]
+    fn generated_keys_are_saved_privately_and_loaded_again() {
+        let path = path();
+        let keys = ApiKeys::load_or_create(&path).unwrap();
+        assert_eq!(keys.read.len(), 48);
+        assert_ne!(keys.read, keys.full);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let loaded = ApiKeys::load_or_create(&path).unwrap();
+        assert_eq!((loaded.read, loaded.full), (keys.read, keys.full));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn empty_or_invalid_key_files_are_rejected() {
+        let path = path();
+        fs::write(&path, r#"{"read":"","full":"x"}"#).unwrap();
+        assert!(ApiKeys::load_or_create(&path).is_err());
+        fs::write(&path, "not json").unwrap();
+        assert!(ApiKeys::load_or_create(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{fs, net::IpAddr, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// What a peer did wrong, with the points it costs.
+#[derive(Clone, Copy, Debug)]
+pub enum Violation {
+    /// A line that matches no known frame.
+    UnknownFrame,
+    /// A known frame whose payload did not parse.
+    InvalidPayload,
+    /// Kept exceeding its message or byte rate limit.
+    Flooding,
+    /// Sent a line longer than the maximum frame size.
+    Oversized,
+}
+
+impl Violation {
+    pub fn penalty(self) -> i64 {
+        match self {
+            Violation::UnknownFrame => 10,
+            Violation::InvalidPayload => 20,
+            Violation::Flooding => 25,
+            Violation::Oversized => 50,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Violation::UnknownFrame => "unknown frame",
+            Violation::InvalidPayload => "invalid payload",
+            Violation::Flooding => "flooding",
+            Violation::Oversized => "oversized frame",
+        }
+    }
+}
+
+/// Scores start at 0, grow by one per valid frame up to this cap and shrink on violations.
+pub const SCORE_MAX: i64 = 100;
+
+#[derive(Clone, Debug)]
+pub struct BanConfig {
+    /// Peers whose score drops to this value are disconnected and banned.
+    pub threshold: i64,
+    pub duration: Duration,
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    pub node_id: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub reason: String,
+    /// Unix time in seconds when the ban expires.
+    pub until: u64,
+}
+
+/// Time-limited bans by node id and IP. Changes are made in memory and written to disk by
+/// the task from `spawn_writer`, so callers holding the lock never wait on the file system.
+pub struct Bans {
+    cfg: BanConfig,
+    bans: Vec<Ban>,
+    /// Signalled on every change that should reach the file.
+    dirty: Arc<Notify>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn write(path: &Path, json: anyhow::Result<Vec<u8>>) {
+    if let Err(e) = json.and_then(|json| fs::write(path, json).map_err(anyhow::Error::from)) {
+        warn!("Failed to save ban list to {}: {}", path.display(), e);
+    }
+}
+
+/// Saves `bans` after every change, off the async workers. Changes made while a write is in
+/// progress are coalesced into the next one, which always writes the latest list.
+pub fn spawn_writer(bans: Arc<Mutex<Bans>>) {
+    let dirty = bans.lock().unwrap().dirty.clone();
+    tokio::spawn(async move {
+        loop {
+            dirty.notified().await;
+            let (path, json) = {
+                let bans = bans.lock().unwrap();
+                (bans.cfg.path.clone(), bans.to_json())
+            };
+            let _ = tokio::task::spawn_blocking(move || write(&path, json)).await;
+        }
+    });
+}
+
+impl Bans {
+    pub fn load(cfg: BanConfig) -> Self {
+        let bans: Vec<Ban> = match fs::read(&cfg.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable ban list {}: {}", cfg.path.display(), e);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+
+        let mut bans = Self { cfg, bans, dirty: Arc::default() };
+        bans.expire();
+        if !bans.bans.is_empty() {
+            info!("Loaded {} bans from {}", bans.bans.len(), bans.cfg.path.display());
+        }
+        bans
+    }
+
+    /// Replaces the list with what the file holds now. An unreadable file leaves it as it was.
+    pub fn reload(&mut self) -> anyhow::Result<usize> {
+        let bans: Vec<Ban> = match fs::read(&self.cfg.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Invalid ban list {}", self.cfg.path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.cfg.path.display())),
+        };
+        self.bans = bans;
+        self.expire();
+        info!("Reloaded {} bans from {}", self.bans.len(), self.cfg.path.display());
+        Ok(self.bans.len())
+    }
+
+    pub fn threshold(&self) -> i64 {
+        self.cfg.threshold
+    }
+
+    fn expire(&mut self) {
+        let now = now();
+        self.bans.retain(|b| b.until > now);
+    }
+
+    fn to_json(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(&self.bans)?)
+    }
+
+    /// Has the writer task save the list.
+    fn save(&self) {
+        self.dirty.notify_one();
+    }
+
+    /// Bans a node id and IP for the configured duration. Loopback addresses are shared by
+    /// every node on the host, so only the node id is banned for them.
+    pub fn ban(&mut self, node_id: Option<String>, ip: Option<IpAddr>, reason: &str) -> Option<Ban> {
+        let ip = ip.filter(|ip| !ip.is_loopback());
+        if node_id.is_none() && ip.is_none() {
+            return None;
+        }
+
+        self.expire();
+        let ban = Ban {
+            node_id,
+            ip,
+            reason: reason.to_string(),
+            until: now() + self.cfg.duration.as_secs(),
+        };
+        self.bans.push(ban.clone());
+        self.save();
+        Some(ban)
+    }
+
+    /// Writes the current list to disk right away, for shutdown when the writer task may
+    /// not get to run again.
+    pub fn flush(&mut self) {
+        self.expire();
+        write(&self.cfg.path, self.to_json());
+    }
+
+    pub fn is_banned_node(&mut self, node_id: &str) -> bool {
+        self.expire();
+        self.bans.iter().any(|b| b.node_id.as_deref() == Some(node_id))
+    }
+
+    pub fn is_banned_ip(&mut self, ip: IpAddr) -> bool {
+        self.expire();
+        self.bans.iter().any(|b| b.ip == Some(ip))
+    }
+
+    pub fn list(&mut self) -> Vec<Ban> {
+        self.expire();
+        self.bans.clone()
+    }
+
+    /// Lifts every ban matching `key`, a node id or an IP. Returns how many were removed.
+    pub fn unban(&mut self, key: &str) -> usize {
+        let ip = key.parse::<IpAddr>().ok();
+        let before = self.bans.len();
+        self.bans.retain(|b| b.node_id.as_deref() != Some(key) && (ip.is_none() || b.ip != ip));
+        let removed = before - self.bans.len();
+        if removed > 0 {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.bans.clear();
+        self.save();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Deletes a test's ban list file when dropped, so failing tests don't leave it behind either.
+    struct RemoveOnDrop(PathBuf);
+
+    impl Drop for RemoveOnDrop {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// The file `bans` is saved to, once it holds the current list.
+    pub(crate) fn saved_file(bans: &Bans) -> Option<PathBuf> {
+        let saved = fs::read(&bans.cfg.path).ok()?;
+        (saved == bans.to_json().ok()?).then(|| bans.cfg.path.clone())
+    }
+
+    fn bans(duration: Duration) -> (Bans, RemoveOnDrop) {
+        let path = std::env::temp_dir().join(format!("test-bans-{}.json", uuid::Uuid::new_v4()));
+        (Bans::load(BanConfig { threshold: -100, duration, path: path.clone() }), RemoveOnDrop(path))
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn worse_violations_cost_more() {
+        let order = [Violation::UnknownFrame, Violation::InvalidPayload, Violation::Flooding, Violation::Oversized];
+        assert!(order.windows(2).all(|w| w[0].penalty() < w[1].penalty()));
+        assert!(order.iter().all(|v| v.penalty() > 0 && v.penalty() < SCORE_MAX));
+    }
+
+    #[test]
+    fn bans_node_and_ip() {
+        let (mut bans, _file) = bans(Duration::from_secs(60));
+        let ban = bans.ban(Some("a".into()), ip("10.0.0.1"), "flooding").unwrap();
+        assert_eq!(ban.reason, "flooding");
+        assert!(bans.is_banned_node("a"));
+        assert!(bans.is_banned_ip("10.0.0.1".parse().unwrap()));
+        assert!(!bans.is_banned_node("b"));
+        assert!(!bans.is_banned_ip("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn loopback_is_never_banned() {
+        let (mut bans, _file) = bans(Duration::from_secs(60));
+        let ban = bans.ban(Some("a".into()), ip("127.0.0.1"), "flooding").unwrap();
+        assert_eq!(ban.ip, None);
+        assert!(bans.is_banned_node("a"));
+        assert!(!bans.is_banned_ip("127.0.0.1".parse().unwrap()));
+
+        // Nothing left to ban without a node id.
+        assert!(bans.ban(None, ip("::1"), "flooding").is_none());
+        assert_eq!(bans.list().len(), 1);
+    }
+
+    #[test]
+    fn bans_expire() {
+        let (mut bans, _file) = bans(Duration::ZERO);
+        bans.ban(Some("a".into()), ip("10.0.0.1"), "flooding");
+        assert!(!bans.is_banned_node("a"));
+        assert!(!bans.is_banned_ip("10.0.0.1".parse().unwrap()));
+        assert!(bans.list().is_empty());
+    }
+
+    #[test]
+    fn unban_by_node_id_or_ip() {
+        let (mut bans, _file) = bans(Duration::from_secs(60));
+        bans.ban(Some("a".into()), ip("10.0.0.1"), "flooding");
+        bans.ban(Some("b".into()), ip("10.0.0.2"), "flooding");
+        bans.ban(Some("c".into()), None, "flooding");
+
+        assert_eq!(bans.unban("a"), 1);
+        assert_eq!(bans.unban("10.0.0.2"), 1);
+        assert_eq!(bans.unban("10.0.0.3"), 0);
+        assert!(!bans.is_banned_node("a") && !bans.is_banned_node("b"));
+        assert!(bans.is_banned_node("c"));
+
+        bans.clear();
+        assert!(bans.list().is_empty());
+    }
+
+    #[test]
+    fn flushed_list_loads_again() {
+        let (mut bans, _file) = bans(Duration::from_secs(60));
+        bans.ban(Some("a".into()), ip("10.0.0.1"), "flooding");
+        bans.ban(Some("b".into()), None, "oversized frame");
+        bans.flush();
+
+        let mut loaded = Bans::load(bans.cfg.clone());
+        assert!(loaded.is_banned_node("a") && loaded.is_banned_node("b"));
+        assert!(loaded.is_banned_ip("10.0.0.1".parse().unwrap()));
+        assert_eq!(loaded.list().len(), 2);
+    }
+
+    #[test]
+    fn unreadable_file_is_ignored_on_load_and_rejected_on_reload() {
+        let (mut bans, _file) = bans(Duration::from_secs(60));
+        bans.ban(Some("a".into()), None, "flooding");
+        fs::write(&bans.cfg.path, "not json").unwrap();
+
+        assert!(bans.reload().is_err());
+        assert!(bans.is_banned_node("a"));
+        assert!(Bans::load(bans.cfg.clone()).list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn writer_saves_changes() {
+        let (bans, _file) = bans(Duration::from_secs(60));
+        let bans = Arc::new(Mutex::new(bans));
+        let path = bans.lock().unwrap().cfg.path.clone();
+        spawn_writer(bans.clone());
+
+        bans.lock().unwrap().ban(Some("a".into()), None, "flooding");
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !fs::read_to_string(&path).is_ok_and(|s| s.contains("\"a\"")) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("ban list not written");
+
+        bans.lock().unwrap().unban("a");
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while fs::read_to_string(&path).is_ok_and(|s| s.contains("\"a\"")) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("unban not written");
+    }
+}
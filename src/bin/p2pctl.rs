@@ -0,0 +1,379 @@
+//! Manages a running node through its HTTP API.
+
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use futures::{SinkExt, StreamExt};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde_json::{json, Value};
+use rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore};
+use tokio_tungstenite::{tungstenite::{client::IntoClientRequest, http::header::AUTHORIZATION, Message}, Connector};
+
+#[derive(Parser)]
+#[command(name = "p2pctl", about = "Control a running tcp_rust node")]
+struct Args {
+    /// The node's API as host:port or an http(s):// URL; by default on its --port + 100
+    #[arg(long, default_value = "127.0.0.1:8100")]
+    api: String,
+    /// Certificate to trust for an https:// API, such as the node's tls/cert.der
+    #[arg(long, default_value = "tls/cert.der")]
+    ca_cert: PathBuf,
+    /// Print the API's JSON instead of text
+    #[arg(long, default_value_t = false)]
+    json: bool,
+    /// API token; defaults to the full access key in --key-file
+    #[arg(long, env = "P2P_API_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// The node's API key file, read when no --token is given
+    #[arg(long, default_value = "api_keys.json")]
+    key_file: PathBuf,
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Show the node's id, name and listener addresses
+    Node,
+    /// List connected peers
+    Peers,
+    /// Dial a peer at host:port
+    Connect { addr: String },
+    /// Drop a peer by name, node id or id prefix
+    Disconnect {
+        node: String,
+        /// Also ban its node id and IP
+        #[arg(long, default_value_t = false)]
+        ban: bool,
+        /// Reason recorded with the ban
+        #[arg(long, requires = "ban")]
+        reason: Option<String>,
+    },
+    /// Show one peer's connection
+    Peer { node: String },
+    /// Send a chat message to every peer, or privately to one
+    Send {
+        /// Send privately to this peer, by name, node id or id prefix
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(required = true, num_args = 1..)]
+        text: Vec<String>,
+    },
+    /// Print events as they happen until interrupted
+    Events,
+    /// Show node counters
+    Stats,
+    /// List current bans
+    Bans,
+    /// Re-read the ban list file. Bans are the only thing a running node reloads; other
+    /// settings are command-line flags and need a restart
+    ReloadBans,
+}
+
+/// A non-2xx answer from the node, with the status kept for callers that handle some of them.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// The status of a failed call, if the node answered at all.
+fn status(e: &anyhow::Error) -> Option<StatusCode> {
+    e.downcast_ref::<ApiError>().map(|e| e.status)
+}
+
+struct Api {
+    client: Client,
+    base: String,
+    token: Option<String>,
+}
+
+impl Api {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let req = self.client.request(method, format!("{}{}", self.base, path));
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> anyhow::Result<Response> {
+        let mut req = self.request(method, path);
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+        self.send(req).await
+    }
+
+    /// Sends `req` and turns a non-2xx answer into an `ApiError` carrying the node's message.
+    async fn send(&self, req: RequestBuilder) -> anyhow::Result<Response> {
+        let resp = req.send().await.with_context(|| format!("Node API at {} is not reachable", self.base))?;
+        let status = resp.status();
+        if !status.is_success() {
+            let message = resp.text().await.unwrap_or_default().trim().to_string();
+            return Err(ApiError { status, message }.into());
+        }
+        Ok(resp)
+    }
+
+    async fn json(&self, path: &str) -> anyhow::Result<Value> {
+        Ok(self.call(Method::GET, path, None).await?.json().await?)
+    }
+
+    /// Peers we hold a connection to. With SWIM on, /peers also lists members we don't.
+    async fn connected(&self) -> anyhow::Result<Vec<Value>> {
+        let peers = self.json("/peers").await?.as_array().cloned().unwrap_or_default();
+        Ok(peers.into_iter().filter(|p| p.get("queue").is_some()).collect())
+    }
+}
+
+fn text(value: &Value, key: &str) -> String {
+    match value.get(key) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => "?".to_string(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// A peer's node id from its name, full id or an id prefix that matches one peer.
+fn resolve(peers: &[Value], node: &str) -> anyhow::Result<String> {
+    let ids = |matches: &dyn Fn(&Value) -> bool| -> Vec<String> {
+        peers.iter().filter(|p| matches(p)).map(|p| text(p, "node_id")).collect()
+    };
+    let mut found = ids(&|p| text(p, "node_id") == node);
+    if found.is_empty() {
+        found = ids(&|p| text(p, "uname") == node);
+    }
+    if found.is_empty() {
+        found = ids(&|p| text(p, "node_id").starts_with(node));
+    }
+    match found.as_slice() {
+        [id] => Ok(id.clone()),
+        [] => anyhow::bail!("no connected peer called {}", node),
+        _ => anyhow::bail!("{} matches {} peers, use a node id", node, found.len()),
+    }
+}
+
+/// The `uname` in `value`, or the start of the node id under `id_key` when there is none.
+fn name(value: &Value, uname_key: &str, id_key: &str) -> String {
+    match value.get(uname_key).and_then(Value::as_str) {
+        Some(uname) => uname.to_string(),
+        None => text(value, id_key).chars().take(8).collect(),
+    }
+}
+
+/// One line per frontend event, like the node's console shows them.
+fn event_line(event: &Value) -> String {
+    let peer = || name(&event["peer"], "uname", "node_id");
+    match text(event, "kind").as_str() {
+        "message_received" => format!("{}: {}", name(event, "uname", "from"), text(event, "content")),
+        "direct_message" => format!("{} (private): {}", name(event, "uname", "from"), text(event, "content")),
+        "message_sent" if event["to"].is_string() => format!("You -> {} (private): {}", name(event, "to_uname", "to"), text(event, "content")),
+        "message_sent" => format!("You: {}", text(event, "content")),
+        "peer_renamed" => format!("* {} is now known as {}", name(event, "old", "node_id"), text(event, "uname")),
+        "peer_joined" => format!("* {} joined", peer()),
+        "peer_reconnected" => format!("* {} reconnected", peer()),
+        "peer_disconnected" => format!("* {} disconnected", peer()),
+        "peer_left" => format!("* {} left", peer()),
+        "peer_banned" => format!("* banned {} until {} ({})",
+            event.get("node_id").and_then(Value::as_str).map_or_else(|| text(event, "ip"), str::to_string),
+            text(event, "until"), text(event, "reason")),
+        "connect_failed" => format!("! could not connect to {}: {}", text(event, "addr"), text(event, "error")),
+        "handshake_failed" => format!("! handshake with {} failed: {}", text(event, "addr"), text(event, "error")),
+        "rate_limited" => format!("! {} exceeded its {} rate limit, {} dropped", text(event, "source"), text(event, "limit"), text(event, "dropped")),
+        _ => event.to_string(),
+    }
+}
+
+/// The token from `--token`, or the full access key of a node running in this directory.
+fn token(args: &Args) -> anyhow::Result<Option<String>> {
+    if args.token.is_some() {
+        return Ok(args.token.clone());
+    }
+    match std::fs::read(&args.key_file) {
+        Ok(bytes) => {
+            let keys: Value = serde_json::from_slice(&bytes)
+                .with_context(|| format!("Invalid API key file {}", args.key_file.display()))?;
+            Ok(keys.get("full").and_then(Value::as_str).map(str::to_string))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Trusts only `path`, since nodes serve self-signed certificates.
+fn tls_config(path: &Path) -> anyhow::Result<Arc<ClientConfig>> {
+    let cert = std::fs::read(path)
+        .with_context(|| format!("Failed to read {}, pass the node's certificate with --ca-cert", path.display()))?;
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(cert))
+        .with_context(|| format!("Invalid certificate {}", path.display()))?;
+    Ok(Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()))
+}
+
+async fn tail_events(base: &str, tls: Option<Arc<ClientConfig>>, token: Option<&str>, json: bool) -> anyhow::Result<()> {
+    let url = format!("{}/ws", base.replacen("http", "ws", 1));
+    let mut req = url.as_str().into_client_request()?;
+    if let Some(token) = token {
+        req.headers_mut().insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
+    }
+    let (mut ws, _) = tokio_tungstenite::connect_async_tls_with_config(req, None, false, tls.map(Connector::Rustls)).await
+        .with_context(|| format!("Failed to open {}", url))?;
+    ws.send(Message::text(json!({ "type": "subscribe", "id": 1 }).to_string())).await?;
+    while let Some(msg) = ws.next().await {
+        let Message::Text(msg) = msg? else { continue };
+        let frame: Value = serde_json::from_str(&msg).with_context(|| format!("Unexpected frame {}", msg))?;
+        match frame.get("type").and_then(Value::as_str) {
+            Some("event") if json => println!("{}", frame["event"]),
+            Some("event") => println!("{}", event_line(&frame["event"])),
+            Some("error") => anyhow::bail!("{}", text(&frame, "error")),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn print_table(value: &Value) {
+    if let Value::Object(fields) = value {
+        for (name, value) in fields {
+            println!("{:<22} {}", name, value);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // reqwest is built without a TLS backend of its own, so it needs rustls's provider up front.
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let base = match args.api.contains("://") {
+        true => args.api.trim_end_matches('/').to_string(),
+        false => format!("http://{}", args.api),
+    };
+    let tls = match base.starts_with("https://") {
+        true => Some(tls_config(&args.ca_cert)?),
+        false => None,
+    };
+    let client = match &tls {
+        Some(cfg) => Client::builder().use_preconfigured_tls(ClientConfig::clone(cfg)).build()?,
+        None => Client::new(),
+    };
+    let api = Api { client, base, token: token(&args)? };
+
+    match args.cmd {
+        Cmd::Node => {
+            let node = api.json("/node").await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&node)?);
+            } else {
+                print_table(&node);
+            }
+        }
+        Cmd::Peers => {
+            let peers = api.json("/peers").await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&peers)?);
+                return Ok(());
+            }
+            let peers = peers.as_array().cloned().unwrap_or_default();
+            if peers.is_empty() {
+                println!("No peers connected");
+            }
+            for peer in peers {
+                let queue = peer.get("queue").map(|q| format!("queue {}/{}", text(q, "depth"), text(q, "capacity")));
+                let state = peer.get("state").map(|s| s.as_str().unwrap_or_default().to_string());
+                println!("{:<16} {:<36} {:<21} {}",
+                    text(&peer, "uname"), text(&peer, "node_id"), text(&peer, "listen_addr"),
+                    [queue, state].into_iter().flatten().collect::<Vec<_>>().join(", "));
+            }
+        }
+        Cmd::Connect { addr } => {
+            let connected: Value = api.call(Method::POST, "/peers", Some(json!({ "addr": addr }))).await?.json().await?;
+            if args.json {
+                println!("{}", connected);
+            } else {
+                println!("Connected to {} at {}", text(&connected, "node_id"), addr);
+            }
+        }
+        Cmd::Peer { node } => {
+            let node_id = resolve(&api.connected().await?, &node)?;
+            let peer = api.json(&format!("/peers/{}", node_id)).await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&peer)?);
+            } else {
+                print_table(&peer);
+            }
+        }
+        Cmd::Disconnect { node, ban, reason } => {
+            let node_id = resolve(&api.connected().await?, &node)?;
+            let mut req = api.request(Method::DELETE, &format!("/peers/{}", node_id));
+            if ban {
+                req = req.query(&[("ban", "true")]);
+            }
+            if let Some(reason) = &reason {
+                req = req.query(&[("reason", reason)]);
+            }
+            let resp = api.send(req).await;
+            match resp {
+                Err(e) if status(&e) == Some(StatusCode::NOT_FOUND) => anyhow::bail!("{} is no longer connected", node),
+                res => { res?; }
+            }
+            if args.json {
+                println!("{}", json!({ "disconnected": node_id, "banned": ban }));
+            } else {
+                println!("{} {}", if ban { "Banned and disconnected" } else { "Disconnected" }, node_id);
+            }
+        }
+        Cmd::Send { to, text } => {
+            let msg = text.join(" ");
+            let to = match to {
+                Some(node) => Some(resolve(&api.connected().await?, &node)?),
+                None => None,
+            };
+            api.call(Method::POST, "/send", Some(json!({ "msg": msg, "to": to }))).await?;
+            if args.json {
+                println!("{}", json!({ "sent": msg, "to": to }));
+            }
+        }
+        Cmd::Events => tail_events(&api.base, tls, api.token.as_deref(), args.json).await?,
+        Cmd::Stats => {
+            let stats = api.json("/stats").await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                print_table(&stats);
+            }
+        }
+        Cmd::Bans => {
+            let bans = api.json("/bans").await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&bans)?);
+                return Ok(());
+            }
+            let bans = bans.as_array().cloned().unwrap_or_default();
+            if bans.is_empty() {
+                println!("No bans");
+            }
+            for ban in bans {
+                println!("{:<36} {:<15} until {} ({})", text(&ban, "node_id"), text(&ban, "ip"), text(&ban, "until"), text(&ban, "reason"));
+            }
+        }
+        Cmd::ReloadBans => {
+            let reloaded: Value = api.call(Method::POST, "/bans/reload", None).await?.json().await?;
+            if args.json {
+                println!("{}", reloaded);
+            } else {
+                println!("Reloaded {} bans", text(&reloaded, "bans"));
+            }
+        }
+    }
+    Ok(())
+}
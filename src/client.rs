@@ -1,16 +1,29 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use crate::network::{connect_new_peer};
+use crate::membership::Priority;
 use crate::peer_manager::PeerSummary;
 use crate::{peer_manager::PeerManagerHandle};
-use tracing::{warn};
 
-pub async fn connect(client_peer_info: PeerSummary, server_info: PeerSummary, peer_manager: Arc<PeerManagerHandle>) -> anyhow::Result<()> {
+/// Dials the peer at `server_info`'s listen address and returns the new connection's id.
+pub async fn connect(client_peer_info: PeerSummary, server_info: PeerSummary, peer_manager: Arc<PeerManagerHandle>) -> anyhow::Result<String> {
     let listen_addr = server_info.listen_addr_or_err(1)?;
 
-    let new_peer= connect_new_peer(&client_peer_info, listen_addr, peer_manager.clone()).await;
-    if let Err(e) = new_peer {
-        warn!("Failed to connect to peer: {}", e);
-    } 
-    Ok(())
+    let conn_id = connect_new_peer(&client_peer_info, listen_addr, peer_manager.clone()).await?;
+    if let Some(membership) = peer_manager.overlay.membership() {
+        membership.announce(&peer_manager, None, Some(conn_id.clone()), Priority::Join)?;
+    }
+    Ok(conn_id)
+}
+
+/// How long a dialed peer has to identify itself with JOIN.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Dials `addr` and returns the node id the peer registers with.
+pub async fn connect_node(peer_manager: Arc<PeerManagerHandle>, addr: String) -> anyhow::Result<String> {
+    let target = PeerSummary { remote_addr: None, listen_addr: Some(addr.clone()), node_id: None, uname: None };
+    let conn_id = connect(peer_manager.self_summary(), target, peer_manager.clone()).await?;
+    // A peer that is full answers BUSY, and a banned one is dropped after its JOIN.
+    peer_manager.wait_registered(&conn_id, &addr, JOIN_TIMEOUT).await
+        .ok_or_else(|| anyhow::anyhow!("{} closed the connection or did not JOIN in time", addr))
 }
\ No newline at end of file
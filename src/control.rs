@@ -0,0 +1,447 @@
+use std::{fs, os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{Notify, mpsc, watch},
+};
+use tracing::{debug, warn};
+
+use crate::{client, gossip, peer_manager::{FrontendEvent, PeerManagerHandle}, protocol::LineBreak, web_api};
+
+/// Longest request line accepted; a longer one closes the connection.
+const MAX_REQUEST: usize = 64 * 1024;
+/// Lines queued for one connection. A subscriber that lets it fill is disconnected.
+const CLIENT_QUEUE: usize = 1024;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The method ran and failed, e.g. a dial that was refused.
+const FAILED: i64 = -32000;
+
+/// A connection that asked for events.
+struct Subscriber {
+    tx: mpsc::Sender<String>,
+    /// Told when the connection fell too far behind and is being dropped.
+    lagged: Arc<Notify>,
+}
+
+type Subscribers = Arc<Mutex<Vec<Subscriber>>>;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: Option<String>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Absent for notifications, which get no response.
+    id: Option<Value>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(FAILED, format!("{:#}", e))
+    }
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// Binds the control socket and limits it to `mode`, so only users the file permissions let
+/// in can connect. A socket left behind by a node that is gone is replaced.
+///
+/// The socket is created inside a private 0700 directory and only moved to `path` once its
+/// permissions are set, so nobody can connect while it still has the umask's.
+pub fn bind(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("{} is in use by another node", path.display());
+        }
+        fs::remove_file(path).with_context(|| format!("Failed to remove stale {}", path.display()))?;
+    }
+
+    let name = path.file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)
+        .with_context(|| format!("Failed to create {}", private.display()))?;
+    let staged = private.join("sock");
+    let res = bind_staged(&staged, path, mode);
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&private);
+    let listener = res?;
+    debug!("Control socket listening on {}", path.display());
+    Ok(listener)
+}
+
+fn bind_staged(staged: &Path, path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    let listener = UnixListener::bind(staged)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    fs::set_permissions(staged, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+    fs::rename(staged, path)
+        .with_context(|| format!("Failed to move control socket to {}", path.display()))?;
+    Ok(listener)
+}
+
+/// Serves JSON-RPC clients until `shutdown` flips to true, then removes the socket file.
+pub async fn run(
+    listener: UnixListener,
+    path: PathBuf,
+    pm: Arc<PeerManagerHandle>,
+    api_url: Option<String>,
+    events: mpsc::Receiver<FrontendEvent>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let subscribers = Subscribers::default();
+    forward_events(events, subscribers.clone());
+
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, pm.clone(), api_url.clone(), subscribers.clone()));
+                }
+                Err(e) => warn!("Control socket accept failed: {}", e),
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        }
+    }
+    if let Err(e) = fs::remove_file(&path) {
+        debug!("Failed to remove {}: {}", path.display(), e);
+    }
+}
+
+fn forward_events(mut events: mpsc::Receiver<FrontendEvent>, subscribers: Subscribers) {
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let msg = json!({ "jsonrpc": "2.0", "method": "event", "params": event }).to_string();
+            subscribers.lock().unwrap().retain(|sub| match sub.tx.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Control client fell {} lines behind, disconnecting it", CLIENT_QUEUE);
+                    sub.lagged.notify_one();
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+        }
+    });
+}
+
+/// One line per request and per response or notification.
+async fn serve(stream: UnixStream, pm: Arc<PeerManagerHandle>, api_url: Option<String>, subscribers: Subscribers) {
+    debug!("Control client connected");
+    let (reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<String>(CLIENT_QUEUE);
+    let lagged = Arc::new(Notify::new());
+
+    let write_task = {
+        let lagged = lagged.clone();
+        tokio::spawn(async move {
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => msg,
+                    _ = lagged.notified() => None,
+                };
+                let Some(msg) = msg else { break };
+                if writer.write_all(format!("{}\n", msg).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        })
+    };
+
+    let mut buf = BufReader::new(reader);
+    let mut bytes = Vec::new();
+    loop {
+        bytes.clear();
+        let mut line = (&mut buf).take(MAX_REQUEST as u64 + 1);
+        let read = tokio::select! {
+            read = line.read_until(b'\n', &mut bytes) => read,
+            _ = tx.closed() => break,
+        };
+        match read {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if bytes.len() > MAX_REQUEST {
+            let _ = tx.send(response(Value::Null, Err(RpcError::new(INVALID_REQUEST, "request too large")))).await;
+            break;
+        }
+        if bytes.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        let req = match serde_json::from_slice::<Value>(&bytes) {
+            Ok(req) => req,
+            Err(e) => {
+                let _ = tx.send(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))).await;
+                continue;
+            }
+        };
+        let id = req.get("id").cloned().unwrap_or_default();
+        let req = match serde_json::from_value::<Request>(req) {
+            Ok(req) if req.jsonrpc.as_deref() == Some("2.0") => req,
+            _ => {
+                let _ = tx.send(response(id, Err(RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request")))).await;
+                continue;
+            }
+        };
+
+        // A dial can take a full connect timeout; other requests on the connection go on meanwhile.
+        let (pm, api_url, tx, lagged, subscribers) = (pm.clone(), api_url.clone(), tx.clone(), lagged.clone(), subscribers.clone());
+        tokio::spawn(async move {
+            let res = call(&req.method, req.params, &pm, api_url, &tx, &lagged, &subscribers).await;
+            if let Some(id) = req.id {
+                let _ = tx.send(response(id, res)).await;
+            }
+        });
+    }
+
+    subscribers.lock().unwrap().retain(|sub| !sub.tx.same_channel(&tx));
+    drop(tx);
+    let _ = write_task.await;
+    debug!("Control client disconnected");
+}
+
+fn response(id: Value, res: Result<Value, RpcError>) -> String {
+    match res {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => json!({ "jsonrpc": "2.0", "error": { "code": e.code, "message": e.message }, "id": id }),
+    }.to_string()
+}
+
+#[derive(Deserialize)]
+struct ConnectParams {
+    addr: String,
+}
+
+#[derive(Deserialize)]
+struct NodeParams {
+    node_id: String,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    msg: String,
+    /// Node id to send the message to privately instead of to everyone.
+    to: Option<String>,
+}
+
+async fn call(
+    method: &str,
+    args: Value,
+    pm: &Arc<PeerManagerHandle>,
+    api_url: Option<String>,
+    tx: &mpsc::Sender<String>,
+    lagged: &Arc<Notify>,
+    subscribers: &Subscribers,
+) -> Result<Value, RpcError> {
+    match method {
+        "node" => Ok(json!(web_api::node_view(pm, api_url))),
+        "peers" => Ok(json!(web_api::peer_views(pm))),
+        "connect" => {
+            let ConnectParams { addr } = params(args)?;
+            let node_id = client::connect_node(pm.clone(), addr).await?;
+            Ok(json!({ "node_id": node_id }))
+        }
+        "disconnect" => {
+            let NodeParams { node_id } = params(args)?;
+            if pm.get_peer(node_id.clone()).is_none() {
+                return Err(RpcError::new(FAILED, format!("{} is not connected", node_id)));
+            }
+            pm.remove_node(node_id).await;
+            Ok(Value::Null)
+        }
+        "send" => {
+            let SendParams { msg, to } = params(args)?;
+            let sent = match to {
+                Some(node_id) => gossip::send_direct(pm, node_id, &msg).await,
+                None => gossip::send_chat(pm, &msg).await,
+            };
+            match sent {
+                Ok(()) => Ok(Value::Null),
+                Err(e) if e.is::<LineBreak>() => Err(RpcError::new(INVALID_PARAMS, e.to_string())),
+                Err(e) => Err(e.into()),
+            }
+        }
+        "stats" => Ok(json!(pm.stats.snapshot())),
+        "subscribe" => {
+            let mut subs = subscribers.lock().unwrap();
+            if !subs.iter().any(|sub| sub.tx.same_channel(tx)) {
+                subs.push(Subscriber { tx: tx.clone(), lagged: lagged.clone() });
+            }
+            Ok(Value::Bool(true))
+        }
+        "unsubscribe" => {
+            subscribers.lock().unwrap().retain(|sub| !sub.tx.same_channel(tx));
+            Ok(Value::Bool(true))
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::{BufReader, Lines}, net::unix::OwnedReadHalf, time};
+
+    use super::*;
+    use crate::peer_manager::{EventKind, tests::node};
+
+    const NODE_ID: &str = "00000000-0000-4000-8000-000000000001";
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: tokio::net::unix::OwnedWriteHalf,
+    }
+
+    impl Client {
+        fn start() -> Self {
+            let (ours, theirs) = UnixStream::pair().unwrap();
+            let (pm, _events) = node(NODE_ID);
+            tokio::spawn(serve(theirs, pm, None, Subscribers::default()));
+            let (reader, writer) = ours.into_split();
+            Self { lines: BufReader::new(reader).lines(), writer }
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+        }
+
+        /// The next response, or None once the node closed the connection.
+        async fn recv(&mut self) -> Option<Value> {
+            let line = time::timeout(Duration::from_secs(2), self.lines.next_line()).await
+                .expect("no response in time").unwrap()?;
+            Some(serde_json::from_str(&line).unwrap())
+        }
+
+        async fn call(&mut self, request: Value) -> Value {
+            self.send(&request.to_string()).await;
+            self.recv().await.expect("connection closed")
+        }
+    }
+
+    fn error_code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_request_id() {
+        let mut client = Client::start();
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "node", "id": 7 })).await;
+        assert_eq!(res["id"], 7);
+        assert_eq!(res["result"]["node_id"], NODE_ID);
+
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "peers", "id": "p" })).await;
+        assert_eq!(res["id"], "p");
+        assert_eq!(res["result"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_json_rpc_errors() {
+        let mut client = Client::start();
+
+        client.send("{not json").await;
+        let res = client.recv().await.unwrap();
+        assert_eq!((error_code(&res), &res["id"]), (PARSE_ERROR, &Value::Null));
+
+        let res = client.call(json!({ "method": "node", "id": 1 })).await;
+        assert_eq!((error_code(&res), &res["id"]), (INVALID_REQUEST, &json!(1)));
+
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "reboot", "id": 2 })).await;
+        assert_eq!(error_code(&res), METHOD_NOT_FOUND);
+
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "connect", "params": {}, "id": 3 })).await;
+        assert_eq!(error_code(&res), INVALID_PARAMS);
+
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "disconnect", "params": { "node_id": "nobody" }, "id": 4 })).await;
+        assert_eq!(error_code(&res), FAILED);
+        assert!(res["error"]["message"].as_str().unwrap().contains("not connected"));
+    }
+
+    #[tokio::test]
+    async fn line_breaks_are_invalid_params() {
+        let mut client = Client::start();
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "send", "params": { "msg": "hi\nLEAVE|" }, "id": 1 })).await;
+        assert_eq!(error_code(&res), INVALID_PARAMS);
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "send", "params": { "msg": "hi" }, "id": 2 })).await;
+        assert_eq!(res["result"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let mut client = Client::start();
+        client.send(&json!({ "jsonrpc": "2.0", "method": "stats" }).to_string()).await;
+        client.send("").await;
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "unsubscribe", "id": 1 })).await;
+        assert_eq!(res["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_request_closes_the_connection() {
+        let mut client = Client::start();
+        let padding = "x".repeat(MAX_REQUEST);
+        client.send(&json!({ "jsonrpc": "2.0", "method": "node", "params": padding, "id": 1 }).to_string()).await;
+        let res = client.recv().await.unwrap();
+        assert_eq!((error_code(&res), &res["id"]), (INVALID_REQUEST, &Value::Null));
+        assert!(client.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_events() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let (pm, _events) = node(NODE_ID);
+        let subscribers = Subscribers::default();
+        let (events_tx, events_rx) = mpsc::channel(8);
+        forward_events(events_rx, subscribers.clone());
+        tokio::spawn(serve(theirs, pm, None, subscribers));
+        let (reader, writer) = ours.into_split();
+        let mut client = Client { lines: BufReader::new(reader).lines(), writer };
+
+        let res = client.call(json!({ "jsonrpc": "2.0", "method": "subscribe", "id": 1 })).await;
+        assert_eq!(res["result"], true);
+        let event = EventKind::PeerRenamed { node_id: "b".into(), old: None, uname: "bob".into() };
+        events_tx.send(event.into()).await.unwrap();
+        let notification = client.recv().await.unwrap();
+        assert_eq!(notification["method"], "event");
+        assert_eq!(notification["params"]["kind"], "peer_renamed");
+        assert_eq!(notification.get("id"), None);
+    }
+
+    #[tokio::test]
+    async fn bind_sets_the_mode_and_replaces_stale_sockets() {
+        let path = std::env::temp_dir().join(format!("test-control-{}.sock", crate::peer_manager::generate_unique_id()));
+        let listener = bind(&path, 0o600).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(bind(&path, 0o600).is_err(), "bound over a live socket");
+
+        drop(listener);
+        let _listener = bind(&path, 0o660).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, sync::{Arc, Mutex}, time::Duration};
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, time};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{network::connect_new_peer, peer_manager::{PeerManagerHandle, PeerSummary, generate_unique_id}};
+
+const KEY_BITS: usize = 128;
+const RPC_TIMEOUT: Duration = Duration::from_secs(3);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a node that doesn't fit into a bucket stays connected, so a lookup it
+/// dialed us for can still get its NODES reply.
+const FULL_GRACE: Duration = Duration::from_secs(2 * RPC_TIMEOUT.as_secs());
+
+/// Node ids are UUIDs, so the 128-bit value doubles as the Kademlia key.
+pub fn node_key(node_id: &str) -> Option<u128> {
+    Uuid::parse_str(node_id).ok().map(|u| u.as_u128())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Contact {
+    pub node_id: String,
+    pub listen_addr: String,
+}
+
+impl Contact {
+    pub fn from_summary(summary: &PeerSummary) -> Option<Self> {
+        Some(Self {
+            node_id: summary.node_id.clone()?,
+            listen_addr: summary.listen_addr.clone()?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Insert {
+    Added,
+    Updated,
+    Full,
+}
+
+/// k-buckets indexed by the length of the common prefix with our own key.
+pub struct RoutingTable {
+    self_key: u128,
+    k: usize,
+    buckets: Vec<VecDeque<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(self_key: u128, k: usize) -> Self {
+        Self { self_key, k, buckets: vec![VecDeque::new(); KEY_BITS] }
+    }
+
+    fn bucket_index(&self, key: u128) -> Option<usize> {
+        let distance = self.self_key ^ key;
+        if distance == 0 {
+            return None;
+        }
+        Some(KEY_BITS - 1 - distance.leading_zeros() as usize)
+    }
+
+    pub fn insert(&mut self, contact: Contact) -> Insert {
+        let Some(idx) = node_key(&contact.node_id).and_then(|key| self.bucket_index(key)) else {
+            return Insert::Full;
+        };
+        let k = self.k;
+        let bucket = &mut self.buckets[idx];
+
+        if let Some(pos) = bucket.iter().position(|c| c.node_id == contact.node_id) {
+            bucket.remove(pos);
+            bucket.push_back(contact);
+            Insert::Updated
+        } else if bucket.len() < k {
+            bucket.push_back(contact);
+            Insert::Added
+        } else {
+            Insert::Full
+        }
+    }
+
+    /// True when `node_id` is unknown and its bucket still has room.
+    pub fn has_room_for(&self, node_id: &str) -> bool {
+        match node_key(node_id).and_then(|key| self.bucket_index(key)) {
+            Some(idx) => {
+                let bucket = &self.buckets[idx];
+                bucket.len() < self.k && !bucket.iter().any(|c| c.node_id == node_id)
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, node_id: &str) {
+        if let Some(idx) = node_key(node_id).and_then(|key| self.bucket_index(key)) {
+            self.buckets[idx].retain(|c| c.node_id != node_id);
+        }
+    }
+
+    pub fn contains(&self, node_id: &str) -> bool {
+        self.get(node_id).is_some()
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<Contact> {
+        let idx = node_key(node_id).and_then(|key| self.bucket_index(key))?;
+        self.buckets[idx].iter().find(|c| c.node_id == node_id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.is_empty())
+    }
+
+    pub fn closest(&self, target: u128, n: usize) -> Vec<Contact> {
+        let mut all: Vec<(u128, &Contact)> = self.buckets
+            .iter()
+            .flatten()
+            .filter_map(|c| node_key(&c.node_id).map(|key| (key ^ target, c)))
+            .collect();
+        all.sort_by_key(|(distance, _)| *distance);
+        all.into_iter().take(n).map(|(_, c)| c.clone()).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FindNode {
+    request_id: String,
+    target: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Nodes {
+    request_id: String,
+    nodes: Vec<Contact>,
+}
+
+/// Kademlia-style routing state. Connections are kept only to nodes in the routing
+/// table, so the number of open connections stays bounded by the bucket sizes.
+pub struct Dht {
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<String, oneshot::Sender<Vec<Contact>>>>,
+    /// Nodes our own lookups are dialing; the lookup decides whether to keep them.
+    dialing: Mutex<HashSet<String>>,
+    k: usize,
+    alpha: usize,
+}
+
+impl Dht {
+    pub fn new(self_node_id: &str, k: usize, alpha: usize) -> anyhow::Result<Arc<Self>> {
+        let self_key = node_key(self_node_id)
+            .ok_or_else(|| anyhow::anyhow!("Node id {} is not a valid DHT key", self_node_id))?;
+
+        Ok(Arc::new(Self {
+            table: Mutex::new(RoutingTable::new(self_key, k)),
+            pending: Mutex::new(HashMap::new()),
+            dialing: Mutex::new(HashSet::new()),
+            k,
+            alpha: alpha.max(1),
+        }))
+    }
+
+    pub fn contains(&self, node_id: &str) -> bool {
+        self.table.lock().unwrap().contains(node_id)
+    }
+
+    pub fn has_room_for(&self, node_id: &str) -> bool {
+        self.table.lock().unwrap().has_room_for(node_id)
+    }
+
+    pub fn remove(&self, node_id: &str) {
+        self.table.lock().unwrap().remove(node_id);
+    }
+
+    /// Called once a connected node has completed its JOIN. A node that doesn't fit into
+    /// its bucket is disconnected again, unless one of our lookups dialed it.
+    pub fn on_registered(pm: Arc<PeerManagerHandle>, dht: Arc<Dht>, conn_id: String, summary: &PeerSummary) {
+        let Some(contact) = Contact::from_summary(summary) else {
+            return;
+        };
+
+        let (res, first) = {
+            let mut table = dht.table.lock().unwrap();
+            let first = table.is_empty();
+            (table.insert(contact.clone()), first)
+        };
+        debug!("DHT insert {} -> {:?}", contact.node_id, res);
+
+        if res == Insert::Full && !dht.dialing.lock().unwrap().contains(&contact.node_id) {
+            tokio::spawn(async move {
+                time::sleep(FULL_GRACE).await;
+                if !dht.contains(&contact.node_id) {
+                    debug!("Closing connection to {}: its bucket is full", contact.node_id);
+                    pm.remove_conn(conn_id).await;
+                }
+            });
+            return;
+        }
+
+        // The first contact is our way into the network: find the nodes closest to us.
+        if first && res == Insert::Added {
+            tokio::spawn(async move {
+                if let Some(self_id) = pm.self_peer_info.node_id.clone() {
+                    let _ = dht.lookup(pm.clone(), &self_id).await;
+                }
+            });
+        }
+    }
+
+    /// Answers with the k closest contacts we know. Replies go by conn_id since the
+    /// request may arrive before the requester's JOIN has been registered.
+    pub fn handle_find_node(&self, pm: Arc<PeerManagerHandle>, conn_id: String, msg: String) -> anyhow::Result<()> {
+        let payload = msg.strip_prefix("FIND_NODE|")
+            .ok_or_else(|| anyhow::anyhow!("Invalid FIND_NODE message format"))?;
+        let req: FindNode = serde_json::from_str(payload)?;
+        let target = node_key(&req.target)
+            .ok_or_else(|| anyhow::anyhow!("Invalid FIND_NODE target {}", req.target))?;
+
+        let nodes = self.table.lock().unwrap().closest(target, self.k);
+
+        let resp = Nodes { request_id: req.request_id, nodes };
+        let msg = format!("NODES|{}", serde_json::to_string(&resp)?);
+        pm.send_to(None, Some(conn_id), msg)
+    }
+
+    pub fn handle_nodes(&self, msg: String) -> anyhow::Result<()> {
+        let payload = msg.strip_prefix("NODES|")
+            .ok_or_else(|| anyhow::anyhow!("Invalid NODES message format"))?;
+        let resp: Nodes = serde_json::from_str(payload)?;
+
+        if let Some(tx) = self.pending.lock().unwrap().remove(&resp.request_id) {
+            let _ = tx.send(resp.nodes);
+        }
+        Ok(())
+    }
+
+    /// Iterative FIND_NODE lookup. Returns the contact for `target` if any node knows it.
+    pub async fn lookup(&self, pm: Arc<PeerManagerHandle>, target: &str) -> anyhow::Result<Option<Contact>> {
+        let target_key = node_key(target)
+            .ok_or_else(|| anyhow::anyhow!("Invalid node id {}", target))?;
+        let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+
+        if let Some(contact) = self.table.lock().unwrap().get(target) {
+            return Ok(Some(contact));
+        }
+
+        let mut shortlist: BTreeMap<u128, Contact> = self.table.lock().unwrap()
+            .closest(target_key, self.k)
+            .into_iter()
+            .filter_map(|c| node_key(&c.node_id).map(|key| (key ^ target_key, c)))
+            .collect();
+        let mut queried: HashSet<String> = HashSet::new();
+        let mut opened: Vec<String> = Vec::new();
+
+        loop {
+            let batch: Vec<Contact> = shortlist.values()
+                .take(self.k)
+                .filter(|c| !queried.contains(&c.node_id))
+                .take(self.alpha)
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let queries = batch.iter().map(|c| {
+                queried.insert(c.node_id.clone());
+                self.query(pm.clone(), c.clone(), target.to_string())
+            }).collect::<Vec<_>>();
+
+            for (contacts, was_opened) in join_all(queries).await.into_iter().flatten() {
+                if let Some(node_id) = was_opened {
+                    opened.push(node_id);
+                }
+                for c in contacts {
+                    if c.node_id == self_id {
+                        continue;
+                    }
+                    if let Some(key) = node_key(&c.node_id) {
+                        shortlist.entry(key ^ target_key).or_insert(c);
+                    }
+                }
+            }
+
+            if shortlist.contains_key(&0) {
+                break;
+            }
+        }
+
+        // Connections opened only for this lookup are dropped unless the node made it into a bucket.
+        for node_id in opened {
+            self.dialing.lock().unwrap().remove(&node_id);
+            if !self.contains(&node_id) {
+                debug!("Closing lookup connection to {}", node_id);
+                pm.remove_node(node_id).await;
+            }
+        }
+
+        Ok(shortlist.remove(&0))
+    }
+
+    /// Sends FIND_NODE to `contact`, dialing it first when we are not connected yet.
+    /// Returns the contacts it replied with and its node_id if a connection was opened.
+    async fn query(&self, pm: Arc<PeerManagerHandle>, contact: Contact, target: String) -> anyhow::Result<(Vec<Contact>, Option<String>)> {
+        let mut opened = None;
+
+        if pm.get_peer(contact.node_id.clone()).is_none() {
+            let self_peer = pm.self_summary();
+            self.dialing.lock().unwrap().insert(contact.node_id.clone());
+            let conn_id = match connect_new_peer(&self_peer, contact.listen_addr.clone(), pm.clone()).await {
+                Ok(conn_id) => conn_id,
+                Err(e) => {
+                    self.dialing.lock().unwrap().remove(&contact.node_id);
+                    return Err(e);
+                }
+            };
+            opened = Some(contact.node_id.clone());
+
+            match pm.wait_registered(&conn_id, &contact.listen_addr, RPC_TIMEOUT).await {
+                Some(node_id) if node_id == contact.node_id => {}
+                Some(node_id) => {
+                    warn!("DHT contact {} at {} turned out to be {}", contact.node_id, contact.listen_addr, node_id);
+                    return Ok((vec![], opened));
+                }
+                None => {
+                    warn!("DHT contact {} did not complete JOIN", contact.node_id);
+                    return Ok((vec![], opened));
+                }
+            }
+        }
+
+        let request_id = generate_unique_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id.clone(), tx);
+
+        let req = FindNode { request_id: request_id.clone(), target };
+        let msg = format!("FIND_NODE|{}", serde_json::to_string(&req)?);
+        if let Err(e) = pm.send_to(Some(contact.node_id.clone()), None, msg) {
+            self.pending.lock().unwrap().remove(&request_id);
+            return Err(e);
+        }
+
+        match time::timeout(RPC_TIMEOUT, rx).await {
+            Ok(Ok(nodes)) => Ok((nodes, opened)),
+            _ => {
+                self.pending.lock().unwrap().remove(&request_id);
+                debug!("FIND_NODE to {} timed out", contact.node_id);
+                Ok((vec![], opened))
+            }
+        }
+    }
+
+    /// Periodically re-runs lookups so buckets pick up nodes that joined later.
+    pub async fn refresh_loop(self: Arc<Self>, pm: Arc<PeerManagerHandle>) {
+        let mut ticker = time::interval(REFRESH_INTERVAL);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            if self.table.lock().unwrap().is_empty() {
+                continue;
+            }
+            if let Some(self_id) = pm.self_peer_info.node_id.clone() {
+                let _ = self.lookup(pm.clone(), &self_id).await;
+            }
+            let _ = self.lookup(pm.clone(), &generate_unique_id()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(key: u128) -> Contact {
+        Contact { node_id: Uuid::from_u128(key).to_string(), listen_addr: format!("127.0.0.1:{}", key % 60000) }
+    }
+
+    #[test]
+    fn buckets_by_common_prefix() {
+        let table = RoutingTable::new(0, 2);
+        assert_eq!(table.bucket_index(0), None);
+        assert_eq!(table.bucket_index(1), Some(0));
+        assert_eq!(table.bucket_index(0b110), Some(2));
+        assert_eq!(table.bucket_index(u128::MAX), Some(KEY_BITS - 1));
+    }
+
+    #[test]
+    fn insert_fills_bucket_up_to_k() {
+        let mut table = RoutingTable::new(0, 2);
+        assert_eq!(table.insert(contact(4)), Insert::Added);
+        assert_eq!(table.insert(contact(5)), Insert::Added);
+        assert!(!table.has_room_for(&contact(6).node_id));
+        assert_eq!(table.insert(contact(6)), Insert::Full);
+        // Another bucket is unaffected.
+        assert!(table.has_room_for(&contact(8).node_id));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn reinsert_updates_contact() {
+        let mut table = RoutingTable::new(0, 2);
+        table.insert(contact(4));
+        table.insert(contact(5));
+        let moved = Contact { listen_addr: "127.0.0.1:9".to_string(), ..contact(4) };
+        assert_eq!(table.insert(moved.clone()), Insert::Updated);
+        assert_eq!(table.get(&moved.node_id), Some(moved));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn own_and_invalid_ids_are_rejected() {
+        let mut table = RoutingTable::new(7, 2);
+        assert_eq!(table.insert(contact(7)), Insert::Full);
+        assert_eq!(table.insert(Contact { node_id: "not-a-uuid".to_string(), listen_addr: String::new() }), Insert::Full);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn remove_frees_room() {
+        let mut table = RoutingTable::new(0, 1);
+        table.insert(contact(2));
+        assert!(!table.has_room_for(&contact(3).node_id));
+        table.remove(&contact(2).node_id);
+        assert!(!table.contains(&contact(2).node_id));
+        assert!(table.has_room_for(&contact(3).node_id));
+    }
+
+    #[test]
+    fn closest_sorts_by_xor_distance() {
+        let mut table = RoutingTable::new(0, 8);
+        for key in [1, 2, 3, 8, 12] {
+            table.insert(contact(key));
+        }
+        let ids: Vec<String> = table.closest(9, 3).into_iter().map(|c| c.node_id).collect();
+        assert_eq!(ids, vec![contact(8).node_id, contact(12).node_id, contact(1).node_id]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, sync::Arc, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time};
+use tracing::{debug, warn};
+
+use crate::{membership::Priority, network::connect_new_peer, peer_manager::PeerManagerHandle};
+
+/// Settings for the opt-in LAN discovery service.
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// Multicast group (or broadcast address) beacons are sent to and received on.
+    pub group: SocketAddrV4,
+    /// Only beacons carrying the same network name are dialed.
+    pub network: String,
+    pub interval: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Beacon {
+    network: String,
+    node_id: String,
+    listen_addr: String,
+}
+
+/// A node found through a beacon, with the address to dial it on.
+#[derive(Debug, PartialEq)]
+struct Found {
+    node_id: String,
+    listen_addr: String,
+    dial_addr: String,
+}
+
+/// Decides which received beacons are worth dialing.
+struct Beacons {
+    node_id: String,
+    network: String,
+    /// When each node was last dialed, so a failing peer is not retried on every beacon.
+    last_dial: HashMap<String, Instant>,
+    retry_after: Duration,
+}
+
+impl Beacons {
+    fn new(node_id: String, network: String, retry_after: Duration) -> Self {
+        Self { node_id, network, last_dial: HashMap::new(), retry_after }
+    }
+
+    /// The node behind a beacon from `from`, unless it is malformed, our own, from another
+    /// network or dialed too recently.
+    fn check(&self, data: &[u8], from: SocketAddr) -> Option<Found> {
+        let Some(found) = parse_beacon(data) else {
+            debug!("Ignoring malformed beacon from {}", from);
+            return None;
+        };
+        if found.node_id == self.node_id || found.network != self.network {
+            return None;
+        }
+        if self.last_dial.get(&found.node_id).is_some_and(|t| t.elapsed() < self.retry_after) {
+            return None;
+        }
+
+        // The announced IP may be a wildcard or not routable from here; the port is what
+        // counts, on the address the beacon came from.
+        let Ok(announced) = found.listen_addr.parse::<SocketAddr>() else {
+            debug!("Ignoring beacon from {} with listen address {}", from, found.listen_addr);
+            return None;
+        };
+        let dial_addr = SocketAddr::new(from.ip(), announced.port()).to_string();
+        Some(Found { node_id: found.node_id, listen_addr: found.listen_addr, dial_addr })
+    }
+
+    fn dialed(&mut self, node_id: String) {
+        self.last_dial.insert(node_id, Instant::now());
+        self.last_dial.retain(|_, t| t.elapsed() < self.retry_after);
+    }
+}
+
+pub async fn run(cfg: DiscoveryConfig, pm: Arc<PeerManagerHandle>) -> anyhow::Result<()> {
+    let self_peer = pm.self_summary();
+    let listen_addr = self_peer.listen_addr_or_err(11)?;
+    let node_id = self_peer.node_id.clone()
+        .ok_or_else(|| anyhow::anyhow!("Node id is missing"))?;
+
+    let iface = match listen_addr.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(addr)) => *addr.ip(),
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+    let socket = bind_socket(&cfg, iface)?;
+    debug!("Discovery beacons on {} for network '{}'", cfg.group, cfg.network);
+
+    let beacon = format!("BEACON|{}", serde_json::to_string(&Beacon {
+        network: cfg.network.clone(),
+        node_id: node_id.clone(),
+        listen_addr: listen_addr.clone(),
+    })?);
+
+    let mut beacons = Beacons::new(node_id, cfg.network.clone(), cfg.interval * 3);
+
+    let mut ticker = time::interval(cfg.interval);
+    let mut buf = vec![0u8; 2048];
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if let Err(e) = socket.send_to(beacon.as_bytes(), cfg.group).await {
+                    warn!("Failed to send discovery beacon: {}", e);
+                }
+            }
+
+            res = socket.recv_from(&mut buf) => {
+                let (len, from) = match res {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Discovery socket error: {}", e);
+                        continue;
+                    }
+                };
+
+                let Some(Found { node_id, listen_addr, dial_addr }) = beacons.check(&buf[..len], from) else {
+                    continue;
+                };
+                if pm.contains_listen_addr(listen_addr) || pm.contains_listen_addr(dial_addr.clone()) {
+                    continue;
+                }
+
+                debug!("Discovered node {} at {}", node_id, dial_addr);
+                beacons.dialed(node_id);
+
+                let pm = pm.clone();
+                let self_peer = self_peer.clone();
+                tokio::spawn(async move {
+                    match connect_new_peer(&self_peer, dial_addr, pm.clone()).await {
+                        Ok(conn_id) => {
+                            if let Some(membership) = pm.overlay.membership() {
+                                let _ = membership.announce(&pm, None, Some(conn_id), Priority::Join);
+                            }
+                        }
+                        Err(e) => warn!("Failed to connect to discovered peer: {}", e),
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn parse_beacon(data: &[u8]) -> Option<Beacon> {
+    let text = std::str::from_utf8(data).ok()?;
+    let payload = text.trim().strip_prefix("BEACON|")?;
+    serde_json::from_str(payload).ok()
+}
+
+fn bind_socket(cfg: &DiscoveryConfig, iface: Ipv4Addr) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Several nodes on one host share the beacon port.
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, cfg.group.port()).into())?;
+
+    let group_ip = *cfg.group.ip();
+    if group_ip.is_multicast() {
+        socket.join_multicast_v4(&group_ip, &iface)?;
+        socket.set_multicast_if_v4(&iface)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(1)?;
+    } else {
+        socket.set_broadcast(true)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FROM: &str = "192.168.1.20:45678";
+
+    fn beacons() -> Beacons {
+        Beacons::new("me".to_string(), "lan".to_string(), Duration::from_secs(30))
+    }
+
+    fn beacon(network: &str, node_id: &str, listen_addr: &str) -> Vec<u8> {
+        let beacon = Beacon { network: network.to_string(), node_id: node_id.to_string(), listen_addr: listen_addr.to_string() };
+        format!("BEACON|{}\n", serde_json::to_string(&beacon).unwrap()).into_bytes()
+    }
+
+    fn check(beacons: &Beacons, data: &[u8]) -> Option<Found> {
+        beacons.check(data, FROM.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_beacons() {
+        let found = parse_beacon(&beacon("lan", "a", "0.0.0.0:8000")).unwrap();
+        assert_eq!((found.network.as_str(), found.node_id.as_str(), found.listen_addr.as_str()), ("lan", "a", "0.0.0.0:8000"));
+
+        assert!(parse_beacon(b"MSG|hello").is_none());
+        assert!(parse_beacon(b"BEACON|{not json").is_none());
+        assert!(parse_beacon(b"BEACON|{\"network\":\"lan\"}").is_none());
+        assert!(parse_beacon(&[0xff, 0xfe]).is_none());
+    }
+
+    #[test]
+    fn dials_the_announced_port_on_the_sender_address() {
+        let found = check(&beacons(), &beacon("lan", "a", "0.0.0.0:8000")).unwrap();
+        assert_eq!(found, Found { node_id: "a".into(), listen_addr: "0.0.0.0:8000".into(), dial_addr: "192.168.1.20:8000".into() });
+    }
+
+    #[test]
+    fn ignores_own_foreign_and_malformed_beacons() {
+        let beacons = beacons();
+        assert!(check(&beacons, &beacon("lan", "me", "127.0.0.1:8000")).is_none());
+        assert!(check(&beacons, &beacon("other", "a", "127.0.0.1:8000")).is_none());
+        assert!(check(&beacons, &beacon("lan", "a", "not an address")).is_none());
+        assert!(check(&beacons, b"garbage").is_none());
+    }
+
+    #[test]
+    fn redials_only_after_the_retry_window() {
+        let mut beacons = beacons();
+        let data = beacon("lan", "a", "127.0.0.1:8000");
+        beacons.dialed("a".to_string());
+        assert!(check(&beacons, &data).is_none());
+        // Others are not held back.
+        assert!(check(&beacons, &beacon("lan", "b", "127.0.0.1:8001")).is_some());
+
+        *beacons.last_dial.get_mut("a").unwrap() -= Duration::from_secs(31);
+        assert!(check(&beacons, &data).is_some());
+    }
+
+    #[test]
+    fn expired_dials_are_forgotten() {
+        let mut beacons = beacons();
+        beacons.dialed("a".to_string());
+        *beacons.last_dial.get_mut("a").unwrap() -= Duration::from_secs(31);
+        beacons.dialed("b".to_string());
+        assert_eq!(beacons.last_dial.keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn beacons_reach_other_nodes_on_loopback() {
+        let cfg = DiscoveryConfig {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 77), 47_000 + rand::random::<u16>() % 1000),
+            network: "lan".to_string(),
+            interval: Duration::from_secs(1),
+        };
+        let (Ok(a), Ok(b)) = (bind_socket(&cfg, Ipv4Addr::LOCALHOST), bind_socket(&cfg, Ipv4Addr::LOCALHOST)) else {
+            eprintln!("skipping: no multicast on loopback");
+            return;
+        };
+
+        let data = beacon("lan", "a", "127.0.0.1:8000");
+        a.send_to(&data, cfg.group).await.unwrap();
+        let mut buf = vec![0u8; 2048];
+        let (len, from) = time::timeout(Duration::from_secs(2), b.recv_from(&mut buf)).await
+            .expect("beacon not received").unwrap();
+        let found = Beacons::new("b".to_string(), "lan".to_string(), Duration::from_secs(30))
+            .check(&buf[..len], from).unwrap();
+        assert_eq!(found.dial_addr, "127.0.0.1:8000");
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::{HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::peer_manager::{EventKind, PeerManagerHandle, generate_unique_id};
+
+const SEEN_CAPACITY: usize = 4096;
+
+/// Chat message flooded through overlays where nodes are not connected to everyone.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Gossip {
+    pub id: String,
+    pub origin: String,
+    pub uname: Option<String>,
+    pub content: String,
+}
+
+/// Bounded set of recently seen message ids used to stop relay loops.
+#[derive(Default)]
+pub struct SeenCache {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl SeenCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false if `id` was already seen.
+    pub fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > SEEN_CAPACITY
+            && let Some(old) = self.order.pop_front() {
+            self.ids.remove(&old);
+        }
+        true
+    }
+}
+
+/// Sends a chat line from this node, flooding it when the overlay is partial.
+/// Fails with `FrameTooLarge` or `LineBreak` when the resulting frame would be rejected by peers.
+pub async fn send_chat(pm: &PeerManagerHandle, content: &str) -> anyhow::Result<()> {
+    if !pm.overlay.relays_chat() {
+        let frame = format!("MSG|{}", content);
+        pm.check_frame(&frame)?;
+        pm.broadcast(frame).await;
+        // MSG carries no id on the wire; one is made up so frontends can refer to it.
+        pm.emit(EventKind::MessageSent { message_id: generate_unique_id(), to: None, to_uname: None, content: content.to_string() });
+        return Ok(());
+    }
+
+    let gossip = Gossip {
+        id: generate_unique_id(),
+        origin: pm.self_peer_info.node_id.clone().unwrap_or_default(),
+        uname: pm.uname(),
+        content: content.to_string(),
+    };
+    let frame = format!("GOSSIP|{}", serde_json::to_string(&gossip)?);
+    pm.check_frame(&frame)?;
+
+    pm.seen.lock().unwrap().insert(&gossip.id);
+    pm.broadcast(frame).await;
+    pm.emit(EventKind::MessageSent { message_id: gossip.id, to: None, to_uname: None, content: gossip.content });
+    Ok(())
+}
+
+/// Sends a chat line to one connected peer only.
+pub async fn send_direct(pm: &PeerManagerHandle, node_id: String, content: &str) -> anyhow::Result<()> {
+    let frame = format!("DM|{}", content);
+    pm.check_frame(&frame)?;
+    pm.send_chat_to(node_id.clone(), frame).await?;
+
+    let to_uname = pm.get_peer(node_id.clone()).and_then(|p| p.uname);
+    pm.emit(EventKind::MessageSent { message_id: generate_unique_id(), to: Some(node_id), to_uname, content: content.to_string() });
+    Ok(())
+}
+
+/// Relays a GOSSIP frame to every other peer. Returns the message if it was new.
+pub fn handle_gossip_json(pm: &PeerManagerHandle, node_id: String, msg: String) -> anyhow::Result<Option<Gossip>> {
+    let payload = msg.strip_prefix("GOSSIP|")
+        .ok_or_else(|| anyhow::anyhow!("Invalid GOSSIP message format"))?;
+    let gossip: Gossip = serde_json::from_str(payload)?;
+
+    if !pm.seen.lock().unwrap().insert(&gossip.id) {
+        return Ok(None);
+    }
+    if pm.self_peer_info.node_id.as_deref() == Some(gossip.origin.as_str()) {
+        return Ok(None);
+    }
+
+    pm.broadcast_except(msg, Some(node_id));
+    Ok(Some(gossip))
+}
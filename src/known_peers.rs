@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+
+use crate::peer_manager::PeerSummary;
+
+pub enum Delta {
+    Added(PeerSummary),
+    Removed(String),
+}
+
+pub enum Applied {
+    /// Applied in order; carries the peer if it was not known before.
+    InOrder(Option<PeerSummary>),
+    /// Already covered by a newer version from the same sender.
+    Stale,
+    /// Versions were skipped, so the sender has to resend its full list.
+    Gap,
+}
+
+/// Peers our neighbours are connected to, kept up to date from their
+/// versioned PEERS snapshots and PEER_ADDED / PEER_REMOVED deltas.
+pub struct KnownPeers {
+    self_id: String,
+    versions: HashMap<String, u64>,
+    /// Neighbours that currently report each peer, by node id.
+    peers: HashMap<String, HashSet<String>>,
+}
+
+impl KnownPeers {
+    pub fn new(self_id: String) -> Self {
+        Self { self_id, versions: HashMap::new(), peers: HashMap::new() }
+    }
+
+    /// Replaces everything `from` reported with its full list. Returns the peers that are new to us.
+    pub fn sync(&mut self, from: &str, version: u64, peers: Vec<PeerSummary>) -> Vec<PeerSummary> {
+        if self.versions.get(from).is_some_and(|v| *v > version) {
+            return vec![];
+        }
+        self.versions.insert(from.to_string(), version);
+
+        let listed: HashSet<&str> = peers.iter().filter_map(|p| p.node_id.as_deref()).collect();
+        self.peers.retain(|node_id, sources| {
+            if !listed.contains(node_id.as_str()) {
+                sources.remove(from);
+            }
+            !sources.is_empty()
+        });
+
+        peers.into_iter().filter_map(|summary| self.add(from, summary)).collect()
+    }
+
+    pub fn apply(&mut self, from: &str, version: u64, delta: Delta) -> Applied {
+        match self.versions.get(from) {
+            Some(v) if version <= *v => return Applied::Stale,
+            Some(v) if version == v + 1 => {}
+            _ => return Applied::Gap,
+        }
+        self.versions.insert(from.to_string(), version);
+
+        match delta {
+            Delta::Added(summary) => Applied::InOrder(self.add(from, summary)),
+            Delta::Removed(node_id) => {
+                self.remove(from, &node_id);
+                Applied::InOrder(None)
+            }
+        }
+    }
+
+    /// Drops everything learned from a neighbour we are no longer connected to.
+    pub fn forget(&mut self, from: &str) {
+        self.versions.remove(from);
+        self.peers.retain(|_, sources| {
+            sources.remove(from);
+            !sources.is_empty()
+        });
+    }
+
+    fn add(&mut self, from: &str, summary: PeerSummary) -> Option<PeerSummary> {
+        let node_id = summary.node_id.clone()?;
+        if node_id == self.self_id || node_id == from {
+            return None;
+        }
+
+        match self.peers.entry(node_id) {
+            Entry::Occupied(mut e) => {
+                e.get_mut().insert(from.to_string());
+                None
+            }
+            Entry::Vacant(e) => {
+                e.insert(HashSet::from([from.to_string()]));
+                Some(summary)
+            }
+        }
+    }
+
+    fn remove(&mut self, from: &str, node_id: &str) {
+        if let Some(sources) = self.peers.get_mut(node_id) {
+            sources.remove(from);
+            if sources.is_empty() {
+                self.peers.remove(node_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(node_id: &str) -> PeerSummary {
+        PeerSummary { remote_addr: None, listen_addr: Some(format!("{}:8000", node_id)), node_id: Some(node_id.to_string()), uname: None }
+    }
+
+    fn ids(peers: Vec<PeerSummary>) -> Vec<String> {
+        let mut ids: Vec<String> = peers.into_iter().filter_map(|p| p.node_id).collect();
+        ids.sort();
+        ids
+    }
+
+    fn added(applied: Applied) -> Option<String> {
+        match applied {
+            Applied::InOrder(peer) => peer.and_then(|p| p.node_id),
+            _ => panic!("expected an in-order delta"),
+        }
+    }
+
+    #[test]
+    fn sync_returns_new_peers_only() {
+        let mut known = KnownPeers::new("self".to_string());
+        assert_eq!(ids(known.sync("a", 1, vec![peer("b"), peer("c"), peer("self"), peer("a")])), vec!["b", "c"]);
+        assert_eq!(ids(known.sync("d", 1, vec![peer("b"), peer("e")])), vec!["e"]);
+    }
+
+    #[test]
+    fn older_sync_is_ignored() {
+        let mut known = KnownPeers::new("self".to_string());
+        known.sync("a", 5, vec![peer("b")]);
+        assert!(known.sync("a", 4, vec![peer("c")]).is_empty());
+        assert!(matches!(known.apply("a", 6, Delta::Added(peer("c"))), Applied::InOrder(Some(_))));
+    }
+
+    #[test]
+    fn sync_drops_peers_no_longer_listed() {
+        let mut known = KnownPeers::new("self".to_string());
+        known.sync("a", 1, vec![peer("b"), peer("c")]);
+        known.sync("a", 2, vec![peer("c")]);
+        assert_eq!(ids(known.sync("d", 1, vec![peer("b"), peer("c")])), vec!["b"]);
+    }
+
+    #[test]
+    fn deltas_apply_in_order() {
+        let mut known = KnownPeers::new("self".to_string());
+        known.sync("a", 1, vec![]);
+        assert_eq!(added(known.apply("a", 2, Delta::Added(peer("b")))).as_deref(), Some("b"));
+        assert_eq!(added(known.apply("a", 3, Delta::Added(peer("b")))), None);
+        assert_eq!(added(known.apply("a", 4, Delta::Removed("b".to_string()))), None);
+        assert_eq!(added(known.apply("a", 5, Delta::Added(peer("b")))).as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn repeated_delta_is_stale() {
+        let mut known = KnownPeers::new("self".to_string());
+        known.sync("a", 3, vec![]);
+        assert!(matches!(known.apply("a", 3, Delta::Added(peer("b"))), Applied::Stale));
+        assert!(matches!(known.apply("a", 2, Delta::Added(peer("b"))), Applied::Stale));
+    }
+
+    #[test]
+    fn skipped_version_is_a_gap() {
+        let mut known = KnownPeers::new("self".to_string());
+        assert!(matches!(known.apply("a", 1, Delta::Added(peer("b"))), Applied::Gap));
+        known.sync("a", 1, vec![]);
+        assert!(matches!(known.apply("a", 3, Delta::Added(peer("b"))), Applied::Gap));
+        // A gap leaves the version alone, so the next in-order delta still applies.
+        assert!(matches!(known.apply("a", 2, Delta::Added(peer("b"))), Applied::InOrder(Some(_))));
+    }
+
+    #[test]
+    fn peer_stays_known_while_any_source_reports_it() {
+        let mut known = KnownPeers::new("self".to_string());
+        known.sync("a", 1, vec![peer("c")]);
+        known.sync("b", 1, vec![peer("c")]);
+        known.apply("a", 2, Delta::Removed("c".to_string()));
+        assert_eq!(added(known.apply("b", 2, Delta::Added(peer("c")))), None);
+        known.forget("b");
+        assert_eq!(added(known.apply("a", 3, Delta::Added(peer("c")))).as_deref(), Some("c"));
+    }
+}
@@ -0,0 +1,22 @@
+pub mod client;
+pub mod server;
+pub mod protocol;
+pub mod web_api;
+pub mod peer_manager;
+pub mod network;
+pub mod tls_utils;
+pub mod discovery;
+pub mod dht;
+pub mod gossip;
+pub mod membership;
+pub mod swim;
+pub mod known_peers;
+pub mod bans;
+pub mod rate_limit;
+pub mod stats;
+pub mod send_queue;
+pub mod ui;
+pub mod tui;
+pub mod auth;
+#[cfg(unix)]
+pub mod control;
@@ -1,40 +1,295 @@
-
-use clap::{Parser};
+use clap::{Parser, ValueEnum};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use tokio::{io::{self, AsyncBufReadExt}, sync::mpsc};
-use std::{fs, net::SocketAddr, sync::{Arc, Mutex}};
-use crate::{peer_manager::{FrontendEvent, PeerManagerHandle, PeerSummary, generate_unique_id}, tls_utils::{TlsCert, generate_self_signed_cert}, web_api::ApiState};
-use tracing::{error, debug};
-use tracing_subscriber;
-
-mod client;
-mod server;
-mod protocol;
-mod web_api;
-mod peer_manager;
-mod network;
-mod tls_utils;
+use tokio::{sync::{mpsc, watch}, time};
+use anyhow::Context;
+use std::{fs, io::IsTerminal, net::{IpAddr, SocketAddr, SocketAddrV4}, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+use tcp_rust::{client, protocol, discovery, gossip, server, tui, ui, web_api, auth::ApiKeys, bans::{BanConfig, Bans}, rate_limit::RateLimits, send_queue::SendPolicy, dht::Dht, discovery::DiscoveryConfig, network::DialConfig, membership::{Membership, MembershipConfig}, swim::{Swim, SwimConfig}, peer_manager::{ConnLimits, Eviction, FrontendEvent, Overlay, PeerManagerHandle, PeerSummary, generate_unique_id}, tls_utils::{TlsCert, generate_self_signed_cert, make_acceptor, make_server_config}, web_api::ApiState};
+#[cfg(unix)]
+use tcp_rust::control;
+use tracing::{error, debug, info, warn};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OverlayKind {
+    Mesh,
+    Dht,
+    Hyparview,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
     #[arg(long)]
     port: u16,
+    /// IP the peer listener binds to and announces; use a LAN address for discovery across hosts
+    #[arg(long, default_value = "127.0.0.1")]
+    host: IpAddr,
     #[arg(long)]
     peer: Option<String>,
     #[arg(long)]
     uname: Option<String>,
     #[arg(long, default_value_t = false)]
     tls: bool,
+    /// Address for the HTTP API, or "off"; defaults to 127.0.0.1 on --port + 100
+    #[arg(long)]
+    api_bind: Option<String>,
+    /// Serve the API over HTTPS with the certificate in tls/
+    #[arg(long, default_value_t = false)]
+    api_tls: bool,
+    /// Run as a service: don't read chat lines from stdin
+    #[arg(long, default_value_t = false)]
+    headless: bool,
+    /// Full-screen terminal UI with message, peer and log panes
+    #[arg(long, default_value_t = false, conflicts_with = "headless")]
+    tui: bool,
+    /// Unix socket to serve JSON-RPC control requests on
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+    /// Octal permissions of the control socket; only users they allow can connect
+    #[arg(long, default_value = "600", value_parser = parse_mode)]
+    control_mode: u32,
+    /// File holding the API's read and full access tokens; generated if missing
+    #[arg(long, default_value = "api_keys.json")]
+    api_keys_file: PathBuf,
+    /// Serve the API without tokens, for local development
+    #[arg(long, default_value_t = false)]
+    no_auth: bool,
+    /// Announce this node and dial others found on the LAN
+    #[arg(long, default_value_t = false)]
+    discovery: bool,
+    /// Multicast group (or broadcast address) used for discovery beacons
+    #[arg(long, default_value = "239.255.42.98:7645")]
+    discovery_addr: SocketAddrV4,
+    /// Seconds between discovery beacons
+    #[arg(long, default_value_t = 5)]
+    discovery_interval: u64,
+    /// Network name; discovery ignores nodes from other networks
+    #[arg(long, default_value = "default")]
+    network: String,
+    /// Peer overlay: full mesh or Kademlia-style DHT with bounded connections
+    #[arg(long, value_enum, default_value_t = OverlayKind::Mesh)]
+    overlay: OverlayKind,
+    /// DHT bucket size (k)
+    #[arg(long, default_value_t = 8)]
+    dht_k: usize,
+    /// DHT lookup parallelism (alpha)
+    #[arg(long, default_value_t = 3)]
+    dht_alpha: usize,
+    /// HyParView active view size (open connections)
+    #[arg(long, default_value_t = 5)]
+    active_view: usize,
+    /// HyParView passive view size (known addresses kept for repair)
+    #[arg(long, default_value_t = 30)]
+    passive_view: usize,
+    /// Seconds between HyParView shuffles
+    #[arg(long, default_value_t = 10)]
+    shuffle_interval: u64,
+    /// Run the SWIM failure detector (probes, suspicion, dead member dissemination)
+    #[arg(long, default_value_t = false)]
+    swim: bool,
+    /// Milliseconds between SWIM probes
+    #[arg(long, default_value_t = 1000)]
+    probe_interval_ms: u64,
+    /// Milliseconds a SWIM probe waits for an ACK
+    #[arg(long, default_value_t = 500)]
+    probe_timeout_ms: u64,
+    /// Seconds a member stays suspect before it is declared dead
+    #[arg(long, default_value_t = 5)]
+    suspect_timeout: u64,
+    /// Peers asked to probe indirectly when a direct probe fails
+    #[arg(long, default_value_t = 3)]
+    indirect_probes: usize,
+    /// Maximum connections accepted from other nodes
+    #[arg(long, default_value_t = 64)]
+    max_inbound: usize,
+    /// Maximum connections dialed by this node
+    #[arg(long, default_value_t = 32)]
+    max_outbound: usize,
+    /// Maximum connections overall
+    #[arg(long, default_value_t = 96)]
+    max_peers: usize,
+    /// Which inbound peer is evicted when a new node connects while we are full
+    #[arg(long, value_enum, default_value_t = Eviction::None)]
+    eviction: Eviction,
+    /// Longest frame in bytes sent to or accepted from a peer; longer lines drop the connection
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_frame_size: usize,
+    /// Frames queued for a single peer before --send-policy applies
+    #[arg(long, default_value_t = 1024)]
+    send_queue: usize,
+    /// What to do when a peer's send queue is full. Under `block` only this node's own chat,
+    /// direct messages and NICK wait; relayed and protocol frames disconnect a peer whose
+    /// queue is full
+    #[arg(long, value_enum, default_value_t = SendPolicy::Disconnect)]
+    send_policy: SendPolicy,
+    /// Frames per second accepted from a single peer
+    #[arg(long, default_value_t = 50.0)]
+    msg_rate: f64,
+    /// Frames a peer may send in a burst above --msg-rate
+    #[arg(long, default_value_t = 200.0)]
+    msg_burst: f64,
+    /// Bytes per second accepted from a single peer
+    #[arg(long, default_value_t = 256.0 * 1024.0)]
+    byte_rate: f64,
+    /// Bytes a peer may send in a burst above --byte-rate
+    #[arg(long, default_value_t = 1024.0 * 1024.0)]
+    byte_burst: f64,
+    /// Connection attempts per second accepted from one IP
+    #[arg(long, default_value_t = 5.0)]
+    conn_rate: f64,
+    /// Connection attempts one IP may make in a burst above --conn-rate
+    #[arg(long, default_value_t = 20.0)]
+    conn_burst: f64,
+    /// Score at which a misbehaving peer is disconnected and banned
+    #[arg(long, default_value_t = -100, allow_hyphen_values = true)]
+    ban_threshold: i64,
+    /// Seconds a ban lasts
+    #[arg(long, default_value_t = 3600)]
+    ban_duration: u64,
+    /// File the ban list is kept in across restarts
+    #[arg(long, default_value = "bans.json")]
+    bans_file: PathBuf,
+    /// Outbound connection attempts allowed in flight at once
+    #[arg(long, default_value_t = 8)]
+    dial_concurrency: usize,
+    /// Milliseconds to wait for a TCP connect before giving up on a peer
+    #[arg(long, default_value_t = 3000)]
+    connect_timeout_ms: u64,
+    /// Milliseconds to wait for the TLS handshake on peer connections
+    #[arg(long, default_value_t = 3000)]
+    tls_handshake_timeout_ms: u64,
+    /// Milliseconds to spend flushing peer queues and API connections on shutdown
+    #[arg(long, default_value_t = 3000)]
+    shutdown_timeout_ms: u64,
+}
+
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut term) => { term.recv().await; }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Passes a lifecycle state such as `READY=1` to systemd when it started us with
+/// `Type=notify`; does nothing otherwise.
+fn sd_notify(state: &str) {
+    #[cfg(target_os = "linux")]
+    if let Some(path) = std::env::var_os("NOTIFY_SOCKET") {
+        use std::os::{linux::net::SocketAddrExt, unix::net::{SocketAddr as UnixAddr, UnixDatagram}};
+
+        let path = path.to_string_lossy();
+        let addr = match path.strip_prefix('@') {
+            Some(name) => UnixAddr::from_abstract_name(name),
+            None => UnixAddr::from_pathname(&*path),
+        };
+        let res = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+        if let Err(e) = res {
+            warn!("Failed to notify systemd: {}", e);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("{} is not an octal file mode like 600", mode)),
+    }
+}
+
+/// Where the API listens: `--api-bind`, nothing for "off", or localhost on the peer port + 100.
+fn api_bind(args: &Args) -> anyhow::Result<Option<String>> {
+    match args.api_bind.as_deref() {
+        Some("off") => Ok(None),
+        Some(addr) => Ok(Some(addr.to_string())),
+        // The peer port is picked by the OS, so the API's is too.
+        None if args.port == 0 => Ok(Some("127.0.0.1:0".to_string())),
+        None => match args.port.checked_add(100) {
+            Some(port) => Ok(Some(format!("127.0.0.1:{}", port))),
+            None => anyhow::bail!("No default API port above {}, choose one with --api-bind", args.port),
+        },
+    }
+}
+
+/// Copies frontend events to the API, the terminal and the control socket, building a
+/// receiver only for those `wanted`. A consumer that falls 1000 events behind misses events
+/// instead of holding up the others.
+fn fan_out<const N: usize>(mut events: mpsc::Receiver<FrontendEvent>, wanted: [bool; N]) -> [Option<mpsc::Receiver<FrontendEvent>>; N] {
+    let mut txs = Vec::with_capacity(N);
+    let rxs = wanted.map(|wanted| wanted.then(|| {
+        let (tx, rx) = mpsc::channel(1000);
+        txs.push(tx);
+        rx
+    }));
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            txs.retain(|tx| match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    debug!("Frontend event dropped, a consumer is behind");
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+        }
+    });
+    rxs
+}
+
+/// Next stdin line, or never once stdin is closed or not read at all.
+async fn next_line(lines: &mut Option<mpsc::Receiver<String>>) -> String {
+    if let Some(rx) = lines {
+        if let Some(line) = rx.recv().await {
+            return line;
+        }
+        // Without stdin the node keeps running until it is signalled.
+        debug!("stdin closed");
+        *lines = None;
+    }
+    std::future::pending().await
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()>{
-    tracing_subscriber::fmt::init();
-    
-
     let args = Args::parse();
-    let s_listen_addr = format!("127.0.0.1:{}", args.port);
+    if args.tui && !std::io::stdout().is_terminal() {
+        anyhow::bail!("--tui needs a terminal");
+    }
+    let log_pane = args.tui.then(tui::LogPane::default);
+    match &log_pane {
+        Some(pane) => tui::init_logging(pane.clone()),
+        None => tracing_subscriber::fmt::init(),
+    }
+
+    if let Some(uname) = &args.uname {
+        protocol::check_uname(uname).context("Invalid --uname")?;
+    }
+    let api_bind = api_bind(&args)?;
+    if args.api_tls && api_bind.is_none() {
+        anyhow::bail!("--api-tls needs the API, which --api-bind off turns off");
+    }
+    let s_listen_addr = SocketAddr::new(args.host, args.port).to_string();
 
     //server side
     let s_info = PeerSummary { 
@@ -43,10 +298,25 @@ async fn main() -> anyhow::Result<()>{
         node_id: Some(generate_unique_id()),
         uname: args.uname
     };
+
+    // Bound before anything else so a taken port fails startup with an error, and so the
+    // address we announce to peers has the real port when --port is 0.
+    let listener = server::bind(&s_info).await?;
+    let s_info = PeerSummary { listen_addr: Some(listener.local_addr()?.to_string()), ..s_info };
+    let api_listener = match &api_bind {
+        Some(addr) => Some(tokio::net::TcpListener::bind(addr.as_str()).await
+            .with_context(|| format!("Failed to bind API on {}", addr))?),
+        None => None,
+    };
+
     let (web_api_tx, web_api_rx) = mpsc::channel::<FrontendEvent>(1000);
+    // The console and the TUI show the same events the WebSockets get.
+    let [api_events, term_events, control_events] =
+        fan_out(web_api_rx, [api_bind.is_some(), !args.headless, args.control_socket.is_some()]);
     let tls_enabled = args.tls;
     
-    let tls_cert: Option<Arc<TlsCert>> = if tls_enabled { 
+    // The API can use the node's certificate without peers using TLS, and the other way round.
+    let cert: Option<Arc<TlsCert>> = if tls_enabled || args.api_tls { 
         if fs::exists("tls/cert.der").unwrap_or(false) && fs::exists("tls/key.der").unwrap_or(false) {
             let cert_bytes = fs::read("tls/cert.der")?;
             let key_bytes = fs::read("tls/key.der")?;
@@ -64,16 +334,80 @@ async fn main() -> anyhow::Result<()>{
         }
     } else {None};
 
-    let peer_manager = PeerManagerHandle::new(s_info.clone(), web_api_tx, tls_enabled, tls_cert);
-    
+    let overlay = match args.overlay {
+        OverlayKind::Mesh => Overlay::Mesh,
+        OverlayKind::Dht => {
+            let node_id = s_info.node_id.clone().unwrap_or_default();
+            Overlay::Dht(Dht::new(&node_id, args.dht_k, args.dht_alpha)?)
+        }
+        OverlayKind::Hyparview => {
+            let cfg = MembershipConfig::new(
+                args.active_view,
+                args.passive_view,
+                Duration::from_secs(args.shuffle_interval.max(1)),
+            );
+            Overlay::HyParView(Membership::new(cfg))
+        }
+    };
+
+    let swim = args.swim.then(|| Swim::new(SwimConfig {
+        probe_interval: Duration::from_millis(args.probe_interval_ms.max(10)),
+        probe_timeout: Duration::from_millis(args.probe_timeout_ms.max(1)),
+        suspect_timeout: Duration::from_secs(args.suspect_timeout),
+        indirect_probes: args.indirect_probes,
+    }));
+
+    let dial = DialConfig {
+        concurrency: args.dial_concurrency,
+        connect_timeout: Duration::from_millis(args.connect_timeout_ms),
+        tls_handshake_timeout: Duration::from_millis(args.tls_handshake_timeout_ms),
+    };
+
+    let limits = ConnLimits {
+        max_inbound: args.max_inbound,
+        max_outbound: args.max_outbound,
+        max_peers: args.max_peers,
+        eviction: args.eviction,
+        max_frame_size: args.max_frame_size,
+        send_queue: args.send_queue,
+        send_policy: args.send_policy,
+    };
+
+    let bans = Bans::load(BanConfig {
+        threshold: args.ban_threshold,
+        duration: Duration::from_secs(args.ban_duration),
+        path: args.bans_file,
+    });
+
+    let rate_limits = RateLimits {
+        msg_rate: args.msg_rate,
+        msg_burst: args.msg_burst,
+        byte_rate: args.byte_rate,
+        byte_burst: args.byte_burst,
+        conn_rate: args.conn_rate,
+        conn_burst: args.conn_burst,
+    };
+
+    let peer_manager = PeerManagerHandle::new(s_info.clone(), web_api_tx, tls_enabled, if tls_enabled { cert.clone() } else { None }, dial, limits, rate_limits, bans, overlay, swim);
+
+    #[cfg(unix)]
+    let control = match (&args.control_socket, control_events) {
+        (Some(path), Some(events)) => Some((control::bind(path, args.control_mode)?, path.clone(), events)),
+        _ => None,
+    };
+    #[cfg(not(unix))]
+    if args.control_socket.is_some() {
+        anyhow::bail!("--control-socket needs Unix domain sockets");
+    }
+    #[cfg(not(unix))]
+    let _ = control_events;
+
     let server_pm = peer_manager.clone();
     let s_info_copy = s_info.clone();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server_shutdown = shutdown_rx.clone();
 
-    tokio::spawn(async move {
-        if let Err(e) = server::run(s_info_copy, server_pm).await{
-            error!("Error on server side: {}", e)
-        }
-    });
+    let mut server_task = tokio::spawn(server::run(listener, s_info_copy, server_pm, server_shutdown));
     
     //client side
     if let Some(peer_addr) = args.peer{
@@ -93,50 +427,142 @@ async fn main() -> anyhow::Result<()>{
         });
     }
 
-    let api_state = ApiState {
-        peer_manager: peer_manager.clone(),
-        clients: Arc::new(Mutex::new(Vec::new())),
+    if args.discovery {
+        let discovery_cfg = DiscoveryConfig {
+            group: args.discovery_addr,
+            network: args.network.clone(),
+            interval: Duration::from_secs(args.discovery_interval.max(1)),
+        };
+        let discovery_pm = peer_manager.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = discovery::run(discovery_cfg, discovery_pm).await {
+                error!("Discovery stopped: {}", e)
+            }
+        });
+    }
+
+    let api_url = match &api_listener {
+        Some(listener) => Some(format!("{}://{}", if args.api_tls { "https" } else { "http" }, listener.local_addr()?)),
+        None => None,
     };
+    let mut api_task = match (api_listener, api_events) {
+        (Some(listener), Some(api_events)) => {
+            let api_keys = if args.no_auth {
+                warn!("API authentication is off, anyone who can reach {} controls this node", listener.local_addr()?);
+                None
+            } else {
+                Some(Arc::new(ApiKeys::load_or_create(&args.api_keys_file)?))
+            };
 
-    let api_router = web_api::router(api_state, web_api_rx);
+            let api_state = ApiState {
+                peer_manager: peer_manager.clone(),
+                subscribers: Arc::new(Mutex::new(Vec::new())),
+                keys: api_keys,
+                api_url: api_url.clone(),
+            };
+            let api_router = web_api::router(api_state, api_events);
+            let acceptor = match &cert {
+                Some(cert) if args.api_tls => Some(make_acceptor(make_server_config(cert)?)),
+                _ => None,
+            };
+            let handshake_timeout = Duration::from_millis(args.tls_handshake_timeout_ms);
+            Some(tokio::spawn(web_api::serve(listener, api_router, acceptor, handshake_timeout, shutdown_rx.clone())))
+        }
+        _ => None,
+    };
+
+    #[cfg(unix)]
+    let control_task = control.map(|(listener, path, events)| {
+        info!("Control socket on {}", path.display());
+        tokio::spawn(control::run(listener, path, peer_manager.clone(), api_url.clone(), events, shutdown_rx.clone()))
+    });
 
-    let api_addr: SocketAddr = format!("127.0.0.1:{}", args.port + 100)
-        .parse()
-        .expect("Invalid API address");
+    info!("Node {} ready, peers on {}, API {}",
+        s_info.node_id.as_deref().unwrap_or_default(), s_info.listen_addr.as_deref().unwrap_or_default(),
+        api_url.as_deref().map_or("off".to_string(), |url| format!("on {}", url)));
+    sd_notify("READY=1");
 
-    tokio::spawn(async move {
-        debug!("Web API listening on {}", api_addr);
+    let mut tui = None;
+    let (mut lines, output) = match (term_events, log_pane) {
+        (Some(events), Some(logs)) => {
+            let (started, lines, output) = tui::Tui::start(peer_manager.clone(), events, logs)?;
+            tui = Some(started);
+            (Some(lines), output)
+        }
+        (Some(events), None) => {
+            ui::print_events(events);
+            (Some(ui::console_lines(peer_manager.clone())), ui::Output::Stdout)
+        }
+        (None, _) => (None, ui::Output::Stdout),
+    };
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
-        let listener = tokio::net::TcpListener::bind(api_addr)
-            .await
-            .expect("Failed to bind API port");
+    // A listener that stops on its own is fatal: the node can't be reached any more.
+    let mut api_done = false;
+    let fatal = loop {
+        tokio::select! {
+            line = next_line(&mut lines) => {
+                let line_trim = line.trim();
 
-        if let Err(e) = axum::serve(listener, api_router).await {
-            error!("API server error: {}", e);
+                if line_trim.is_empty() { continue; }
+                if let Some(cmd) = line_trim.strip_prefix('/') {
+                    let res = match ui::parse_command(cmd) {
+                        Ok(ui::Command::Quit) => break None,
+                        Ok(cmd) => ui::run_command(cmd, peer_manager.clone(), &output).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = res {
+                        output.line(e.to_string());
+                    }
+                    continue;
+                }
+                if let Err(e) = gossip::send_chat(&peer_manager, &line).await {
+                    error!("Message not sent: {}", e);
+                }
+            }
+            _ = &mut shutdown => break None,
+            res = &mut server_task => {
+                break Some(match res {
+                    Ok(Err(e)) => e.context("Peer listener failed"),
+                    Ok(Ok(())) => anyhow::anyhow!("Peer listener stopped"),
+                    Err(e) => anyhow::anyhow!("Peer listener task failed: {}", e),
+                });
+            }
+            res = async { match api_task.as_mut() { Some(task) => task.await, None => std::future::pending().await } } => {
+                api_done = true;
+                break Some(match res {
+                    Ok(Err(e)) => e.context("API server failed"),
+                    Ok(Ok(())) => anyhow::anyhow!("API server stopped"),
+                    Err(e) => anyhow::anyhow!("API server task failed: {}", e),
+                });
+            }
         }
-    });
+    };
+
+    match &fatal {
+        Some(e) => error!("{:#}, shutting down", e),
+        None => info!("Shutting down"),
+    }
+    sd_notify("STOPPING=1");
+    let timeout = Duration::from_millis(args.shutdown_timeout_ms);
+    let _ = shutdown_tx.send(true);
+    peer_manager.shutdown(timeout).await;
+    peer_manager.bans.lock().unwrap().flush();
+    #[cfg(unix)]
+    if let Some(task) = control_task {
+        let _ = task.await;
+    }
 
-    let stdin = io::BufReader::new(io::stdin());
-    let mut lines = stdin.lines();
-
-    while let Ok(Some(line)) = lines.next_line().await {
-        let line_trim = line.trim();
-        
-        if line_trim.is_empty() { continue; }
-        // if line_trim.starts_with("/"){
-        //         let ui_pm = peer_manager.clone();
-        //         ui::parse_command(
-        //             &line_trim[1..], ui_pm)
-        //             .await;
-        //         continue; 
-        // } else{
-        // }
-        peer_manager.broadcast(format!("MSG|{}", line)).await;
-        
-    }
-    loop {
-        tokio::time::sleep(std::time::Duration::from_secs(3600)).await;
-    }
-
-    // Ok(())
+    // Open WebSockets hold the API server up; they get the same grace period.
+    if let Some(task) = api_task.filter(|_| !api_done)
+        && time::timeout(timeout, task).await.is_err() {
+        warn!("API server did not stop within {:?}", timeout);
+    }
+    info!("Shutdown complete");
+    if let Some(tui) = tui {
+        tui.stop();
+    }
+    fatal.map_or(Ok(()), Err)
 }
\ No newline at end of file
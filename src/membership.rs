@@ -0,0 +1,567 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::{dht::Contact, network::connect_new_peer, peer_manager::{PeerManagerHandle, PeerSummary}};
+
+/// View sizes and timings for the HyParView overlay.
+#[derive(Clone, Debug)]
+pub struct MembershipConfig {
+    pub active_size: usize,
+    pub passive_size: usize,
+    pub shuffle_interval: Duration,
+    /// Active random walk length for FORWARD_JOIN.
+    pub arwl: u32,
+    /// Walk step at which FORWARD_JOIN also stores the new node in the passive view.
+    pub prwl: u32,
+    /// How many active and passive contacts go into a shuffle sample.
+    pub shuffle_active: usize,
+    pub shuffle_passive: usize,
+}
+
+impl MembershipConfig {
+    pub fn new(active_size: usize, passive_size: usize, shuffle_interval: Duration) -> Self {
+        let active_size = active_size.max(1);
+        Self {
+            active_size,
+            passive_size: passive_size.max(active_size),
+            shuffle_interval,
+            arwl: 6,
+            prwl: 3,
+            shuffle_active: 3,
+            shuffle_passive: 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// A node entering the overlay; always accepted and advertised with FORWARD_JOIN.
+    Join,
+    /// Always accepted, evicting a random active peer if needed.
+    High,
+    /// Accepted only while the active view has room.
+    Low,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Neighbor {
+    from: Contact,
+    priority: Priority,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ForwardJoin {
+    node: Contact,
+    sender: String,
+    ttl: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Shuffle {
+    nodes: Vec<Contact>,
+}
+
+#[derive(Serialize)]
+pub struct Views {
+    pub active: Vec<Contact>,
+    pub passive: Vec<Contact>,
+}
+
+/// Where a FORWARD_JOIN goes from this node.
+#[derive(Debug, PartialEq)]
+enum Walk {
+    /// The walk is about us; drop it.
+    Ignore,
+    /// Pass it on to this active neighbour.
+    Forward(String),
+    /// The walk ends here and the new node becomes our active neighbour.
+    End,
+}
+
+#[derive(Default)]
+struct State {
+    active: HashMap<String, Contact>,
+    passive: HashMap<String, Contact>,
+}
+
+/// HyParView-style partial view membership: a small active view of open
+/// connections and a larger passive view of known addresses used for repair.
+pub struct Membership {
+    cfg: MembershipConfig,
+    state: Mutex<State>,
+}
+
+pub fn is_membership_msg(msg: &str) -> bool {
+    ["NEIGHBOR|", "NEIGHBOR_ACCEPT|", "NEIGHBOR_REJECT|", "FORWARD_JOIN|", "DISCONNECT|", "SHUFFLE|", "SHUFFLE_REPLY|"]
+        .iter()
+        .any(|p| msg.starts_with(p))
+}
+
+impl Membership {
+    pub fn new(cfg: MembershipConfig) -> Arc<Self> {
+        Arc::new(Self { cfg, state: Mutex::new(State::default()) })
+    }
+
+    pub fn views(&self) -> Views {
+        let state = self.state.lock().unwrap();
+        Views {
+            active: state.active.values().cloned().collect(),
+            passive: state.passive.values().cloned().collect(),
+        }
+    }
+
+    fn self_contact(pm: &PeerManagerHandle) -> Option<Contact> {
+        Contact::from_summary(&pm.self_peer_info)
+    }
+
+    fn add_passive(&self, state: &mut State, self_id: &str, contact: Contact) {
+        if contact.node_id == self_id
+            || state.active.contains_key(&contact.node_id)
+            || state.passive.contains_key(&contact.node_id) {
+            return;
+        }
+        self.insert_passive(state, contact);
+    }
+
+    /// Inserts into the passive view, dropping a random entry when it is full.
+    fn insert_passive(&self, state: &mut State, contact: Contact) {
+        if state.passive.len() >= self.cfg.passive_size
+            && let Some(victim) = state.passive.keys().choose(&mut rand::rng()).cloned() {
+            state.passive.remove(&victim);
+        }
+        state.passive.insert(contact.node_id.clone(), contact);
+    }
+
+    /// Adds `contact` to the active view. Returns the peer evicted to make room, if any,
+    /// which has been moved to the passive view.
+    fn add_active(&self, state: &mut State, contact: Contact) -> Option<Contact> {
+        if state.active.contains_key(&contact.node_id) {
+            return None;
+        }
+        state.passive.remove(&contact.node_id);
+
+        let mut evicted = None;
+        if state.active.len() >= self.cfg.active_size
+            && let Some(victim) = state.active.keys().choose(&mut rand::rng()).cloned() {
+            evicted = state.active.remove(&victim);
+        }
+        state.active.insert(contact.node_id.clone(), contact);
+        if let Some(victim) = &evicted {
+            self.insert_passive(state, victim.clone());
+        }
+        evicted
+    }
+
+    /// Tells a peer evicted by `add_active` and drops its connection.
+    async fn evict(&self, pm: &PeerManagerHandle, victim: Contact) {
+        debug!("Evicting {} from active view", victim.node_id);
+        let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+        let msg = format!("DISCONNECT|{}", self_id);
+        let _ = pm.send_to(Some(victim.node_id.clone()), None, msg);
+        pm.remove_node(victim.node_id).await;
+    }
+
+    /// Asks a connected peer to add us to its active view. It answers with
+    /// NEIGHBOR_ACCEPT or NEIGHBOR_REJECT.
+    pub fn announce(&self, pm: &PeerManagerHandle, node_id: Option<String>, conn_id: Option<String>, priority: Priority) -> anyhow::Result<()> {
+        let from = Self::self_contact(pm).ok_or_else(|| anyhow::anyhow!("Own contact is incomplete"))?;
+        let msg = format!("NEIGHBOR|{}", serde_json::to_string(&Neighbor { from, priority })?);
+        pm.send_to(node_id, conn_id, msg)
+    }
+
+    /// Dials `contact` (unless already connected) and asks it to become an active neighbour.
+    pub async fn dial(self: Arc<Self>, pm: Arc<PeerManagerHandle>, contact: Contact, priority: Priority) -> anyhow::Result<()> {
+        if self.state.lock().unwrap().active.contains_key(&contact.node_id) {
+            return Ok(());
+        }
+        if pm.get_peer(contact.node_id.clone()).is_some() {
+            return self.announce(&pm, Some(contact.node_id), None, priority);
+        }
+        let self_peer = pm.self_summary();
+        let conn_id = connect_new_peer(&self_peer, contact.listen_addr.clone(), pm.clone()).await?;
+        self.announce(&pm, None, Some(conn_id), priority)
+    }
+
+    /// Passive contacts learnt from a mesh node's PEERS list.
+    pub fn learn(&self, pm: &PeerManagerHandle, summaries: &[PeerSummary]) {
+        let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        for contact in summaries.iter().filter_map(Contact::from_summary) {
+            self.add_passive(&mut state, &self_id, contact);
+        }
+    }
+
+    pub async fn handle_msg(self: Arc<Self>, pm: Arc<PeerManagerHandle>, conn_id: String, msg: String) -> anyhow::Result<()> {
+        let (kind, payload) = msg.split_once('|')
+            .ok_or_else(|| anyhow::anyhow!("Invalid membership message format"))?;
+
+        match kind {
+            "NEIGHBOR" => self.handle_neighbor(pm, conn_id, serde_json::from_str(payload)?).await,
+            "NEIGHBOR_ACCEPT" => {
+                let contact: Contact = serde_json::from_str(payload)?;
+                let evicted = self.add_active(&mut self.state.lock().unwrap(), contact);
+                if let Some(victim) = evicted {
+                    self.evict(&pm, victim).await;
+                }
+                Ok(())
+            }
+            "NEIGHBOR_REJECT" => {
+                let contact: Contact = serde_json::from_str(payload)?;
+                let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+                self.add_passive(&mut self.state.lock().unwrap(), &self_id, contact);
+                Ok(())
+            }
+            "FORWARD_JOIN" => self.handle_forward_join(pm, serde_json::from_str(payload)?),
+            "DISCONNECT" => {
+                let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+                let mut state = self.state.lock().unwrap();
+                if let Some(contact) = state.active.remove(payload) {
+                    self.add_passive(&mut state, &self_id, contact);
+                }
+                Ok(())
+            }
+            "SHUFFLE" => {
+                let shuffle: Shuffle = serde_json::from_str(payload)?;
+                let reply = self.sample(&pm, shuffle.nodes.len(), 0);
+                self.integrate(&pm, shuffle.nodes);
+                let msg = format!("SHUFFLE_REPLY|{}", serde_json::to_string(&Shuffle { nodes: reply })?);
+                pm.send_to(None, Some(conn_id), msg)
+            }
+            "SHUFFLE_REPLY" => {
+                let shuffle: Shuffle = serde_json::from_str(payload)?;
+                self.integrate(&pm, shuffle.nodes);
+                Ok(())
+            }
+            _ => anyhow::bail!("Unknown membership message {}", kind),
+        }
+    }
+
+    async fn handle_neighbor(&self, pm: Arc<PeerManagerHandle>, conn_id: String, req: Neighbor) -> anyhow::Result<()> {
+        let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+        let me = serde_json::to_string(&Self::self_contact(&pm))?;
+
+        let full = self.state.lock().unwrap().active.len() >= self.cfg.active_size;
+        if req.priority == Priority::Low && full {
+            debug!("Rejecting low priority neighbour {}", req.from.node_id);
+            pm.send_to(None, Some(conn_id.clone()), format!("NEIGHBOR_REJECT|{}", me))?;
+            self.add_passive(&mut self.state.lock().unwrap(), &self_id, req.from);
+            pm.remove_conn(conn_id).await;
+            return Ok(());
+        }
+
+        let evicted = self.add_active(&mut self.state.lock().unwrap(), req.from.clone());
+        if let Some(victim) = evicted {
+            self.evict(&pm, victim).await;
+        }
+        pm.send_to(None, Some(conn_id), format!("NEIGHBOR_ACCEPT|{}", me))?;
+
+        if req.priority == Priority::Join {
+            let others: Vec<String> = self.state.lock().unwrap().active.keys()
+                .filter(|id| **id != req.from.node_id)
+                .cloned()
+                .collect();
+            let fwd = ForwardJoin { node: req.from, sender: self_id, ttl: self.cfg.arwl };
+            let msg = format!("FORWARD_JOIN|{}", serde_json::to_string(&fwd)?);
+            for node_id in others {
+                let _ = pm.send_to(Some(node_id), None, msg.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the walk's node in the passive view at step `prwl` and picks where it goes next.
+    fn walk(&self, self_id: &str, fwd: &ForwardJoin) -> Walk {
+        if fwd.node.node_id == self_id {
+            return Walk::Ignore;
+        }
+        let mut state = self.state.lock().unwrap();
+        if fwd.ttl == self.cfg.prwl {
+            self.add_passive(&mut state, self_id, fwd.node.clone());
+        }
+        if fwd.ttl == 0 || state.active.len() <= 1 {
+            return Walk::End;
+        }
+        state.active.keys()
+            .filter(|id| **id != fwd.sender && **id != fwd.node.node_id)
+            .choose(&mut rand::rng())
+            .map_or(Walk::End, |id| Walk::Forward(id.clone()))
+    }
+
+    fn handle_forward_join(self: Arc<Self>, pm: Arc<PeerManagerHandle>, fwd: ForwardJoin) -> anyhow::Result<()> {
+        let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+        match self.walk(&self_id, &fwd) {
+            Walk::Ignore => Ok(()),
+            Walk::Forward(node_id) => {
+                let fwd = ForwardJoin { node: fwd.node, sender: self_id, ttl: fwd.ttl - 1 };
+                let msg = format!("FORWARD_JOIN|{}", serde_json::to_string(&fwd)?);
+                pm.send_to(Some(node_id), None, msg)
+            }
+            Walk::End => {
+                // The walk ends here: the new node becomes one of our active neighbours.
+                let node = fwd.node;
+                tokio::spawn(async move {
+                    if let Err(e) = self.dial(pm, node, Priority::High).await {
+                        warn!("Failed to accept forwarded join: {}", e);
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Called when the connection to `node_id` is gone.
+    pub fn on_disconnected(self: Arc<Self>, pm: Arc<PeerManagerHandle>, node_id: &str) {
+        let was_active = self.state.lock().unwrap().active.remove(node_id).is_some();
+        if was_active {
+            debug!("Active peer {} failed, promoting from passive view", node_id);
+            tokio::spawn(self.repair(pm));
+        }
+    }
+
+    /// Takes a random passive contact to promote while the active view has room. With no
+    /// active neighbours left the request must not be refused, so it goes out as High.
+    fn promotion(&self) -> Option<(Contact, Priority)> {
+        let mut state = self.state.lock().unwrap();
+        if state.active.len() >= self.cfg.active_size {
+            return None;
+        }
+        let id = state.passive.keys().choose(&mut rand::rng()).cloned()?;
+        let priority = if state.active.is_empty() { Priority::High } else { Priority::Low };
+        state.passive.remove(&id).map(|contact| (contact, priority))
+    }
+
+    /// Fills the active view from the passive view until it is full or candidates run out.
+    async fn repair(self: Arc<Self>, pm: Arc<PeerManagerHandle>) {
+        while let Some((candidate, priority)) = self.promotion() {
+            debug!("Promoting {} with {:?} priority", candidate.node_id, priority);
+            if let Err(e) = self.clone().dial(pm.clone(), candidate, priority).await {
+                debug!("Passive peer unreachable: {}", e);
+                continue;
+            }
+            // A low priority request may still be rejected; the next tick retries.
+            if priority == Priority::Low {
+                return;
+            }
+        }
+    }
+
+    fn sample(&self, pm: &PeerManagerHandle, len: usize, active: usize) -> Vec<Contact> {
+        let state = self.state.lock().unwrap();
+        let mut rng = rand::rng();
+        let mut nodes = state.active.values().cloned().choose_multiple(&mut rng, active);
+        nodes.extend(state.passive.values().cloned().choose_multiple(&mut rng, len.saturating_sub(nodes.len())));
+        if let Some(me) = Self::self_contact(pm) {
+            nodes.push(me);
+        }
+        nodes
+    }
+
+    fn integrate(&self, pm: &PeerManagerHandle, nodes: Vec<Contact>) {
+        let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        for contact in nodes {
+            self.add_passive(&mut state, &self_id, contact);
+        }
+    }
+
+    /// Periodic shuffle with a random active neighbour, plus active view repair.
+    pub async fn maintenance_loop(self: Arc<Self>, pm: Arc<PeerManagerHandle>) {
+        let mut ticker = time::interval(self.cfg.shuffle_interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            self.clone().repair(pm.clone()).await;
+
+            let target = self.state.lock().unwrap().active.keys().choose(&mut rand::rng()).cloned();
+            let Some(target) = target else { continue };
+
+            let len = self.cfg.shuffle_active + self.cfg.shuffle_passive;
+            let mut nodes = self.sample(&pm, len, self.cfg.shuffle_active);
+            nodes.retain(|c| c.node_id != target);
+
+            match serde_json::to_string(&Shuffle { nodes }) {
+                Ok(json) => {
+                    if let Err(e) = pm.send_to(Some(target), None, format!("SHUFFLE|{}", json)) {
+                        debug!("Shuffle failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to encode shuffle: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ME: &str = "me";
+
+    fn membership(active_size: usize, passive_size: usize) -> Arc<Membership> {
+        Membership::new(MembershipConfig::new(active_size, passive_size, Duration::from_secs(10)))
+    }
+
+    fn contact(id: usize) -> Contact {
+        Contact { node_id: format!("n{}", id), listen_addr: format!("127.0.0.1:{}", 9000 + id) }
+    }
+
+    fn activate(m: &Membership, ids: impl IntoIterator<Item = usize>) {
+        let mut state = m.state.lock().unwrap();
+        for id in ids {
+            m.add_active(&mut state, contact(id));
+        }
+    }
+
+    fn fill_passive(m: &Membership, ids: impl IntoIterator<Item = usize>) {
+        let mut state = m.state.lock().unwrap();
+        for id in ids {
+            m.add_passive(&mut state, ME, contact(id));
+        }
+    }
+
+    fn sizes(m: &Membership) -> (usize, usize) {
+        let state = m.state.lock().unwrap();
+        (state.active.len(), state.passive.len())
+    }
+
+    fn forward(id: usize, sender: &str, ttl: u32) -> ForwardJoin {
+        ForwardJoin { node: contact(id), sender: sender.to_string(), ttl }
+    }
+
+    #[test]
+    fn config_keeps_passive_at_least_as_large_as_active() {
+        let cfg = MembershipConfig::new(0, 2, Duration::from_secs(1));
+        assert_eq!(cfg.active_size, 1);
+        let cfg = MembershipConfig::new(5, 2, Duration::from_secs(1));
+        assert_eq!(cfg.passive_size, 5);
+    }
+
+    #[test]
+    fn views_stay_within_bounds() {
+        let m = membership(3, 5);
+        activate(&m, 0..10);
+        fill_passive(&m, 100..120);
+        assert_eq!(sizes(&m), (3, 5));
+    }
+
+    #[test]
+    fn passive_view_skips_self_and_known_contacts() {
+        let m = membership(3, 5);
+        activate(&m, [1]);
+        {
+            let mut state = m.state.lock().unwrap();
+            m.add_passive(&mut state, ME, Contact { node_id: ME.to_string(), listen_addr: "127.0.0.1:1".to_string() });
+            m.add_passive(&mut state, ME, contact(1));
+            m.add_passive(&mut state, ME, contact(2));
+            m.add_passive(&mut state, ME, contact(2));
+        }
+        assert_eq!(sizes(&m), (1, 1));
+    }
+
+    #[test]
+    fn eviction_moves_the_victim_to_the_passive_view() {
+        let m = membership(2, 4);
+        activate(&m, [1, 2]);
+        let evicted = m.add_active(&mut m.state.lock().unwrap(), contact(3)).unwrap();
+
+        let state = m.state.lock().unwrap();
+        assert!(evicted.node_id == "n1" || evicted.node_id == "n2");
+        assert!(state.active.contains_key("n3"));
+        assert!(!state.active.contains_key(&evicted.node_id));
+        assert!(state.passive.contains_key(&evicted.node_id));
+    }
+
+    #[test]
+    fn activating_a_passive_contact_removes_it_from_the_passive_view() {
+        let m = membership(2, 4);
+        fill_passive(&m, [1]);
+        assert!(m.add_active(&mut m.state.lock().unwrap(), contact(1)).is_none());
+        assert_eq!(sizes(&m), (1, 0));
+        // Already active: no change and nothing evicted.
+        assert!(m.add_active(&mut m.state.lock().unwrap(), contact(1)).is_none());
+        assert_eq!(sizes(&m), (1, 0));
+    }
+
+    #[test]
+    fn forward_join_about_self_is_ignored() {
+        let m = membership(3, 5);
+        activate(&m, [1, 2]);
+        let fwd = ForwardJoin { node: Contact { node_id: ME.to_string(), listen_addr: "127.0.0.1:1".to_string() }, sender: "n1".to_string(), ttl: 3 };
+        assert_eq!(m.walk(ME, &fwd), Walk::Ignore);
+    }
+
+    #[test]
+    fn forward_join_goes_on_while_ttl_lasts() {
+        let m = membership(4, 8);
+        activate(&m, [1, 2, 3]);
+        // Never back to the sender or to the joining node itself.
+        for _ in 0..20 {
+            assert_eq!(m.walk(ME, &forward(3, "n1", 5)), Walk::Forward("n2".to_string()));
+        }
+        assert_eq!(m.walk(ME, &forward(9, "n1", 0)), Walk::End);
+    }
+
+    #[test]
+    fn forward_join_ends_without_other_neighbours() {
+        let m = membership(4, 8);
+        activate(&m, [1]);
+        assert_eq!(m.walk(ME, &forward(9, "n1", 5)), Walk::End);
+
+        activate(&m, [2]);
+        assert_eq!(m.walk(ME, &forward(2, "n1", 5)), Walk::End);
+    }
+
+    #[test]
+    fn forward_join_is_stored_in_the_passive_view_at_prwl() {
+        let m = membership(4, 8);
+        activate(&m, [1, 2]);
+        let prwl = m.cfg.prwl;
+
+        m.walk(ME, &forward(8, "n1", prwl + 1));
+        m.walk(ME, &forward(8, "n1", prwl - 1));
+        assert!(!m.state.lock().unwrap().passive.contains_key("n8"));
+
+        m.walk(ME, &forward(8, "n1", prwl));
+        assert!(m.state.lock().unwrap().passive.contains_key("n8"));
+    }
+
+    #[test]
+    fn promotion_takes_from_the_passive_view() {
+        let m = membership(2, 4);
+        assert!(m.promotion().is_none());
+
+        fill_passive(&m, [1, 2]);
+        let (first, priority) = m.promotion().unwrap();
+        assert_eq!(priority, Priority::High);
+        assert!(!m.state.lock().unwrap().passive.contains_key(&first.node_id));
+
+        activate(&m, [5]);
+        let (_, priority) = m.promotion().unwrap();
+        assert_eq!(priority, Priority::Low);
+        assert_eq!(sizes(&m), (1, 0));
+        assert!(m.promotion().is_none());
+    }
+
+    #[test]
+    fn no_promotion_while_active_view_is_full() {
+        let m = membership(2, 4);
+        activate(&m, [1, 2]);
+        fill_passive(&m, [3]);
+        assert!(m.promotion().is_none());
+        assert_eq!(sizes(&m), (2, 1));
+    }
+
+    #[test]
+    fn membership_frames_are_recognised() {
+        assert!(is_membership_msg("DISCONNECT|n1"));
+        assert!(is_membership_msg("SHUFFLE_REPLY|{}"));
+        assert!(!is_membership_msg("MSG|hi"));
+    }
+}
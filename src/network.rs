@@ -1,12 +1,32 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use crate::peer_manager::{PeerEntry, PeerSummary, generate_unique_id};
+use crate::peer_manager::{Admission, Direction, EventKind, PeerEntry, PeerSummary, generate_unique_id};
 use crate::tls_utils::{make_client_config, make_connector, server_name};
 use crate::{peer_manager::PeerManagerHandle};
 use crate::protocol::{send_join};
 use tracing::{warn, error, debug};
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, time};
 
+/// Limits for outbound connection attempts.
+#[derive(Clone, Debug)]
+pub struct DialConfig {
+    /// Dials allowed in flight at once across the whole node.
+    pub concurrency: usize,
+    pub connect_timeout: Duration,
+    pub tls_handshake_timeout: Duration,
+}
+
+/// Keeps an address marked as being dialed until the attempt is over.
+struct Dialing<'a> {
+    pm: &'a PeerManagerHandle,
+    listen_addr: String,
+}
+
+impl Drop for Dialing<'_> {
+    fn drop(&mut self) {
+        self.pm.finish_dial(&self.listen_addr);
+    }
+}
 
 pub async fn connect_new_peer(self_peer: &PeerSummary, listen_addr: String, pm: Arc<PeerManagerHandle>) -> anyhow::Result<String> {
     let l_addr_copy = listen_addr.clone();
@@ -16,11 +36,34 @@ pub async fn connect_new_peer(self_peer: &PeerSummary, listen_addr: String, pm:
         anyhow::bail!("Cannot connect to itself {}", listen_addr)
     }
 
-    if pm.contains_listen_addr(listen_addr.clone()).await {
+    if let Ok(addr) = listen_addr.parse::<std::net::SocketAddr>()
+        && pm.bans.lock().unwrap().is_banned_ip(addr.ip()) {
+        anyhow::bail!("{} is banned", listen_addr)
+    }
+
+    // Two dials to the same address from one side would leave duplicates the tie-break can't order.
+    if !pm.start_dial(&listen_addr) {
+        anyhow::bail!("Already dialing {}", listen_addr)
+    }
+    let _dialing = Dialing { pm: &pm, listen_addr: listen_addr.clone() };
+
+    let cfg = pm.dial_config();
+    // Held until the handshake is done so a burst of new addresses can't open unbounded sockets.
+    let _slot = pm.dial_slots().acquire_owned().await?;
+
+    // Checked after waiting for a slot since another dial may have reached the peer meanwhile.
+    if pm.contains_listen_addr(listen_addr.clone()) {
         anyhow::bail!("Peer is already in list")
     }
 
-    match TcpStream::connect(listen_addr.clone()).await {
+    let Admission::Accept(_slot) = pm.admit(true).await else {
+        anyhow::bail!("Outbound connection limit reached, not dialing {}", listen_addr)
+    };
+
+    let connect = time::timeout(cfg.connect_timeout, TcpStream::connect(listen_addr.clone())).await
+        .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")));
+
+    match connect {
         Ok(socket) => {
             if pm.tls_enabled() {
                 let server_cert = pm.tls_cert().unwrap().certs[0].clone(); 
@@ -30,7 +73,10 @@ pub async fn connect_new_peer(self_peer: &PeerSummary, listen_addr: String, pm:
                 let host = listen_addr.split(":").next().ok_or_else(|| anyhow::anyhow!("Invalid listen addr"))?;
                 let sname = server_name(host)?;
                 
-                match connector.connect(sname, socket).await {
+                let handshake = time::timeout(cfg.tls_handshake_timeout, connector.connect(sname, socket)).await
+                    .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out")));
+
+                match handshake {
                     Ok(tls_stream) => {
                         let summary = PeerSummary {
                             remote_addr: None,
@@ -41,7 +87,7 @@ pub async fn connect_new_peer(self_peer: &PeerSummary, listen_addr: String, pm:
             
                         let conn_id = generate_unique_id();
 
-                        let entry = PeerEntry::new(conn_id.clone(), summary, tls_stream, pm.events_tx());
+                        let entry = PeerEntry::new(conn_id.clone(), summary, tls_stream, true, pm.peer_limiter(), pm.send_queue(), pm.events_tx());
                         pm.add_entry(conn_id.clone(), entry).await?;
             
                         let client_info = self_peer.clone();
@@ -53,6 +99,7 @@ pub async fn connect_new_peer(self_peer: &PeerSummary, listen_addr: String, pm:
                     Err(e) => {
                         let err_text = format!("TLS handshake failed to {}: {}", listen_addr, e);
                         error!("{}", err_text);
+                        pm.emit(EventKind::HandshakeFailed { addr: listen_addr, direction: Direction::Outbound, error: e.to_string() });
                         anyhow::bail!(err_text);
                     }
                 }
@@ -65,7 +112,7 @@ pub async fn connect_new_peer(self_peer: &PeerSummary, listen_addr: String, pm:
                 };
     
                 let conn_id = generate_unique_id();
-                pm.add_conn(conn_id.clone(), summary, socket).await?;
+                pm.add_conn(conn_id.clone(), summary, socket, true).await?;
     
                 let client_info = self_peer.clone();
                 send_join(client_info, conn_id.clone(), pm.clone()).await?;
@@ -77,20 +124,26 @@ pub async fn connect_new_peer(self_peer: &PeerSummary, listen_addr: String, pm:
         Err(e) => { 
             let err_text = format!("Failed to connect to {}: {}", listen_addr, e);
             error!("{}", err_text);
+            pm.emit(EventKind::ConnectFailed { addr: listen_addr, error: e.to_string() });
             anyhow::bail!(err_text);
         }
     }
 }
 
 
+/// Dials every address in the background; `connect_new_peer` bounds how many run at once.
 pub async fn handle_peer_list(pm: Arc<PeerManagerHandle>, peer_list: Vec<String>) -> anyhow::Result<()>{
-    let self_peer = pm.self_peer_info.clone();
+    let self_peer = pm.self_summary();
 
     for listen_addr in peer_list {
-        debug!("Connecting new peer {}", listen_addr);
-        if let Err(e) = connect_new_peer(&self_peer, listen_addr, pm.clone()).await {
-            warn!("Failed to connect to peer: {}", e);
-        }
+        let pm = pm.clone();
+        let self_peer = self_peer.clone();
+        tokio::spawn(async move {
+            debug!("Connecting new peer {}", listen_addr);
+            if let Err(e) = connect_new_peer(&self_peer, listen_addr, pm).await {
+                warn!("Failed to connect to peer: {}", e);
+            }
+        });
     }
 
     Ok(())
@@ -1,22 +1,178 @@
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpStream, sync::{mpsc::{Receiver, Sender}, oneshot, RwLock}};
-use std::{collections::HashMap, sync::{Arc}};
+use tokio::{net::TcpStream, sync::{mpsc::{Receiver, Sender}, oneshot, Notify, RwLock, Semaphore}};
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering}}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use clap::ValueEnum;
+use rand::seq::IteratorRandom;
 use uuid::Uuid;
 use tracing::{warn, debug, error, info};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, split, AsyncWriteExt, BufReader, AsyncBufReadExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, split, AsyncWriteExt, BufReader, BufWriter, AsyncBufReadExt},
     sync::{mpsc},
+    task::JoinHandle,
+    time,
 };
 
-use crate::{protocol::{handle_join_json, handle_peers_json}, tls_utils};
+use crate::{bans::{self, Ban, Bans, SCORE_MAX, Violation}, rate_limit::{LimitKind, PeerLimiter, RateLimits}, send_queue::{Frame, Push, QueueStats, SendPolicy, SendQueue}, stats::Stats, dht::Dht, gossip::{self, SeenCache}, membership::{self, Membership}, known_peers::KnownPeers, network::DialConfig, protocol::{self, handle_join_json, handle_peers_json}, swim::{self, Swim}, tls_utils};
 
-#[derive(Clone, serde::Serialize)]
-pub enum FrontendEvent {
-    PeerJoined(String),
-    PeerDisconnected(String),
-    MessageReceived { from: String, content: String },
+/// Something the frontends hear about, stamped with when this node saw it. Serialized as the
+/// kind's fields next to `kind` and `at_ms`; `frontend/events.schema.json` describes them all.
+#[derive(Clone, Serialize)]
+pub struct FrontendEvent {
+    /// Unix time in milliseconds.
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
 }
 
+impl From<EventKind> for FrontendEvent {
+    fn from(kind: EventKind) -> Self {
+        let at_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default();
+        Self { at_ms, kind }
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A node registered with JOIN for the first time since we started.
+    PeerJoined { peer: PeerInfo },
+    /// A node we were connected to before registered again.
+    PeerReconnected { peer: PeerInfo },
+    PeerDisconnected { peer: PeerInfo, connected_secs: u64 },
+    /// The peer announced it was shutting down.
+    PeerLeft { peer: PeerInfo, connected_secs: u64 },
+    /// Chat to everyone. `from` is the node that wrote it, `via` the peer it arrived from.
+    MessageReceived { message_id: String, from: String, uname: Option<String>, via: String, content: String },
+    /// Sent with DM to this node only.
+    DirectMessage { message_id: String, from: String, uname: Option<String>, content: String },
+    /// This node sent chat, to everyone or with DM to `to`. Receivers see the same id only for gossip.
+    MessageSent { message_id: String, to: Option<String>, to_uname: Option<String>, content: String },
+    PeerRenamed { node_id: String, old: Option<String>, uname: String },
+    /// `source` exceeded a rate limit and `dropped` frames or connections were refused.
+    RateLimited { source: String, limit: LimitKind, dropped: u64 },
+    /// Dialing `addr` failed before there was a connection.
+    ConnectFailed { addr: String, error: String },
+    /// The connection was made but its TLS handshake or JOIN failed.
+    HandshakeFailed { addr: String, direction: Direction, error: String },
+    PeerBanned(Ban),
+}
+
+/// Which side dialed a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    pub fn of(outbound: bool) -> Self {
+        if outbound { Direction::Outbound } else { Direction::Inbound }
+    }
+}
+
+/// A registered peer as events describe it.
+#[derive(Clone, Serialize)]
+pub struct PeerInfo {
+    pub node_id: String,
+    pub uname: Option<String>,
+    pub listen_addr: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub direction: Direction,
+}
+
+impl PeerInfo {
+    fn new(node_id: &str, summary: &PeerSummary, entry: &PeerEntry) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            uname: summary.uname.clone(),
+            listen_addr: summary.listen_addr.clone(),
+            ip: entry.ip,
+            direction: Direction::of(entry.outbound),
+        }
+    }
+}
+
+/// How nodes find each other and which connections they keep.
+#[derive(Clone)]
+pub enum Overlay {
+    /// Every node connects to every node it hears about via PEERS.
+    Mesh,
+    /// Connections follow a Kademlia routing table; chat is flooded over it.
+    Dht(Arc<Dht>),
+    /// HyParView active/passive views; chat is flooded over the active view.
+    HyParView(Arc<Membership>),
+}
+
+impl Overlay {
+    pub fn relays_chat(&self) -> bool {
+        !matches!(self, Overlay::Mesh)
+    }
+
+    /// Only the full mesh tells its peers about every membership change.
+    pub fn exchanges_peers(&self) -> bool {
+        matches!(self, Overlay::Mesh)
+    }
+
+    pub fn dht(&self) -> Option<Arc<Dht>> {
+        match self {
+            Overlay::Dht(dht) => Some(dht.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn membership(&self) -> Option<Arc<Membership>> {
+        match self {
+            Overlay::HyParView(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Which registered peer makes room when the inbound slots are full.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Eviction {
+    /// Reject new connections with BUSY instead.
+    None,
+    Oldest,
+    LowestScore,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    pub max_peers: usize,
+    pub eviction: Eviction,
+    /// Longest frame in bytes, without the newline, sent or accepted.
+    pub max_frame_size: usize,
+    /// Frames queued per peer before `send_policy` applies.
+    pub send_queue: usize,
+    pub send_policy: SendPolicy,
+}
+
+pub enum Admission {
+    /// Holds the slot until the connection is in the tables.
+    Accept(Slot),
+    /// No slot is free; carries a few of our peers the caller can try instead.
+    Busy(Vec<PeerSummary>),
+}
+
+/// A connection slot reserved by `admit`, so that connections still in their TLS handshake or
+/// dial count against the limits. Released on drop.
+pub struct Slot {
+    reserved: Arc<AtomicUsize>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// How many alternate peers a BUSY frame suggests.
+const BUSY_ALTERNATES: usize = 8;
+
 pub fn generate_unique_id() -> String{
     Uuid::new_v4().to_string()
 }
@@ -42,92 +198,277 @@ impl PeerSummary {
 
 }
 
-#[derive(Clone)]
+/// What `/whois` shows about a registered peer's connection.
+pub struct PeerConn {
+    pub summary: PeerSummary,
+    pub outbound: bool,
+    pub ip: Option<IpAddr>,
+    pub connected_for: Duration,
+    pub score: i64,
+    pub queue: QueueStats,
+}
+
+/// Buffer the writer gathers queued frames in, so a backlog goes out in few writes.
+const WRITE_BUFFER: usize = 64 * 1024;
+
+/// How long a retired duplicate connection waits for the other side to close it.
+const RETIRE_GRACE: Duration = Duration::from_secs(5);
+
 pub struct PeerEntry {
     conn_id: String,                 
     summary: Arc<RwLock<PeerSummary>>,            
-    tx: mpsc::Sender<String>,
+    queue: Arc<SendQueue>,
+    /// True when we dialed this connection.
+    outbound: bool,
+    connected_at: Instant,
+    /// Address the connection came from (or was dialed to), used for IP bans.
+    ip: Option<IpAddr>,
+    /// Grows with valid frames up to `SCORE_MAX` and shrinks on protocol violations.
+    score: Arc<AtomicI64>,
+    closed: Arc<Notify>,
+    drain: Arc<Notify>,
+    /// Set once the other side announced it is retiring this connection as a duplicate.
+    remote_retired: Arc<AtomicBool>,
+    /// Taken on shutdown to wait for the queue to be written out.
+    writer: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl PeerEntry {
-    pub fn new<S>(conn_id: String, summary:PeerSummary, socket: S, events_tx: mpsc::Sender<PeerEvent>) -> Arc<Self>
+    pub fn new<S>(conn_id: String, summary:PeerSummary, socket: S, outbound: bool, limiter: PeerLimiter, queue: Arc<SendQueue>, events_tx: mpsc::Sender<PeerEvent>) -> Arc<Self>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
         let (reader, writer) = split(socket);
-        let (tx, rx) = mpsc::channel::<String>(60);
         
-        let entry = Arc::new(Self { conn_id, summary: Arc::new(RwLock::new(summary)), tx });
+        let ip = summary.remote_addr.as_ref().or(summary.listen_addr.as_ref())
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .map(|addr| addr.ip());
+        let drain = Arc::new(Notify::new());
+        let entry = Arc::new(Self { conn_id, summary: Arc::new(RwLock::new(summary)), queue: queue.clone(), outbound, connected_at: Instant::now(), ip, score: Arc::new(AtomicI64::new(0)), closed: Arc::new(Notify::new()), drain: drain.clone(), remote_retired: Arc::new(AtomicBool::new(false)), writer: Mutex::new(None) });
         let entry_clone = entry.clone();
+        let max_frame_size = limiter.max_frame_size;
 
-        Self::spawn_reader(entry_clone, reader, events_tx.clone());
-        Self::spawn_writer(writer, rx);
+        Self::spawn_reader(entry_clone, reader, limiter, events_tx.clone());
+        *entry.writer.lock().unwrap() = Some(Self::spawn_writer(writer, queue, drain, max_frame_size));
 
         entry
     }
 
-    pub fn spawn_writer<W>(mut writer: W, mut rx: mpsc::Receiver<String>) 
+    pub fn spawn_writer<W>(writer: W, queue: Arc<SendQueue>, drain: Arc<Notify>, max_frame_size: usize) -> JoinHandle<()>
     where 
         W: AsyncWrite + Unpin + Send + 'static
     {
         tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if let Err(e) = writer.write_all(msg.as_bytes()).await {
-                    error!("Peer writer error: {}", e);
-                    break;
+            let mut writer = BufWriter::with_capacity(WRITE_BUFFER, writer);
+            loop {
+                let frame = tokio::select! {
+                    frame = queue.pop() => frame,
+                    // Retired: write out whatever is already queued, then stop.
+                    _ = drain.notified() => {
+                        for frame in queue.close() {
+                            if Self::write_frame(&mut writer, &frame, max_frame_size).await.is_err() {
+                                break;
+                            }
+                        }
+                        let _ = writer.write_all(b"RETIRE|\n").await;
+                        let _ = writer.flush().await;
+                        None
+                    }
+                };
+                let Some(frame) = frame else { break };
+
+                // Frames that queued up during the last write go out with this one.
+                let mut res = Self::write_frame(&mut writer, &frame, max_frame_size).await;
+                while res.is_ok() && let Some(frame) = queue.try_pop() {
+                    res = Self::write_frame(&mut writer, &frame, max_frame_size).await;
                 }
-                if let Err(e) = writer.write_all(b"\n").await {
-                    error!("Peer writer newline error: {}", e);
+                if let Err(e) = res.and(writer.flush().await) {
+                    error!("Peer writer error: {}", e);
                     break;
                 }
             }
+            // Nothing is written past this point, so stop blocked senders from waiting on us.
+            queue.close();
+            let _ = writer.shutdown().await;
+        })
+    }
+
+    async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut BufWriter<W>, frame: &str, max_frame_size: usize) -> std::io::Result<()> {
+        if frame.len() > max_frame_size {
+            // The peer would drop the connection over it, so it never goes out.
+            error!("Not sending {} byte frame, the maximum is {}", frame.len(), max_frame_size);
+            return Ok(());
+        }
+        writer.write_all(frame.as_bytes()).await?;
+        writer.write_all(b"\n").await
+    }
+
+    /// Graceful close of a duplicate connection: flush and shut down our side, keep
+    /// reading until the other end does the same, and give up after a grace period.
+    pub fn retire(self: &Arc<Self>) {
+        self.drain.notify_one();
+        let entry = self.clone();
+        tokio::spawn(async move {
+            time::sleep(RETIRE_GRACE).await;
+            entry.close();
         });
     }
 
-    pub fn spawn_reader<R>(self: Arc<Self>, mut reader: R, events_tx: mpsc::Sender<PeerEvent>)
+    /// Queues `msg` under the send policy, closing the connection if the peer can't keep up.
+    async fn send(&self, msg: Frame) -> anyhow::Result<()> {
+        let pushed = self.queue.push(msg).await;
+        self.pushed(pushed)
+    }
+
+    /// Like `send`, but never waits: under the block policy a full queue closes the connection.
+    fn try_send(&self, msg: Frame) -> anyhow::Result<()> {
+        self.pushed(self.queue.try_push(msg))
+    }
+
+    fn pushed(&self, pushed: Push) -> anyhow::Result<()> {
+        match pushed {
+            Push::Queued | Push::DroppedOldest => Ok(()),
+            Push::Full => {
+                warn!("Send queue to {} is full, disconnecting slow peer", self.conn_id);
+                self.close();
+                anyhow::bail!("send queue to {} is full", self.conn_id)
+            }
+            Push::Stalled => {
+                warn!("Send queue to {} is full and a relayed or protocol frame can't wait under --send-policy block, disconnecting stalled peer", self.conn_id);
+                self.close();
+                anyhow::bail!("send queue to {} is full", self.conn_id)
+            }
+            Push::Closed => anyhow::bail!("connection {} is closing", self.conn_id),
+        }
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Stops the reader; the writer flushes what is queued and then closes the socket.
+    pub fn close(&self) {
+        self.closed.notify_one();
+    }
+
+    pub fn spawn_reader<R>(self: Arc<Self>, mut reader: R, mut limiter: PeerLimiter, events_tx: mpsc::Sender<PeerEvent>)
     where 
         R: AsyncRead + Unpin + Send + 'static
     {
         tokio::spawn(async move {
             let mut buf = BufReader::new(&mut reader);
-            let mut line = String::new();
+            let mut bytes = Vec::new();
+            let max_frame_size = limiter.max_frame_size;
 
             loop {
-                line.clear();
+                bytes.clear();
+                let conn_id = self.conn_id.clone();
                 
+                // Reads at most one byte past the limit, so a line without a newline can't grow unbounded.
+                let mut frame = (&mut buf).take(max_frame_size as u64 + 1);
+                let read = tokio::select! {
+                    res = frame.read_until(b'\n', &mut bytes) => res,
+                    _ = self.closed.notified() => {
+                        let node_id = self.summary.read().await.node_id.clone().unwrap_or_default();
+                        debug!("Closing connection {}", conn_id);
+                        let _ = events_tx
+                            .send(PeerEvent::Disconnected { node_id: node_id.clone(), conn_id: conn_id.clone(), retired: false }).await;
+                        break;
+                    }
+                };
+
+                // Read after the line arrives: the JOIN that sets it may have been registered while we waited.
                 let node_id = {
                     let summary = self.summary.read().await;
                     summary.node_id.clone().unwrap_or_default()
                 };
 
-                let conn_id = self.conn_id.clone();
-                
-                match buf.read_line(&mut line).await {
+                match read {
                     Ok(0) => {
+                        let retired = self.remote_retired.load(Ordering::SeqCst);
+                        let _ = events_tx
+                            .send(PeerEvent::Disconnected { node_id: node_id.clone(), conn_id: conn_id.clone(), retired }).await;
+                        break;
+                    }
+
+                    Ok(n) if n - usize::from(bytes.ends_with(b"\n")) > max_frame_size => {
+                        warn!("Frame from {} exceeds {} bytes, disconnecting", conn_id, max_frame_size);
+                        let _ = events_tx
+                            .send(PeerEvent::Misbehaved { conn_id: conn_id.clone(), violation: Violation::Oversized }).await;
                         let _ = events_tx
-                            .send(PeerEvent::Disconnected { node_id: node_id.clone() }).await;
+                            .send(PeerEvent::Disconnected { node_id: node_id.clone(), conn_id: conn_id.clone(), retired: false }).await;
                         break;
                     }
 
                     Ok(_) => {
+                        let Ok(line) = std::str::from_utf8(&bytes) else {
+                            let _ = events_tx
+                                .send(PeerEvent::Error { node_id: node_id.clone(), error: "frame is not valid UTF-8".to_string() })
+                                .await;
+                            let _ = events_tx
+                                .send(PeerEvent::Disconnected { node_id: node_id.clone(), conn_id: conn_id.clone(), retired: false }).await;
+                            break;
+                        };
                         let msg = line.trim().to_string();
                         if msg.is_empty() { continue; }
-                        let res = (async || { 
-                            if msg.starts_with("JOIN|"){
-                                events_tx
-                                    .send(PeerEvent::Join { conn_id: conn_id.clone(), msg: msg }).await
-                            } else if msg.starts_with("PEERS|") {
-                                events_tx
-                                    .send(PeerEvent::Peers { msg: msg }).await
-                            } else if msg.starts_with("MSG") {
-                                events_tx
-                                    .send(PeerEvent::Message { node_id: node_id.clone(), msg: msg }).await
-                            }
-                            else {
-                                warn!("Could not process msg {}", msg);
-                                Ok(())
+                        if let Some(limit) = limiter.check(line.len()) {
+                            if let Some(dropped) = limiter.report.dropped() {
+                                let source = if node_id.is_empty() { conn_id.clone() } else { node_id.clone() };
+                                let _ = events_tx
+                                    .send(PeerEvent::RateLimited { conn_id: Some(conn_id.clone()), source, limit, dropped }).await;
                             }
-                        })().await;
+                            continue;
+                        }
+                        if self.score.load(Ordering::Relaxed) < SCORE_MAX {
+                            self.score.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if msg == "RETIRE|" {
+                            debug!("Connection {} retired by {}", conn_id, node_id);
+                            self.remote_retired.store(true, Ordering::SeqCst);
+                            continue;
+                        }
+                        if msg == "LEAVE|" {
+                            let _ = events_tx
+                                .send(PeerEvent::Left { node_id: node_id.clone(), conn_id: conn_id.clone() }).await;
+                            continue;
+                        }
+                        let res = if msg.starts_with("JOIN|"){
+                            events_tx
+                                .send(PeerEvent::Join { conn_id: conn_id.clone(), msg }).await
+                        } else if protocol::is_peer_exchange_msg(&msg) {
+                            events_tx
+                                .send(PeerEvent::Peers { conn_id: conn_id.clone(), msg }).await
+                        } else if msg.starts_with("MSG") || msg.starts_with("DM|") {
+                            events_tx
+                                .send(PeerEvent::Message { node_id: node_id.clone(), msg }).await
+                        } else if msg.starts_with("NICK|") {
+                            events_tx
+                                .send(PeerEvent::Nick { node_id: node_id.clone(), msg }).await
+                        } else if msg.starts_with("GOSSIP|") {
+                            events_tx
+                                .send(PeerEvent::Gossip { node_id: node_id.clone(), msg }).await
+                        } else if msg.starts_with("FIND_NODE|") {
+                            events_tx
+                                .send(PeerEvent::FindNode { conn_id: conn_id.clone(), msg }).await
+                        } else if msg.starts_with("NODES|") {
+                            events_tx
+                                .send(PeerEvent::Nodes { msg }).await
+                        } else if swim::is_swim_msg(&msg) {
+                            events_tx
+                                .send(PeerEvent::Swim { conn_id: conn_id.clone(), node_id: node_id.clone(), msg }).await
+                        } else if msg.starts_with("BUSY|") {
+                            // Rejected connections never JOIN, so name them by the address we dialed.
+                            let addr = self.summary.read().await.listen_addr.clone().unwrap_or_default();
+                            events_tx
+                                .send(PeerEvent::Busy { addr, msg }).await
+                        } else if membership::is_membership_msg(&msg) {
+                            events_tx
+                                .send(PeerEvent::Membership { conn_id: conn_id.clone(), msg }).await
+                        } else {
+                            warn!("Could not process msg {}", msg);
+                            events_tx
+                                .send(PeerEvent::Misbehaved { conn_id: conn_id.clone(), violation: Violation::UnknownFrame }).await
+                        };
 
                         if let Err(e) = res{
                             warn!("PeerEvent channel closed {}", e);
@@ -139,6 +480,8 @@ impl PeerEntry {
                         let _ = events_tx
                             .send(PeerEvent::Error { node_id: node_id.clone(), error: e.to_string() })
                             .await;
+                        let _ = events_tx
+                            .send(PeerEvent::Disconnected { node_id: node_id.clone(), conn_id: conn_id.clone(), retired: false }).await;
                         break;
                     }
                 }
@@ -149,12 +492,84 @@ impl PeerEntry {
 
 }
 
+/// Readers' copy of the actor's connection tables. The actor updates it entry by entry as it
+/// changes the tables, so lookups, listings and sends never wait behind other commands.
+#[derive(Default)]
+pub struct Directory {
+    /// Registered peers by node id, with the summary they registered with.
+    peers: HashMap<String, (Arc<PeerEntry>, PeerSummary)>,
+    /// Every connection, registered or not, by conn id.
+    conns: HashMap<String, (Arc<PeerEntry>, PeerSummary)>,
+    /// Connections per listen address.
+    listen_addrs: HashMap<String, usize>,
+}
+
+impl Directory {
+    fn insert_conn(&mut self, entry: &Arc<PeerEntry>, summary: PeerSummary) {
+        self.remove_conn(&entry.conn_id);
+        if let Some(addr) = &summary.listen_addr {
+            *self.listen_addrs.entry(addr.clone()).or_default() += 1;
+        }
+        self.conns.insert(entry.conn_id.clone(), (entry.clone(), summary));
+    }
+
+    fn remove_conn(&mut self, conn_id: &str) {
+        let Some((_, summary)) = self.conns.remove(conn_id) else { return };
+        if let Some(addr) = summary.listen_addr
+            && let Some(count) = self.listen_addrs.get_mut(&addr) {
+            *count -= 1;
+            if *count == 0 {
+                self.listen_addrs.remove(&addr);
+            }
+        }
+    }
+
+    fn insert_peer(&mut self, node_id: &str, entry: &Arc<PeerEntry>, summary: PeerSummary) {
+        self.insert_conn(entry, summary.clone());
+        self.peers.insert(node_id.to_string(), (entry.clone(), summary));
+    }
+
+    fn remove_peer(&mut self, node_id: &str) {
+        if let Some((entry, _)) = self.peers.remove(node_id) {
+            self.remove_conn(&entry.conn_id);
+        }
+    }
+
+    fn rename(&mut self, node_id: &str, uname: String) {
+        if let Some((entry, summary)) = self.peers.get(node_id) {
+            let summary = PeerSummary { uname: Some(uname), ..summary.clone() };
+            self.insert_peer(node_id, &entry.clone(), summary);
+        }
+    }
+}
+
+impl Drop for PeerEntry {
+    /// Like dropping a channel sender: the writer sends what is queued, then closes the socket.
+    fn drop(&mut self) {
+        self.queue.finish();
+    }
+}
+
 pub enum PeerEvent {
     Message { node_id: String, msg: String },
     Join { conn_id: String, msg: String },
-    Peers { msg: String },
+    Peers { conn_id: String, msg: String },
+    Gossip { node_id: String, msg: String },
+    Nick { node_id: String, msg: String },
+    FindNode { conn_id: String, msg: String },
+    Nodes { msg: String },
+    Membership { conn_id: String, msg: String },
+    Busy { addr: String, msg: String },
+    Misbehaved { conn_id: String, violation: Violation },
+    /// `conn_id` is unset for connection attempts refused before a connection existed.
+    RateLimited { conn_id: Option<String>, source: String, limit: LimitKind, dropped: u64 },
+    Swim { conn_id: String, node_id: String, msg: String },
+    #[allow(dead_code)]
     Connected { node_id: String },
-    Disconnected { node_id: String },
+    /// `retired` is set when the other side closed it as a duplicate of another connection.
+    Disconnected { node_id: String, conn_id: String, retired: bool },
+    /// The other side sent LEAVE before shutting down.
+    Left { node_id: String, conn_id: String },
     Error { node_id: String, error: String },
 }
 
@@ -163,6 +578,7 @@ enum Command {
         conn_id: String,
         summary: PeerSummary,
         socket: TcpStream,
+        outbound: bool,
         resp: oneshot::Sender<anyhow::Result<()>>,
     },
     AddEntry {
@@ -178,40 +594,35 @@ enum Command {
 
     RemoveConn {
         conn_id: String,
+        resp: oneshot::Sender<Option<(PeerInfo, Duration)>>,
     },
 
     RemoveNode {
         node_id: String,
     },
 
-    Broadcast {
-        msg: String,
+    Rename {
+        node_id: String,
+        uname: String,
     },
 
-    SendTo {
-        node_id: Option<String>,
-        conn_id: Option<String>,
-        msg: String,
-        resp: oneshot::Sender<anyhow::Result<()>>,
+    SendPeerList {
+        conn_id: String,
     },
 
-    GetPeers {
-        resp: oneshot::Sender<Vec<PeerSummary>>,
-    },
-    
-    GetPeer {
-        node_id: String,
-        resp: oneshot::Sender<anyhow::Result<PeerSummary>>,
+    Admit {
+        outbound: bool,
+        resp: oneshot::Sender<Admission>,
     },
 
-    GetConn {
-        conn_id: String,
-        resp: oneshot::Sender<anyhow::Result<PeerSummary>>,
+    Penalize {
+        node_id: Option<String>,
+        conn_id: Option<String>,
+        violation: Violation,
     },
 
-    ContainsListenAddr {
-        listen_addr: String,
-        resp: oneshot::Sender<bool>,
+    Shutdown {
+        resp: oneshot::Sender<Vec<JoinHandle<()>>>,
     },
 
 }
@@ -220,73 +631,306 @@ enum Command {
 pub struct PeerManagerHandle {
     tx: mpsc::Sender<Command>,
     events_tx: mpsc::Sender<PeerEvent>,
+    frontend_tx: mpsc::Sender<FrontendEvent>,
+    /// Node ids that registered before, to tell reconnects from first joins.
+    joined: Arc<Mutex<SeenCache>>,
     tls_enabled: bool,
     tls_cert: Option<Arc<tls_utils::TlsCert>>,
-    pub self_peer_info: PeerSummary 
+    dial: DialConfig,
+    limits: ConnLimits,
+    dial_slots: Arc<Semaphore>,
+    dialing: Arc<Mutex<HashSet<String>>>,
+    /// Set on shutdown; no connection is admitted after that.
+    shutting_down: Arc<AtomicBool>,
+    /// Slots admitted but not yet in the tables, inbound and outbound.
+    reserved: [Arc<AtomicUsize>; 2],
+    directory: Arc<std::sync::RwLock<Directory>>,
+    /// Woken whenever the directory changes.
+    published: Arc<Notify>,
+    pub self_peer_info: PeerSummary,
+    /// Our current name; starts as `self_peer_info.uname` and changes with `/nick`.
+    uname: Arc<Mutex<Option<String>>>,
+    pub overlay: Overlay,
+    pub seen: Arc<Mutex<SeenCache>>,
+    pub known_peers: Arc<Mutex<KnownPeers>>,
+    pub bans: Arc<Mutex<Bans>>,
+    pub stats: Arc<Stats>,
+    rate_limits: RateLimits,
+    pub swim: Option<Arc<Swim>>,
 }
 
 impl PeerManagerHandle {
-    pub fn new(self_peer_info: PeerSummary, web_api_tx: Sender<FrontendEvent>, tls_enabled: bool, tls_cert: Option<Arc<tls_utils::TlsCert>>) -> Arc<Self>  {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(self_peer_info: PeerSummary, web_api_tx: Sender<FrontendEvent>, tls_enabled: bool, tls_cert: Option<Arc<tls_utils::TlsCert>>, dial: DialConfig, limits: ConnLimits, rate_limits: RateLimits, bans: Bans, overlay: Overlay, swim: Option<Arc<Swim>>) -> Arc<Self>  {
         let (tx, rx) = mpsc::channel::<Command>(256);
         let (events_tx, events_rx) = mpsc::channel::<PeerEvent>(1000);
 
         let events_tx_c = events_tx.clone();
-        let handle = Arc::new(Self { tx, events_tx: events_tx_c, tls_enabled, tls_cert, self_peer_info });
+        let seen = Arc::new(Mutex::new(SeenCache::new()));
+        let known_peers = Arc::new(Mutex::new(KnownPeers::new(self_peer_info.node_id.clone().unwrap_or_default())));
+        let dial_slots = Arc::new(Semaphore::new(dial.concurrency.max(1)));
+        let uname = Arc::new(Mutex::new(self_peer_info.uname.clone()));
+        let bans = Arc::new(Mutex::new(bans));
+        bans::spawn_writer(bans.clone());
+        let handle = Arc::new(Self { tx, events_tx: events_tx_c, frontend_tx: web_api_tx, joined: Arc::new(Mutex::new(SeenCache::new())), tls_enabled, tls_cert, dial, limits, dial_slots, dialing: Arc::new(Mutex::new(HashSet::new())), shutting_down: Arc::default(), reserved: Default::default(), directory: Arc::default(), published: Arc::default(), self_peer_info, uname, overlay, seen, known_peers, bans, stats: Arc::new(Stats::default()), rate_limits, swim });
         let handle_clone = Arc::clone(&handle);
 
-        Self::spawn_peer_event_handler(handle_clone, events_rx, web_api_tx);
+        Self::spawn_peer_event_handler(handle_clone, events_rx);
         tokio::spawn(Self::command_loop(handle.clone(), rx, events_tx));
 
+        if let Some(dht) = handle.overlay.dht() {
+            tokio::spawn(dht.refresh_loop(handle.clone()));
+        }
+        if let Some(membership) = handle.overlay.membership() {
+            tokio::spawn(membership.maintenance_loop(handle.clone()));
+        }
+        if let Some(swim) = handle.swim.clone() {
+            tokio::spawn(swim.probe_loop(handle.clone()));
+        }
+
         handle
     }
     
+    pub fn uname(&self) -> Option<String> {
+        self.uname.lock().unwrap().clone()
+    }
+
+    /// `self_peer_info` with the current name, for JOIN and peer lists.
+    pub fn self_summary(&self) -> PeerSummary {
+        PeerSummary { uname: self.uname(), ..self.self_peer_info.clone() }
+    }
+
     pub fn events_tx(&self) -> mpsc::Sender<PeerEvent> {
         self.events_tx.clone()
     }
 
+    /// Passes an event on to the frontends. It is dropped if they have fallen 1000 events
+    /// behind, so a stuck consumer can't stall the peers.
+    pub fn emit(&self, kind: EventKind) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.frontend_tx.try_send(kind.into()) {
+            debug!("Frontend event dropped, consumers are behind");
+        }
+    }
+
     pub fn tls_enabled(&self) -> bool {
-        self.tls_enabled.clone()
+        self.tls_enabled
     }
 
     pub fn tls_cert(&self) -> Option<Arc<tls_utils::TlsCert>> {
         self.tls_cert.clone()
     }
 
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limits
+    }
+
+    /// Message and byte buckets for a new connection.
+    pub fn peer_limiter(&self) -> PeerLimiter {
+        PeerLimiter::new(&self.rate_limits, self.limits.max_frame_size, self.stats.clone())
+    }
+
+    /// Outbound frame queue for a new connection.
+    pub fn send_queue(&self) -> Arc<SendQueue> {
+        SendQueue::new(self.limits.send_queue, self.limits.send_policy, self.stats.clone())
+    }
+
+    /// Fails with `FrameTooLarge` or `LineBreak` if peers would reject `frame`.
+    pub fn check_frame(&self, frame: &str) -> anyhow::Result<()> {
+        if frame.len() > self.limits.max_frame_size {
+            return Err(protocol::FrameTooLarge { size: frame.len(), max: self.limits.max_frame_size }.into());
+        }
+        if frame.contains(['\n', '\r']) {
+            return Err(protocol::LineBreak.into());
+        }
+        Ok(())
+    }
+
+    pub fn dial_config(&self) -> &DialConfig {
+        &self.dial
+    }
+
+    pub fn dial_slots(&self) -> Arc<Semaphore> {
+        self.dial_slots.clone()
+    }
+
+    /// Marks `listen_addr` as being dialed. Returns false if a dial to it is already running.
+    pub fn start_dial(&self, listen_addr: &str) -> bool {
+        self.dialing.lock().unwrap().insert(listen_addr.to_string())
+    }
+
+    pub fn finish_dial(&self, listen_addr: &str) {
+        self.dialing.lock().unwrap().remove(listen_addr);
+    }
+
     async fn command_loop(handle: Arc<Self>, mut rx: mpsc::Receiver<Command>, events_tx: Sender<PeerEvent>) {
         let mut conns: HashMap<String, Arc<PeerEntry>> = HashMap::new();
         let mut peers: HashMap<String, Arc<PeerEntry>> = HashMap::new();
+        // Bumped whenever a node is added to or removed from `peers`.
+        let mut version: u64 = 0;
 
         while let Some(cmd) = rx.recv().await {
-            handle.handle_command(cmd, &mut conns, &mut peers, events_tx.clone()).await;
+            handle.handle_command(cmd, &mut conns, &mut peers, &mut version, events_tx.clone()).await;
+        }
+    }
+
+    /// Applies a table change to the directory readers see. Called before answering the
+    /// command that made it, so the caller's next lookup already sees the change.
+    fn publish(&self, update: impl FnOnce(&mut Directory)) {
+        update(&mut self.directory.write().unwrap());
+        self.published.notify_waiters();
+    }
+
+    fn directory(&self) -> std::sync::RwLockReadGuard<'_, Directory> {
+        self.directory.read().unwrap()
+    }
+
+    /// Sends `to` the full list of our other peers as of `version`.
+    fn send_peer_list_to(&self, version: u64, to: &PeerEntry) {
+        let summaries: Vec<PeerSummary> = self.directory().peers.values()
+            .filter(|(entry, _)| entry.conn_id != to.conn_id)
+            .map(|(_, summary)| summary.clone())
+            .collect();
+
+        let from = self.self_peer_info.node_id.clone().unwrap_or_default();
+        match protocol::peers_payload(from, version, summaries) {
+            Ok(msg) => { let _ = to.try_send(msg.into()); }
+            Err(e) => error!("Failed to encode peer list: {}", e),
+        }
+    }
+
+    /// Sends a PEER_ADDED / PEER_REMOVED frame to every peer except the one it is about.
+    fn publish_delta(peers: &HashMap<String, Arc<PeerEntry>>, about: &str, msg: anyhow::Result<String>) {
+        match msg {
+            Ok(msg) => {
+                let frame = Frame::from(msg);
+                for (node_id, entry) in peers.iter() {
+                    if node_id != about {
+                        let _ = entry.try_send(frame.clone());
+                    }
+                }
+            }
+            Err(e) => error!("Failed to encode peer delta: {}", e),
+        }
+    }
+
+    fn peer_removed(&self, peers: &HashMap<String, Arc<PeerEntry>>, version: &mut u64, node_id: &str) {
+        *version += 1;
+        if self.overlay.exchanges_peers() {
+            let from = self.self_peer_info.node_id.clone().unwrap_or_default();
+            let msg = protocol::peer_removed_payload(from, *version, node_id.to_string());
+            Self::publish_delta(peers, node_id, msg);
+        }
+    }
+
+    /// Tie-break between two connections to the same node. A simultaneous connect keeps the
+    /// stream initiated by the lower node_id; a second dial from the same side is a reconnect
+    /// and replaces the old stream.
+    fn prefer_new_conn(&self, node_id: &str, existing: &PeerEntry, new: &PeerEntry) -> bool {
+        if existing.outbound == new.outbound {
+            return true;
+        }
+        let self_id = self.self_peer_info.node_id.as_deref().unwrap_or_default();
+        new.outbound == (self_id < node_id)
+    }
+
+    /// Checks the slot limits for a new connection, evicting an inbound peer if the policy allows.
+    /// The evicted peer leaves the tables before its slot is handed out, so they never hold
+    /// more than the limits allow.
+    async fn check_slots(&self, conns: &HashMap<String, Arc<PeerEntry>>, peers: &mut HashMap<String, Arc<PeerEntry>>, version: &mut u64, outbound: bool) -> Admission {
+        let reserved = &self.reserved[usize::from(outbound)];
+        let same_direction = conns.values().chain(peers.values()).filter(|e| e.outbound == outbound).count()
+            + reserved.load(Ordering::SeqCst);
+        let total = conns.len() + peers.len() + self.reserved.iter().map(|r| r.load(Ordering::SeqCst)).sum::<usize>();
+        let max = if outbound { self.limits.max_outbound } else { self.limits.max_inbound };
+        if same_direction < max && total < self.limits.max_peers {
+            return self.reserve(outbound);
+        }
+
+        let alternates = self.alternates();
+
+        // Outbound peers are never evicted: we picked them, a stranger dialing in did not.
+        if !outbound && let Some(node_id) = self.eviction_candidate(peers) {
+            info!("Connection limit reached, evicting {}", node_id);
+            if let Some(entry) = peers.get(&node_id) {
+                match protocol::busy_payload(alternates) {
+                    Ok(msg) => { let _ = entry.try_send(msg.into()); }
+                    Err(e) => error!("Failed to encode BUSY: {}", e),
+                }
+            }
+            self.drop_node(peers, version, &node_id).await;
+            return self.reserve(outbound);
+        }
+
+        debug!("Connection limit reached, rejecting {} connection", if outbound { "outbound" } else { "inbound" });
+        Admission::Busy(alternates)
+    }
+
+    fn reserve(&self, outbound: bool) -> Admission {
+        let reserved = self.reserved[usize::from(outbound)].clone();
+        reserved.fetch_add(1, Ordering::SeqCst);
+        Admission::Accept(Slot { reserved })
+    }
+
+    fn eviction_candidate(&self, peers: &HashMap<String, Arc<PeerEntry>>) -> Option<String> {
+        let inbound = peers.iter().filter(|(_, e)| !e.outbound);
+        let candidate = match self.limits.eviction {
+            Eviction::None => None,
+            Eviction::Oldest => inbound.min_by_key(|(_, e)| e.connected_at),
+            Eviction::LowestScore => inbound.min_by_key(|(_, e)| e.score.load(Ordering::Relaxed)),
+        };
+        candidate.map(|(node_id, _)| node_id.clone())
+    }
+
+    /// Drops a registered node from the tables and closes its connection. Its reader stopping
+    /// later finds nothing to remove, so the event is emitted here.
+    async fn drop_node(&self, peers: &mut HashMap<String, Arc<PeerEntry>>, version: &mut u64, node_id: &str) {
+        if let Some(entry) = peers.remove(node_id) {
+            entry.close();
+            self.publish(|dir| dir.remove_peer(node_id));
+            self.peer_removed(peers, version, node_id);
+            let peer = PeerInfo::new(node_id, &*entry.summary.read().await, &entry);
+            self.emit(EventKind::PeerDisconnected { peer, connected_secs: entry.connected_at.elapsed().as_secs() });
         }
     }
 
+    fn alternates(&self) -> Vec<PeerSummary> {
+        self.directory().peers.values()
+            .filter(|(_, summary)| summary.listen_addr.is_some())
+            .choose_multiple(&mut rand::rng(), BUSY_ALTERNATES)
+            .into_iter()
+            .map(|(_, summary)| summary.clone())
+            .collect()
+    }
+
     async fn handle_command(
         &self,
         cmd: Command,
         conns: &mut HashMap<String, Arc<PeerEntry>>,
         peers: &mut HashMap<String, Arc<PeerEntry>>,
+        version: &mut u64,
         events_tx: Sender<PeerEvent>
     ) {
         match cmd {
-            Command::AddConn { conn_id, summary, socket, resp } => {
+            Command::AddConn { conn_id, summary, socket, outbound, resp } => {
                 let res = (|| {
                     if conns.contains_key(&conn_id) {
                         anyhow::bail!("conn already exists");
                     }
-                    let entry = PeerEntry::new(conn_id.clone(), summary, socket, events_tx);
+                    let entry = PeerEntry::new(conn_id.clone(), summary.clone(), socket, outbound, self.peer_limiter(), self.send_queue(), events_tx);
+                    self.publish(|dir| dir.insert_conn(&entry, summary));
                     conns.insert(conn_id, entry);
-                    
                     Ok(())
                 })();
                 let _ = resp.send(res);
             }
 
             Command::AddEntry { conn_id, entry, resp } => {
+                let summary = entry.summary.read().await.clone();
                 let res = (|| {
                     if conns.contains_key(&conn_id) {
                         anyhow::bail!("conn already exists");
                     }
+                    self.publish(|dir| dir.insert_conn(&entry, summary));
                     conns.insert(conn_id, entry);
                     Ok(())
                 })();
@@ -294,7 +938,7 @@ impl PeerManagerHandle {
             }
 
             Command::RegisterNode { conn_id, summary, resp } => {
-                let res: Result<(), anyhow::Error> = (|| async {
+                let res: anyhow::Result<()> = async {
                     let summary_entry = summary.clone();
                     let node_id = &summary_entry
                         .node_id
@@ -309,181 +953,293 @@ impl PeerManagerHandle {
                             *s = summary.clone();
                         };
 
-                        if let Some(old) = peers.remove(node_id) {
-                            warn!("Replacing existing peer with the same node_id {} ", node_id);
-                            drop(old);
+                        // Both ends of a simultaneous connect apply the same rule, so they keep the same stream.
+                        if let Some(existing) = peers.get(node_id)
+                            && !self.prefer_new_conn(node_id, existing, &entry) {
+                            debug!("Keeping connection {} to {}, retiring duplicate {}", existing.conn_id, node_id, conn_id);
+                            entry.retire();
+                            self.publish(|dir| dir.remove_conn(&conn_id));
+                            return Ok(());
                         }
-                        
-                        peers.insert(node_id.clone(), entry);
-                        Ok(())
-                    } else {
-                        if let Some(entry) = peers.get_mut(node_id){
-                            let entry = Arc::clone(entry);
 
-                            tokio::spawn(async move {
-                                let mut s = entry.summary.write().await;
-                                *s = summary.clone();
+                        let replaced = peers.remove(node_id);
+                        if let Some(old) = &replaced {
+                            debug!("Replacing connection {} to {} with {}", old.conn_id, node_id, conn_id);
+                            old.retire();
+                        }
+
+                        peers.insert(node_id.clone(), entry.clone());
+                        self.publish(|dir| {
+                            if let Some(old) = &replaced {
+                                dir.remove_conn(&old.conn_id);
+                            }
+                            dir.insert_peer(node_id, &entry, summary.clone());
+                        });
+
+                        // A new node gets our full list once; everyone else only hears about the change.
+                        if replaced.is_none() {
+                            *version += 1;
+                            let peer = PeerInfo::new(node_id, &summary, &entry);
+                            self.emit(match self.joined.lock().unwrap().insert(node_id) {
+                                true => EventKind::PeerJoined { peer },
+                                false => EventKind::PeerReconnected { peer },
                             });
+                        }
+                        if self.overlay.exchanges_peers() {
+                            if replaced.is_none() {
+                                let from = self.self_peer_info.node_id.clone().unwrap_or_default();
+                                let msg = protocol::peer_added_payload(from, *version, summary.clone());
+                                Self::publish_delta(peers, node_id, msg);
+                            }
+                            self.send_peer_list_to(*version, &entry);
+                        }
+                        Ok(())
+                    } else {
+                        if let Some(entry) = peers.get(node_id){
+                            *entry.summary.write().await = summary.clone();
+                            self.publish(|dir| dir.insert_peer(node_id, entry, summary));
                             Ok(())
                         } else {
                             anyhow::bail!("conn_id not found and node_id not present");
                         }
                     }
-                })().await;
+                }.await;
                 let _ = resp.send(res);
             }
         
-            Command::RemoveConn { conn_id } => {
+            Command::RemoveConn { conn_id, resp } => {
+                let mut removed = None;
                 if let Some(entry) = conns.remove(&conn_id) {
                     debug!("Dropping connection for {}", conn_id);
-                    drop(entry);
+                    entry.close();
+                    self.publish(|dir| dir.remove_conn(&conn_id));
                 } else {
                     let maybe_key = peers.iter()
                         .find_map(|(k, v)| if v.conn_id == conn_id { Some(k.clone()) } else { None });
-                    if let Some(node_id) = maybe_key {
-                        peers.remove(&node_id);
-                        debug!("Dropped connection from peers {}", node_id)
-                    } 
+                    if let Some(node_id) = maybe_key
+                        && let Some(entry) = peers.remove(&node_id) {
+                        entry.close();
+                        debug!("Dropped connection from peers {}", node_id);
+                        self.publish(|dir| dir.remove_peer(&node_id));
+                        self.peer_removed(peers, version, &node_id);
+                        let peer = PeerInfo::new(&node_id, &*entry.summary.read().await, &entry);
+                        removed = Some((peer, entry.connected_at.elapsed()));
+                    }
                 };
+                let _ = resp.send(removed);
             }
             Command::RemoveNode { node_id } => {
-                if peers.remove(&node_id).is_some() {
-                    debug!("Removed node {}", node_id)
+                debug!("Removing node {}", node_id);
+                self.drop_node(peers, version, &node_id).await;
+            }
+            Command::Rename { node_id, uname } => {
+                if let Some(entry) = peers.get(&node_id) {
+                    entry.summary.write().await.uname = Some(uname.clone());
+                    self.publish(|dir| dir.rename(&node_id, uname));
                 }
             }
-            Command::Broadcast { msg } => {
-                for (node_id, entry) in peers.iter() {
-                    let send = entry.tx.clone();
-                    let m = msg.clone();
-                    let node_id = node_id.clone();
-                    tokio::spawn(async move{
-                        if let Err(e) = send.send(m.clone()).await {
-                            warn!("Failed to send a message to peer {} message {} and e {}", node_id, m, e);
-                        }
-                    });
+            Command::Penalize { node_id, conn_id, violation } => {
+                let entry = match (node_id, conn_id) {
+                    (Some(node_id), _) => peers.get(&node_id),
+                    (None, Some(conn_id)) => conns.get(&conn_id)
+                        .or_else(|| peers.values().find(|e| e.conn_id == conn_id)),
+                    (None, None) => None,
                 };
-            }
-            Command::SendTo { node_id, conn_id, msg, resp } => {
-                let res = (|| {
-                    let entry = (|| -> anyhow::Result<Option<&Arc<PeerEntry>>> {
-                        if let Some(node_id) = node_id {
-                            Ok(peers.get(&node_id))
-                        } else if let Some(conn_id) = conn_id {
-                            Ok(conns.get(&conn_id))
-                        } else {
-                            anyhow::bail!("No id to send_to was passed {}", msg)
-                        }
-                    })()?;
-                    if let Some(entry) = entry{
-                        let send = entry.tx.clone();
-                        let m = msg.clone();
-                        tokio::spawn(async move{
-                            if let Err(e) = send.send(m.clone()).await {
-                                warn!("Failed to send a message to peer message {} and e {}", m, e);
-                            }
-                        });
-
-                        Ok(())
-                    } else {
-                        anyhow::bail!("Could not find node to send a messageand msg = {}", msg)
+                let Some(entry) = entry else { return };
+
+                let score = entry.score.fetch_sub(violation.penalty(), Ordering::Relaxed) - violation.penalty();
+                let summary = entry.summary.read().await.clone();
+                let name = summary.node_id.clone().unwrap_or_else(|| entry.conn_id.clone());
+                debug!("Peer {} penalized for {}, score {}", name, violation.reason(), score);
+
+                let mut bans = self.bans.lock().unwrap();
+                if score <= bans.threshold() {
+                    warn!("Banning peer {} ({:?}): {}", name, entry.ip, violation.reason());
+                    if let Some(ban) = bans.ban(summary.node_id, entry.ip, violation.reason()) {
+                        self.emit(EventKind::PeerBanned(ban));
                     }
-                })();
-                let _ = resp.send(res);
+                    // Removal goes through the usual Disconnected path once the reader stops.
+                    entry.close();
+                }
             }
-
-            Command::GetPeers { resp } => {
-                let mut v = Vec::with_capacity(peers.len());
+            Command::Admit { outbound, resp } => {
+                let _ = resp.send(self.check_slots(conns, peers, version, outbound).await);
+            }
+            Command::Shutdown { resp } => {
+                // Registered peers get LEAVE; connections that never JOINed are just closed.
                 for entry in peers.values() {
-                    let summary = entry.summary.read().await.clone();
-                    v.push(summary);
+                    let _ = entry.try_send(Frame::from("LEAVE|"));
                 }
-
-                let _ = resp.send(v);
-            }
-
-            Command::ContainsListenAddr { listen_addr, resp } => {
-                let mut found = false;
-                for e in peers.values() {
-                    let summary = e.summary.read().await;
-                    if summary.listen_addr.as_ref().map(|s| s.as_str()) == Some(listen_addr.as_str()) {
-                        found = true; break
-                    }
-                };
-
-                for e in conns.values() {
-                    let summary = e.summary.read().await;
-                    if summary.listen_addr.as_ref().map(|s| s.as_str()) == Some(listen_addr.as_str()) {
-                        found = true; break
-                    }
-                };
-
-                let _ = resp.send(found);
+                let mut writers = Vec::new();
+                for (_, entry) in peers.drain().chain(conns.drain()) {
+                    entry.close();
+                    entry.queue.finish();
+                    writers.extend(entry.writer.lock().unwrap().take());
+                }
+                self.publish(|dir| *dir = Directory::default());
+                let _ = resp.send(writers);
             }
-
-            Command::GetConn { conn_id, resp } => {
-                let res = (|| async {
-                    if let Some(entry) = conns.get(&conn_id) {
-                        let summary = entry.summary.read().await.clone();
-                        Ok(summary)
-                    } else {
-                        anyhow::bail!("No connection found by id: {}", conn_id)
-                    }
-                })().await;
-
-                let _ = resp.send(res);
+            Command::SendPeerList { conn_id } => {
+                if let Some(entry) = peers.values().find(|e| e.conn_id == conn_id) {
+                    self.send_peer_list_to(*version, entry);
+                }
             }
+        }
+    }
 
-            Command::GetPeer { node_id, resp } => {
-                let res = (|| async {
-                    if let Some(entry) = peers.get(&node_id){
-                        let summary = entry.summary.read().await.clone();
-                        Ok(summary)
-                    } else {
-                        anyhow::bail!("No connection found by id: {}", node_id)
-                    }
-                })().await;
-
-                let _ = resp.send(res);
-            }
+    /// Forgets what the overlays knew through a node whose connection was removed.
+    fn peer_gone(self: &Arc<Self>, node_id: &str) {
+        self.known_peers.lock().unwrap().forget(node_id);
+        if let Some(dht) = self.overlay.dht() {
+            dht.remove(node_id);
+        }
+        if let Some(membership) = self.overlay.membership() {
+            membership.on_disconnected(self.clone(), node_id);
         }
     }
 
-    fn spawn_peer_event_handler(self: Arc<Self>, mut events_rx: Receiver<PeerEvent>, web_api_tx: Sender<FrontendEvent>) {
+    fn spawn_peer_event_handler(self: Arc<Self>, mut events_rx: Receiver<PeerEvent>) {
         tokio::spawn(async move {
             while let Some(event) = events_rx.recv().await {
                 match event {
                     PeerEvent::Message { node_id, msg } => {
                         debug!("Received from {}: {}", node_id, msg);
-                        let peer = self.get_peer(node_id).await.clone();
-                        if let Some(peer) = peer {
-                            if let Some((_, msg)) = msg.split_once("|") {
-                                let uname = peer.uname.clone().unwrap_or("Stranger".to_string());
-                                println!("{}: {}", uname, msg);
-                                
-                                let fe = FrontendEvent::MessageReceived { from: uname, content: msg.to_string() };
-                                let _ = web_api_tx.send(fe).await; 
-                            }
+                        let peer = self.get_peer(node_id.clone());
+                        if let Some(peer) = peer
+                            && let Some((kind, msg)) = msg.split_once("|") {
+                            // Direct frames carry no id on the wire; one is made up so frontends can refer to them.
+                            let (message_id, content) = (generate_unique_id(), msg.to_string());
+                            self.emit(if kind == "DM" {
+                                EventKind::DirectMessage { message_id, from: node_id, uname: peer.uname, content }
+                            } else {
+                                EventKind::MessageReceived { message_id, from: node_id.clone(), uname: peer.uname, via: node_id, content }
+                            });
                         };
                     }
+                    PeerEvent::Nick { node_id, msg } => {
+                        let uname = msg.strip_prefix("NICK|").unwrap_or_default().trim();
+                        if let Err(e) = protocol::check_uname(uname) {
+                            warn!("Ignoring NICK from {}: {}", node_id, e);
+                            self.penalize(Some(node_id), None, Violation::InvalidPayload).await;
+                            continue;
+                        }
+                        let old = self.get_peer(node_id.clone()).and_then(|p| p.uname);
+                        self.rename(node_id.clone(), uname.to_string()).await;
+                        self.emit(EventKind::PeerRenamed { node_id, old, uname: uname.to_string() });
+                    }
                     PeerEvent::Join { conn_id, msg } => {
                         debug!("Received Join from {}: {}", conn_id, msg);
-                        let fe = FrontendEvent::PeerJoined(msg.clone());
-                        let _ = web_api_tx.send(fe).await;
+                        // Looked up first: a rejected JOIN closes the connection.
+                        let conn = self.directory().conns.get(&conn_id)
+                            .map(|(entry, summary)| (summary.listen_addr.clone().or(summary.remote_addr.clone()), entry.outbound));
                         if let Err(e) = handle_join_json(self.clone(), msg.clone(), conn_id.clone()).await {
-                            error!("Error during handling join {}", e)                            
+                            error!("Error during handling join {}", e);
+                            if let Some((addr, outbound)) = conn {
+                                let addr = addr.unwrap_or_default();
+                                self.emit(EventKind::HandshakeFailed { addr, direction: Direction::of(outbound), error: format!("{:#}", e) });
+                            }
+                            self.penalize_invalid(None, Some(conn_id), &e).await;
+                        };
+                    }
+                    PeerEvent::Gossip { node_id, msg } => {
+                        match gossip::handle_gossip_json(&self, node_id.clone(), msg) {
+                            Ok(Some(g)) => {
+                                self.emit(EventKind::MessageReceived { message_id: g.id, from: g.origin, uname: g.uname, via: node_id, content: g.content });
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                error!("Error during handling gossip {}", e);
+                                self.penalize_invalid(Some(node_id), None, &e).await;
+                            }
+                        }
+                    }
+                    PeerEvent::FindNode { conn_id, msg } => {
+                        if let Some(dht) = self.overlay.dht()
+                            && let Err(e) = dht.handle_find_node(self.clone(), conn_id.clone(), msg) {
+                            error!("Error during handling FIND_NODE {}", e);
+                            self.penalize_invalid(None, Some(conn_id), &e).await;
+                        }
+                    }
+                    PeerEvent::Nodes { msg } => {
+                        if let Some(dht) = self.overlay.dht()
+                            && let Err(e) = dht.handle_nodes(msg) {
+                            error!("Error during handling NODES {}", e)
+                        }
+                    }
+                    PeerEvent::Busy { addr, msg } => {
+                        info!("Peer at {} is full", addr);
+                        if let Err(e) = protocol::handle_busy_json(self.clone(), msg).await {
+                            error!("Error during handling BUSY {}", e)
+                        }
+                    }
+                    PeerEvent::RateLimited { conn_id, source, limit, dropped } => {
+                        // Drops are counted in stats where they happen; this is only the throttled report.
+                        warn!("{} exceeded its {:?} rate limit, {} dropped", source, limit, dropped);
+                        self.emit(EventKind::RateLimited { source, limit, dropped });
+                        if let Some(conn_id) = conn_id {
+                            self.penalize(None, Some(conn_id), Violation::Flooding).await;
+                        }
+                    }
+                    PeerEvent::Misbehaved { conn_id, violation } => {
+                        self.penalize(None, Some(conn_id), violation).await;
+                    }
+                    PeerEvent::Membership { conn_id, msg } => {
+                        if let Some(membership) = self.overlay.membership()
+                            && let Err(e) = membership.handle_msg(self.clone(), conn_id.clone(), msg).await {
+                            error!("Error during handling membership message {}", e);
+                            self.penalize_invalid(None, Some(conn_id), &e).await;
+                        }
+                    }
+                    PeerEvent::Swim { conn_id, node_id, msg } => {
+                        if let Some(swim) = self.swim.clone()
+                            && let Err(e) = swim.handle_msg(self.clone(), conn_id.clone(), node_id, msg).await {
+                            error!("Error during handling SWIM message {}", e);
+                            self.penalize_invalid(None, Some(conn_id), &e).await;
+                        }
+                    }
+                    PeerEvent::Peers { conn_id, msg } => {
+                        debug!("Received peer exchange from {}: {}", conn_id, msg);
+                        if let Err(e) = handle_peers_json(self.clone(), conn_id.clone(), msg).await {
+                            error!("Error during handling peers {}", e);
+                            self.penalize_invalid(None, Some(conn_id), &e).await;
                         };
                     }
-                    PeerEvent::Peers { msg } => {
-                        debug!("Received Peers from {}", msg);
-                        if let Err(e) = handle_peers_json(self.clone(), msg.clone()).await {
-                            error!("Error during handling peers {}", e)                            
+                    PeerEvent::Disconnected { node_id, conn_id, retired: true } => {
+                        // The replacement's JOIN may still be on its way over the other stream; only
+                        // drop the node if this connection is still the registered one after the grace period.
+                        let events_tx = self.events_tx();
+                        tokio::spawn(async move {
+                            time::sleep(RETIRE_GRACE).await;
+                            let _ = events_tx.send(PeerEvent::Disconnected { node_id, conn_id, retired: false }).await;
+                        });
+                    }
+                    PeerEvent::Disconnected { node_id, conn_id, retired: false } => {
+                        // Only the registered connection of a node counts; a replaced
+                        // duplicate closing must not drop the node from the overlay.
+                        let Some((peer, connected)) = self.remove_conn(conn_id.clone()).await else {
+                            debug!("Connection {} ({}) closed", conn_id, node_id);
+                            continue;
                         };
+                        self.peer_gone(&peer.node_id);
+                        if let Some(swim) = self.swim.clone() {
+                            swim.on_disconnected(&self, &peer.node_id).await;
+                        }
+                        info!("Peer {} disconnected", peer.node_id);
+                        self.emit(EventKind::PeerDisconnected { peer, connected_secs: connected.as_secs() });
                     }
-                    PeerEvent::Disconnected { node_id } => {
-                        self.remove_node(node_id.clone()).await;
-                        info!("Peer {} disconnected", node_id);
-                        let fe = FrontendEvent::PeerDisconnected(format!("{}", node_id));
-                        let _ = web_api_tx.send(fe).await;
+                    PeerEvent::Left { node_id, conn_id } => {
+                        // Dropped right away instead of waiting for the socket to close.
+                        let Some((peer, connected)) = self.remove_conn(conn_id.clone()).await else {
+                            debug!("Connection {} ({}) left", conn_id, node_id);
+                            continue;
+                        };
+                        self.peer_gone(&peer.node_id);
+                        // A clean exit needs no suspicion period.
+                        if let Some(swim) = self.swim.clone() {
+                            swim.on_left(&self, &peer.node_id).await;
+                        }
+                        info!("Peer {} left", peer.node_id);
+                        self.emit(EventKind::PeerLeft { peer, connected_secs: connected.as_secs() });
                     }
                     PeerEvent::Connected { node_id } => {
                         info!("Peer {} connected", node_id);
@@ -496,9 +1252,9 @@ impl PeerManagerHandle {
         });
     }
 
-    pub async fn add_conn(&self, conn_id: String, summary: PeerSummary, socket: TcpStream) -> anyhow::Result<()> {
+    pub async fn add_conn(&self, conn_id: String, summary: PeerSummary, socket: TcpStream, outbound: bool) -> anyhow::Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::AddConn { conn_id, summary, socket, resp: resp_tx };
+        let cmd = Command::AddConn { conn_id, summary, socket, outbound, resp: resp_tx };
         self.tx.send(cmd).await.map_err(|e| anyhow::anyhow!("actor stopped: {}", e))?;
         resp_rx.await.map_err(|e| anyhow::anyhow!(e))?
     }
@@ -517,58 +1273,560 @@ impl PeerManagerHandle {
         resp_rx.await.map_err(|e| anyhow::anyhow!(e))?
     }
 
-    pub async fn remove_conn(&self, conn_id: String) {
-        let _ = self.tx.send(Command::RemoveConn { conn_id }).await;
+    /// Closes a connection. Returns the peer and how long it was connected if it was registered.
+    pub async fn remove_conn(&self, conn_id: String) -> Option<(PeerInfo, Duration)> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let cmd = Command::RemoveConn { conn_id, resp: resp_tx };
+        if self.tx.send(cmd).await.is_err() {
+            return None
+        }
+        resp_rx.await.ok()?
     }
 
     pub async fn remove_node(&self, node_id: String) {
         let _ = self.tx.send(Command::RemoveNode { node_id }).await;
     }
 
-    pub async fn broadcast(&self, msg: String) {
-        let _ = self.tx.send(Command::Broadcast { msg }).await;
+    /// Updates the name a registered peer announced with NICK.
+    pub async fn rename(&self, node_id: String, uname: String) {
+        let _ = self.tx.send(Command::Rename { node_id, uname }).await;
     }
 
-    pub async fn send_to(&self, node_id: Option<String>, conn_id: Option<String>, msg: String) -> anyhow::Result<()> {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::SendTo { node_id, conn_id, msg, resp: resp_tx };
-        self.tx.send(cmd).await.map_err(|e| anyhow::anyhow!("actor stopped: {}", e))?;
-        resp_rx.await.map_err(|e| anyhow::anyhow!(e))?
+    /// Changes our name and tells every peer.
+    pub async fn set_uname(&self, uname: String) -> anyhow::Result<()> {
+        protocol::check_uname(&uname)?;
+        let frame = format!("NICK|{}", uname);
+        *self.uname.lock().unwrap() = Some(uname);
+        self.broadcast(frame).await;
+        Ok(())
     }
 
-    pub async fn get_peers(&self) -> Vec<PeerSummary> {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::GetPeers { resp: resp_tx };
-        if self.tx.send(cmd).await.is_err() {
-            return vec![];
+    /// Lowers a peer's score; peers that drop to the ban threshold are disconnected and banned.
+    pub async fn penalize(&self, node_id: Option<String>, conn_id: Option<String>, violation: Violation) {
+        let _ = self.tx.send(Command::Penalize { node_id, conn_id, violation }).await;
+    }
+
+    /// Handler errors caused by a payload that failed to parse count against the sender;
+    /// other errors (e.g. a send failing) are ours.
+    async fn penalize_invalid(&self, node_id: Option<String>, conn_id: Option<String>, e: &anyhow::Error) {
+        if e.chain().any(|cause| cause.is::<serde_json::Error>()) {
+            self.penalize(node_id, conn_id, Violation::InvalidPayload).await;
         }
-        resp_rx.await.unwrap_or_default()
     }
 
-    pub async fn get_peer(&self, node_id: String) -> Option<PeerSummary> {
+    /// Sends LEAVE to every peer, closes all connections and waits up to `timeout` for
+    /// their queues to be written out.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
         let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::GetPeer { node_id, resp: resp_tx };
-        if self.tx.send(cmd).await.is_err() {
-            return None
+        if self.tx.send(Command::Shutdown { resp: resp_tx }).await.is_err() {
+            return;
+        }
+        let drained = time::timeout(timeout, async {
+            let writers = resp_rx.await.unwrap_or_default();
+            futures::future::join_all(writers).await;
+        }).await;
+        if drained.is_err() {
+            warn!("Gave up flushing peer queues after {:?}", timeout);
         }
-        resp_rx.await.ok()?    
-            .ok() 
     }
 
-    pub async fn get_conn(&self, conn_id: String) -> Option<PeerSummary> {
+    /// Asks for a connection slot. Inbound callers may get one freed by eviction. The slot
+    /// must be held until `add_conn` or `add_entry` has returned.
+    pub async fn admit(&self, outbound: bool) -> Admission {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Admission::Busy(vec![]);
+        }
         let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::GetConn { conn_id, resp: resp_tx };
-        if self.tx.send(cmd).await.is_err() {
-            return None
+        if self.tx.send(Command::Admit { outbound, resp: resp_tx }).await.is_err() {
+            return Admission::Busy(vec![]);
         }
-        resp_rx.await.ok()?    
-            .ok() 
+        resp_rx.await.unwrap_or(Admission::Busy(vec![]))
     }
 
-    pub async fn contains_listen_addr(&self, addr: String) -> bool {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::ContainsListenAddr { listen_addr: addr, resp: resp_tx };
-        let _ = self.tx.send(cmd).await;
-        resp_rx.await.unwrap_or(false)
+    /// Resends our full peer list to a registered connection that missed deltas.
+    pub async fn send_peer_list(&self, conn_id: String) {
+        let _ = self.tx.send(Command::SendPeerList { conn_id }).await;
+    }
+
+    /// Sends our own chat or NICK to every peer. Under the block policy this waits for room
+    /// in each queue in turn.
+    pub async fn broadcast(&self, msg: String) {
+        // One copy of the frame, shared by every peer's queue.
+        let frame = Frame::from(msg);
+        for (node_id, entry) in self.peer_entries(None) {
+            if let Err(e) = entry.send(frame.clone()).await {
+                debug!("Failed to send a message to peer {}: {}", node_id, e);
+            }
+        }
+    }
+
+    /// Queues a relayed or protocol frame for every peer but `except` without ever waiting,
+    /// so one stalled peer can't hold up the event handler.
+    pub fn broadcast_except(&self, msg: String, except: Option<String>) {
+        let frame = Frame::from(msg);
+        for (node_id, entry) in self.peer_entries(except.as_deref()) {
+            if let Err(e) = entry.try_send(frame.clone()) {
+                debug!("Failed to send a message to peer {}: {}", node_id, e);
+            }
+        }
+    }
+
+    fn peer_entries(&self, except: Option<&str>) -> Vec<(String, Arc<PeerEntry>)> {
+        self.directory().peers.iter()
+            .filter(|(node_id, _)| except != Some(node_id.as_str()))
+            .map(|(node_id, (entry, _))| (node_id.clone(), entry.clone()))
+            .collect()
+    }
+
+    fn entry(&self, node_id: Option<String>, conn_id: Option<String>) -> Option<Arc<PeerEntry>> {
+        let dir = self.directory();
+        match (node_id, conn_id) {
+            (Some(node_id), _) => dir.peers.get(&node_id).map(|(entry, _)| entry.clone()),
+            (None, Some(conn_id)) => dir.conns.get(&conn_id).map(|(entry, _)| entry.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// Queues a protocol frame for one peer or connection without waiting; see `broadcast_except`.
+    pub fn send_to(&self, node_id: Option<String>, conn_id: Option<String>, msg: String) -> anyhow::Result<()> {
+        if node_id.is_none() && conn_id.is_none() {
+            anyhow::bail!("No id to send_to was passed {}", msg)
+        }
+        match self.entry(node_id, conn_id) {
+            Some(entry) => entry.try_send(msg.into()),
+            None => anyhow::bail!("Could not find node to send a messageand msg = {}", msg),
+        }
+    }
+
+    /// Sends our own chat to one registered peer, waiting for room under the block policy.
+    pub async fn send_chat_to(&self, node_id: String, msg: String) -> anyhow::Result<()> {
+        match self.entry(Some(node_id), None) {
+            Some(entry) => entry.send(msg.into()).await,
+            None => anyhow::bail!("Could not find node to send a messageand msg = {}", msg),
+        }
+    }
+
+    pub fn get_peers(&self) -> Vec<PeerSummary> {
+        self.directory().peers.values().map(|(_, summary)| summary.clone()).collect()
+    }
+
+    /// Send queue figures of every registered peer, by node id.
+    pub fn queue_stats(&self) -> HashMap<String, QueueStats> {
+        self.directory().peers.iter()
+            .map(|(node_id, (entry, _))| (node_id.clone(), entry.queue_stats()))
+            .collect()
+    }
+
+    pub fn get_peer(&self, node_id: String) -> Option<PeerSummary> {
+        self.directory().peers.get(&node_id).map(|(_, summary)| summary.clone())
+    }
+
+    pub fn peer_conn(&self, node_id: String) -> Option<PeerConn> {
+        self.directory().peers.get(&node_id).map(|(entry, summary)| PeerConn {
+            summary: summary.clone(),
+            outbound: entry.outbound,
+            ip: entry.ip,
+            connected_for: entry.connected_at.elapsed(),
+            score: entry.score.load(Ordering::Relaxed),
+            queue: entry.queue_stats(),
+        })
+    }
+
+    /// Waits until the peer dialed on `conn_id` has sent JOIN and returns its node id. If that
+    /// connection was dropped as a duplicate, the node kept on another one at `listen_addr` counts.
+    pub async fn wait_registered(&self, conn_id: &str, listen_addr: &str, timeout: Duration) -> Option<String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let published = self.published.notified();
+            tokio::pin!(published);
+            published.as_mut().enable();
+
+            {
+                let dir = self.directory();
+                match dir.conns.get(conn_id) {
+                    Some((_, summary)) => {
+                        let registered = summary.node_id.as_ref()
+                            .filter(|id| dir.peers.get(*id).is_some_and(|(entry, _)| entry.conn_id == conn_id));
+                        if let Some(node_id) = registered {
+                            return Some(node_id.clone());
+                        }
+                    }
+                    None => {
+                        return dir.peers.iter()
+                            .find(|(_, (_, summary))| summary.listen_addr.as_deref() == Some(listen_addr))
+                            .map(|(node_id, _)| node_id.clone());
+                    }
+                }
+            }
+            if time::timeout_at(deadline.into(), published).await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_conn(&self, conn_id: String) -> Option<PeerSummary> {
+        self.directory().conns.get(&conn_id).map(|(_, summary)| summary.clone())
+    }
+
+    pub fn contains_listen_addr(&self, addr: String) -> bool {
+        self.directory().listen_addrs.contains_key(&addr)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, ReadHalf, WriteHalf, duplex};
+
+    use super::*;
+    use crate::bans::BanConfig;
+
+    pub(crate) const LOW: &str = "00000000-0000-4000-8000-000000000001";
+    pub(crate) const HIGH: &str = "ffffffff-ffff-4fff-bfff-ffffffffffff";
+
+    /// A mesh node with generous limits and no listener, for tests across the crate.
+    pub(crate) fn node(node_id: &str) -> (Arc<PeerManagerHandle>, mpsc::Receiver<FrontendEvent>) {
+        node_with(node_id, ConnLimits {
+            max_inbound: 8, max_outbound: 8, max_peers: 16, eviction: Eviction::None,
+            max_frame_size: 64 * 1024, send_queue: 64, send_policy: SendPolicy::Disconnect,
+        })
+    }
+
+    fn node_with(node_id: &str, limits: ConnLimits) -> (Arc<PeerManagerHandle>, mpsc::Receiver<FrontendEvent>) {
+        let summary = PeerSummary { remote_addr: None, listen_addr: Some("127.0.0.1:1".into()), node_id: Some(node_id.to_string()), uname: None };
+        let (frontend_tx, frontend_rx) = mpsc::channel(100);
+        let dial = DialConfig { concurrency: 1, connect_timeout: Duration::from_secs(1), tls_handshake_timeout: Duration::from_secs(1) };
+        let rate_limits = RateLimits { msg_rate: 1e6, msg_burst: 1e6, byte_rate: 1e9, byte_burst: 1e9, conn_rate: 1e6, conn_burst: 1e6 };
+        let path = std::env::temp_dir().join(format!("test-bans-{}.json", generate_unique_id()));
+        let bans = Bans::load(BanConfig { threshold: -100, duration: Duration::from_secs(60), path });
+        let pm = PeerManagerHandle::new(summary, frontend_tx, false, None, dial, limits, rate_limits, bans, Overlay::Mesh, None);
+        (pm, frontend_rx)
+    }
+
+    /// Adds one end of an in-memory stream to `pm` as a new connection.
+    pub(crate) async fn attach(pm: &Arc<PeerManagerHandle>, stream: DuplexStream, outbound: bool) -> String {
+        let conn_id = generate_unique_id();
+        let summary = PeerSummary { remote_addr: None, listen_addr: None, node_id: None, uname: None };
+        let entry = PeerEntry::new(conn_id.clone(), summary, stream, outbound, pm.peer_limiter(), pm.send_queue(), pm.events_tx());
+        pm.add_entry(conn_id.clone(), entry).await.unwrap();
+        conn_id
+    }
+
+    /// The far end of a connection, driven by hand instead of by a peer manager.
+    pub(crate) struct Raw {
+        lines: BufReader<ReadHalf<DuplexStream>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl Raw {
+        pub(crate) fn new(stream: DuplexStream) -> Self {
+            let (reader, writer) = tokio::io::split(stream);
+            Self { lines: BufReader::new(reader), writer }
+        }
+
+        pub(crate) async fn send(&mut self, frame: &str) {
+            self.writer.write_all(format!("{}\n", frame).as_bytes()).await.unwrap();
+        }
+
+        pub(crate) async fn join(&mut self, node_id: &str) {
+            let summary = PeerSummary { remote_addr: None, listen_addr: Some("10.0.0.9:4000".into()), node_id: Some(node_id.to_string()), uname: None };
+            self.send(&format!("JOIN|{}", serde_json::to_string(&summary).unwrap())).await;
+        }
+
+        /// Reads frames until `frame` arrives. False if the stream ended first.
+        pub(crate) async fn wait_for(&mut self, frame: &str) -> bool {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match time::timeout(Duration::from_secs(2), self.lines.read_line(&mut line)).await {
+                    Ok(Ok(0)) | Ok(Err(_)) => return false,
+                    Ok(Ok(_)) if line.trim() == frame => return true,
+                    Ok(Ok(_)) => {}
+                    Err(_) => panic!("timed out waiting for {}", frame),
+                }
+            }
+        }
+
+        pub(crate) async fn at_eof(&mut self) -> bool {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match time::timeout(Duration::from_secs(2), self.lines.read_line(&mut line)).await {
+                    Ok(Ok(0)) => return true,
+                    Ok(Ok(_)) => {}
+                    _ => return false,
+                }
+            }
+        }
+    }
+
+    pub(crate) fn registered_conn(pm: &PeerManagerHandle, node_id: &str) -> Option<String> {
+        pm.directory().peers.get(node_id).map(|(entry, _)| entry.conn_id.clone())
+    }
+
+    /// Deletes the ban list a test node saved, once its writer task has caught up with the
+    /// latest change. Tests that change bans call this at the end.
+    pub(crate) async fn remove_bans_file(pm: &PeerManagerHandle) {
+        until(|| bans::tests::saved_file(&pm.bans.lock().unwrap()).is_some()).await;
+        if let Some(path) = bans::tests::saved_file(&pm.bans.lock().unwrap()) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    pub(crate) async fn until(cond: impl Fn() -> bool) {
+        time::timeout(Duration::from_secs(2), async {
+            while !cond() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn simultaneous_connect_keeps_the_same_stream_on_both_ends() {
+        let (low, _low_events) = node(LOW);
+        let (high, _high_events) = node(HIGH);
+
+        // One stream dialed by each side.
+        let (a, b) = duplex(64 * 1024);
+        let low_dialed = (attach(&low, a, true).await, attach(&high, b, false).await);
+        let (a, b) = duplex(64 * 1024);
+        let high_dialed = (attach(&low, a, false).await, attach(&high, b, true).await);
+
+        for (pm, conn_id) in [(&low, &low_dialed.0), (&low, &high_dialed.0), (&high, &low_dialed.1), (&high, &high_dialed.1)] {
+            protocol::send_join(pm.self_summary(), conn_id.clone(), pm.clone()).await.unwrap();
+        }
+
+        // The lower node id's outbound stream wins, seen from either end.
+        until(|| registered_conn(&low, HIGH).as_ref() == Some(&low_dialed.0)).await;
+        until(|| registered_conn(&high, LOW).as_ref() == Some(&low_dialed.1)).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(registered_conn(&low, HIGH), Some(low_dialed.0));
+        assert_eq!(registered_conn(&high, LOW), Some(low_dialed.1));
+    }
+
+    #[tokio::test]
+    async fn losing_stream_is_retired_and_closed() {
+        let (pm, _events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        let kept = attach(&pm, ours, true).await;
+        let mut kept_raw = Raw::new(theirs);
+        let (ours, theirs) = duplex(64 * 1024);
+        let lost = attach(&pm, ours, false).await;
+        let mut lost_raw = Raw::new(theirs);
+
+        kept_raw.join(HIGH).await;
+        until(|| registered_conn(&pm, HIGH).is_some()).await;
+        lost_raw.join(HIGH).await;
+
+        assert!(lost_raw.wait_for("RETIRE|").await);
+        assert!(lost_raw.at_eof().await);
+        drop(lost_raw);
+        until(|| !pm.directory().conns.contains_key(&lost)).await;
+        assert_eq!(registered_conn(&pm, HIGH), Some(kept));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retired_disconnect_keeps_the_node_for_its_replacement() {
+        let (pm, mut events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        attach(&pm, ours, false).await;
+        let mut raw = Raw::new(theirs);
+        raw.join(HIGH).await;
+        until(|| registered_conn(&pm, HIGH).is_some()).await;
+
+        // The other side retires the stream and closes it, its replacement still on the way.
+        raw.send("RETIRE|").await;
+        drop(raw);
+        time::sleep(RETIRE_GRACE / 2).await;
+
+        let (ours, theirs) = duplex(64 * 1024);
+        let replacement = attach(&pm, ours, true).await;
+        let mut raw = Raw::new(theirs);
+        raw.join(HIGH).await;
+        until(|| registered_conn(&pm, HIGH).as_ref() == Some(&replacement)).await;
+
+        time::advance(RETIRE_GRACE).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(registered_conn(&pm, HIGH), Some(replacement));
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event.kind, EventKind::PeerDisconnected { .. }));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retired_disconnect_drops_the_node_without_a_replacement() {
+        let (pm, mut events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        let conn_id = attach(&pm, ours, false).await;
+        let mut raw = Raw::new(theirs);
+        raw.join(HIGH).await;
+        until(|| registered_conn(&pm, HIGH).is_some()).await;
+
+        raw.send("RETIRE|").await;
+        drop(raw);
+        time::sleep(RETIRE_GRACE - Duration::from_secs(1)).await;
+        assert_eq!(registered_conn(&pm, HIGH), Some(conn_id));
+
+        time::advance(Duration::from_secs(2)).await;
+        loop {
+            let event = time::timeout(Duration::from_secs(2), events.recv()).await
+                .expect("no PeerDisconnected after the grace period").unwrap();
+            if let EventKind::PeerDisconnected { peer, .. } = event.kind {
+                assert_eq!(peer.node_id, HIGH);
+                break;
+            }
+        }
+        assert_eq!(registered_conn(&pm, HIGH), None);
+    }
+
+    #[tokio::test]
+    async fn eviction_never_takes_the_table_over_the_limit() {
+        let (pm, _events) = node_with(LOW, ConnLimits {
+            max_inbound: 3, max_outbound: 3, max_peers: 3, eviction: Eviction::Oldest,
+            max_frame_size: 64 * 1024, send_queue: 64, send_policy: SendPolicy::Disconnect,
+        });
+
+        let watcher = tokio::spawn({
+            let pm = pm.clone();
+            async move {
+                let mut most = 0;
+                loop {
+                    most = most.max(pm.directory().peers.len());
+                    if most > 3 {
+                        return most;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        // Each burst of inbound connections finds the table full and evicts to get in.
+        let mut raws = Vec::new();
+        for _ in 0..4 {
+            let mut burst = Vec::new();
+            for _ in 0..3 {
+                let pm = pm.clone();
+                burst.push(tokio::spawn(async move {
+                    let Admission::Accept(_slot) = pm.admit(false).await else {
+                        return None;
+                    };
+                    let (ours, theirs) = duplex(64 * 1024);
+                    let conn_id = attach(&pm, ours, false).await;
+                    let mut raw = Raw::new(theirs);
+                    raw.join(&generate_unique_id()).await;
+                    until(|| !pm.directory().conns.contains_key(&conn_id) || pm.directory().peers.values().any(|(e, _)| e.conn_id == conn_id)).await;
+                    Some(raw)
+                }));
+            }
+            for task in burst {
+                raws.extend(task.await.unwrap());
+            }
+        }
+
+        assert_eq!(pm.directory().peers.len(), 3);
+        watcher.abort();
+        if let Ok(most) = watcher.await {
+            panic!("{} peers registered with max_peers 3", most);
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_text_chat_is_delivered() {
+        let (pm, mut events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        attach(&pm, ours, false).await;
+        let mut raw = Raw::new(theirs);
+        raw.join(HIGH).await;
+        until(|| registered_conn(&pm, HIGH).is_some()).await;
+
+        raw.send("MSG|hello").await;
+        raw.send("DM|psst").await;
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let event = time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap();
+            match event.kind {
+                EventKind::MessageReceived { from, content, .. } | EventKind::DirectMessage { from, content, .. } => {
+                    assert_eq!(from, HIGH);
+                    received.push(content);
+                }
+                EventKind::PeerBanned(_) => panic!("peer banned for plain text chat"),
+                _ => {}
+            }
+        }
+        assert_eq!(received, ["hello", "psst"]);
+    }
+
+    #[tokio::test]
+    async fn multi_line_chat_neither_splits_nor_penalizes_the_sender() {
+        let (low, _low_events) = node(LOW);
+        let (high, mut high_events) = node(HIGH);
+        let (a, b) = duplex(64 * 1024);
+        let conns = (attach(&low, a, true).await, attach(&high, b, false).await);
+        protocol::send_join(low.self_summary(), conns.0, low.clone()).await.unwrap();
+        protocol::send_join(high.self_summary(), conns.1, high.clone()).await.unwrap();
+        until(|| registered_conn(&low, HIGH).is_some() && registered_conn(&high, LOW).is_some()).await;
+        let score = high.peer_conn(LOW.to_string()).unwrap().score;
+
+        // Either line break would let the content through as frames of its own.
+        assert!(gossip::send_chat(&low, "hi\nLEAVE|").await.unwrap_err().is::<protocol::LineBreak>());
+        assert!(gossip::send_direct(&low, HIGH.to_string(), "a\r\nb").await.unwrap_err().is::<protocol::LineBreak>());
+        gossip::send_chat(&low, "after").await.unwrap();
+
+        loop {
+            let event = time::timeout(Duration::from_secs(2), high_events.recv()).await.unwrap().unwrap();
+            match event.kind {
+                EventKind::MessageReceived { content, .. } => {
+                    assert_eq!(content, "after");
+                    break;
+                }
+                EventKind::DirectMessage { .. } | EventKind::PeerLeft { .. } => panic!("split message reached the peer"),
+                _ => {}
+            }
+        }
+        assert!(high.peer_conn(LOW.to_string()).unwrap().score >= score);
+    }
+
+    #[tokio::test]
+    async fn peer_is_banned_once_its_score_hits_the_threshold() {
+        let (pm, mut events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        attach(&pm, ours, false).await;
+        let mut raw = Raw::new(theirs);
+        raw.join(HIGH).await;
+        until(|| registered_conn(&pm, HIGH).is_some()).await;
+
+        let frames = (pm.bans.lock().unwrap().threshold().abs() / Violation::UnknownFrame.penalty()) + 2;
+        for _ in 0..frames {
+            raw.send("BOGUS|").await;
+        }
+        let ban = loop {
+            let event = time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap();
+            if let EventKind::PeerBanned(ban) = event.kind {
+                break ban;
+            }
+        };
+        assert_eq!(ban.node_id.as_deref(), Some(HIGH));
+        assert!(raw.at_eof().await);
+        assert!(pm.bans.lock().unwrap().is_banned_node(HIGH));
+        remove_bans_file(&pm).await;
+    }
+
+    #[tokio::test]
+    async fn reconnect_from_the_same_side_replaces_the_old_stream() {
+        let (pm, _events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        attach(&pm, ours, false).await;
+        let mut old = Raw::new(theirs);
+        old.join(HIGH).await;
+        until(|| registered_conn(&pm, HIGH).is_some()).await;
+
+        let (ours, theirs) = duplex(64 * 1024);
+        let new_conn = attach(&pm, ours, false).await;
+        let mut new = Raw::new(theirs);
+        new.join(HIGH).await;
+
+        until(|| registered_conn(&pm, HIGH).as_ref() == Some(&new_conn)).await;
+        assert!(old.wait_for("RETIRE|").await);
     }
 }
@@ -1,7 +1,84 @@
-use crate::{network::handle_peer_list, peer_manager::{PeerManagerHandle, PeerSummary}};
+use crate::{dht::Dht, known_peers::{Applied, Delta}, network::handle_peer_list, peer_manager::{Overlay, PeerManagerHandle, PeerSummary}};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
 use std::{sync::Arc};
 use tracing::{error, debug};
 
+/// Full list of the sender's peers at membership `version`.
+#[derive(Serialize, Deserialize)]
+struct PeerList {
+    from: String,
+    version: u64,
+    peers: Vec<PeerSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerAdded {
+    from: String,
+    version: u64,
+    peer: PeerSummary,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerRemoved {
+    from: String,
+    version: u64,
+    node_id: String,
+}
+
+/// Sent instead of JOIN when a node has no free connection slot.
+#[derive(Serialize, Deserialize)]
+struct Busy {
+    peers: Vec<PeerSummary>,
+}
+
+/// A frame longer than peers accept, refused before it is queued.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame of {} bytes exceeds the maximum of {}", self.size, self.max)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// A frame with a line break in it, which peers would read as several frames.
+#[derive(Debug)]
+pub struct LineBreak;
+
+impl std::fmt::Display for LineBreak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message contains a line break")
+    }
+}
+
+impl std::error::Error for LineBreak {}
+
+/// Longest name accepted from `--uname`, `/nick` or a NICK frame, in characters.
+pub const MAX_UNAME: usize = 32;
+
+pub fn check_uname(uname: &str) -> anyhow::Result<()> {
+    if uname.is_empty() {
+        anyhow::bail!("name is empty");
+    }
+    if uname.chars().count() > MAX_UNAME {
+        anyhow::bail!("name is longer than {} characters", MAX_UNAME);
+    }
+    if uname.chars().any(|c| c.is_control()) {
+        anyhow::bail!("name contains control characters");
+    }
+    Ok(())
+}
+
+pub fn is_peer_exchange_msg(msg: &str) -> bool {
+    ["PEERS|", "PEERS_REQ|", "PEER_ADDED|", "PEER_REMOVED|"].iter().any(|p| msg.starts_with(p))
+}
+
 pub async fn handle_join_json(peer_manager: Arc<PeerManagerHandle>, msg: String, conn_id: String) -> anyhow::Result<()> {
         let parts: Vec<&str> = msg.splitn(2, '|').collect();
         if parts.len() < 2 {
@@ -9,37 +86,102 @@ pub async fn handle_join_json(peer_manager: Arc<PeerManagerHandle>, msg: String,
             return Ok(());
         }
 
-        let peer_info: PeerSummary = match serde_json::from_str(parts[1]) {
-            Ok(info) => info,
-            Err(e) => {
-                anyhow::bail!("Failed to parse JOIN payload: {}", e);
-            }
-        };
-        peer_manager.register_node(conn_id, peer_info).await?;
-        send_peers(&peer_manager).await;
+        let peer_info: PeerSummary = serde_json::from_str(parts[1])
+            .context("Failed to parse JOIN payload")?;
+
+        if let Some(node_id) = &peer_info.node_id
+            && peer_manager.bans.lock().unwrap().is_banned_node(node_id) {
+            peer_manager.remove_conn(conn_id).await;
+            anyhow::bail!("Rejected JOIN from banned node {}", node_id);
+        }
+        peer_manager.register_node(conn_id.clone(), peer_info.clone()).await?;
+
+        if let Some(swim) = &peer_manager.swim {
+            swim.on_registered(&peer_manager, &peer_info);
+        }
+
+        match &peer_manager.overlay {
+            // The peer manager sends the full list and PEER_ADDED deltas on registration.
+            Overlay::Mesh => {}
+            Overlay::Dht(dht) => Dht::on_registered(peer_manager.clone(), dht.clone(), conn_id, &peer_info),
+            // Active view membership is negotiated with NEIGHBOR frames instead.
+            Overlay::HyParView(_) => {}
+        }
 
         Ok(())
 }
 
-pub async fn handle_peers_json(peer_manager: Arc<PeerManagerHandle>, msg: String) -> anyhow::Result<()> {
-    let peers_str = &msg["PEERS|".len()..];
+/// Applies a PEERS snapshot or a PEER_ADDED / PEER_REMOVED delta to the known-peers
+/// set and connects to peers we did not know about. Answers PEERS_REQ with a snapshot.
+pub async fn handle_peers_json(peer_manager: Arc<PeerManagerHandle>, conn_id: String, msg: String) -> anyhow::Result<()> {
+    let (kind, payload) = msg.split_once('|')
+        .ok_or_else(|| anyhow::anyhow!("Invalid peer exchange message format"))?;
+
+    let (from, applied) = match kind {
+        "PEERS_REQ" => {
+            peer_manager.send_peer_list(conn_id).await;
+            return Ok(());
+        }
+        "PEERS" => {
+            let list: PeerList = serde_json::from_str(payload)?;
+            let new = peer_manager.known_peers.lock().unwrap().sync(&list.from, list.version, list.peers);
+            return discover_peers(peer_manager, new).await;
+        }
+        "PEER_ADDED" => {
+            let added: PeerAdded = serde_json::from_str(payload)?;
+            let applied = peer_manager.known_peers.lock().unwrap()
+                .apply(&added.from, added.version, Delta::Added(added.peer));
+            (added.from, applied)
+        }
+        "PEER_REMOVED" => {
+            let removed: PeerRemoved = serde_json::from_str(payload)?;
+            let applied = peer_manager.known_peers.lock().unwrap()
+                .apply(&removed.from, removed.version, Delta::Removed(removed.node_id));
+            (removed.from, applied)
+        }
+        _ => anyhow::bail!("Unknown peer exchange message {}", kind),
+    };
+
+    match applied {
+        Applied::InOrder(Some(summary)) => discover_peers(peer_manager, vec![summary]).await,
+        Applied::InOrder(None) | Applied::Stale => Ok(()),
+        Applied::Gap => {
+            debug!("Missed peer deltas from {}, requesting full list", from);
+            peer_manager.send_to(None, Some(conn_id), "PEERS_REQ|".to_string())
+        }
+    }
+}
+
+/// A full node turned us away; try the peers it suggested instead.
+pub async fn handle_busy_json(peer_manager: Arc<PeerManagerHandle>, msg: String) -> anyhow::Result<()> {
+    let payload = msg.strip_prefix("BUSY|")
+        .ok_or_else(|| anyhow::anyhow!("Invalid BUSY message format"))?;
+    let busy: Busy = serde_json::from_str(payload)?;
+    let self_id = peer_manager.self_peer_info.node_id.clone();
+
+    let peers = busy.peers.into_iter().filter(|p| p.node_id != self_id).collect();
+    discover_peers(peer_manager, peers).await
+}
+
+async fn discover_peers(peer_manager: Arc<PeerManagerHandle>, found: Vec<PeerSummary>) -> anyhow::Result<()> {
     let mut addrs: Vec<String> = Vec::new();
+    let mut summaries: Vec<PeerSummary> = Vec::new();
 
-    for entry in peers_str.split(';') {
-        let entry = entry.trim();
-        if entry.is_empty() {
+    for peer_summary in found {
+        // With a DHT only nodes that fit into a bucket are worth a connection.
+        if let Some(dht) = peer_manager.overlay.dht()
+            && !peer_summary.node_id.as_deref().is_some_and(|id| dht.has_room_for(id)) {
             continue;
         }
+        let listen_addr = peer_summary.listen_addr_or_err(10)?;
+        addrs.push(listen_addr);
+        summaries.push(peer_summary);
+    }
 
-        match serde_json::from_str::<PeerSummary>(entry) {
-            Ok(peer_summary) => {
-                let listen_addr = peer_summary.listen_addr_or_err(10)?;
-                addrs.push(listen_addr);
-            }
-            Err(e) => {
-                error!("Failed to parse peer entry '{}': {}", entry, e);
-            }
-        }
+    // Partial views only remember the addresses; repair dials them when needed.
+    if let Some(membership) = peer_manager.overlay.membership() {
+        membership.learn(&peer_manager, &summaries);
+        return Ok(());
     }
 
     handle_peer_list(peer_manager, addrs).await?;
@@ -50,21 +192,10 @@ pub async fn handle_peers_json(peer_manager: Arc<PeerManagerHandle>, msg: String
 
 pub async fn send_join(client_info:PeerSummary, server_conn_id: String, peer_manager: Arc<PeerManagerHandle>) -> anyhow::Result<()>{
     debug!("Sending join from {} to {}", client_info.clone().node_id.unwrap_or("none".to_string()), server_conn_id);
+    // The name may have changed with /nick since `client_info` was taken.
+    let client_info = PeerSummary { uname: peer_manager.uname(), ..client_info };
     let msg = join_payload(client_info).await;
-    peer_manager.send_to(None, Some(server_conn_id), msg).await
-}
-
-pub async fn send_peers(peer_manager: &PeerManagerHandle) {
-    let summaries = peer_manager.get_peers().await;
-    let payload = peers_payload(&summaries).await;
-    
-    for peer_info in summaries {
-        debug!("Sending peers to {}, peers: {}", peer_info.node_id.clone().unwrap_or_default(), payload.clone());
-        let msg = payload.clone();
-        if let Err(e) = peer_manager.send_to(peer_info.node_id, None, msg).await {
-            error!("send_peers failed: {}", e);
-        }
-    }
+    peer_manager.send_to(None, Some(server_conn_id), msg)
 }
 
 async fn join_payload(client_info:PeerSummary) -> String{
@@ -72,16 +203,18 @@ async fn join_payload(client_info:PeerSummary) -> String{
     format!("JOIN|{}\n", client_info_s)
 }
 
-pub async fn peers_payload(summaries: &Vec<PeerSummary>) -> String{
-    let mut payload = String::from("PEERS|");
-    
-    payload.push_str(
-        &summaries
-        .iter()
-        .map(|p| serde_json::to_string(&p).unwrap()) 
-        .collect::<Vec<_>>()
-        .join(";")
-    );
-    
-    payload
-}
\ No newline at end of file
+pub fn peers_payload(from: String, version: u64, peers: Vec<PeerSummary>) -> anyhow::Result<String> {
+    Ok(format!("PEERS|{}", serde_json::to_string(&PeerList { from, version, peers })?))
+}
+
+pub fn peer_added_payload(from: String, version: u64, peer: PeerSummary) -> anyhow::Result<String> {
+    Ok(format!("PEER_ADDED|{}", serde_json::to_string(&PeerAdded { from, version, peer })?))
+}
+
+pub fn busy_payload(peers: Vec<PeerSummary>) -> anyhow::Result<String> {
+    Ok(format!("BUSY|{}", serde_json::to_string(&Busy { peers })?))
+}
+
+pub fn peer_removed_payload(from: String, version: u64, node_id: String) -> anyhow::Result<String> {
+    Ok(format!("PEER_REMOVED|{}", serde_json::to_string(&PeerRemoved { from, version, node_id })?))
+}
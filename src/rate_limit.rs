@@ -0,0 +1,188 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+use crate::stats::Stats;
+
+/// How often a peer that keeps exceeding a limit is reported.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitKind {
+    Messages,
+    Bytes,
+    Connections,
+}
+
+/// Token-bucket settings for inbound traffic.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimits {
+    /// Frames per second a single peer may send, with bursts up to `msg_burst`.
+    pub msg_rate: f64,
+    pub msg_burst: f64,
+    pub byte_rate: f64,
+    pub byte_burst: f64,
+    /// Connection attempts per second from one IP, with bursts up to `conn_burst`.
+    pub conn_rate: f64,
+    pub conn_burst: f64,
+}
+
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self { rate, capacity, tokens: capacity, last: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Takes `n` tokens if they are available.
+    pub fn take(&mut self, n: f64) -> bool {
+        self.refill();
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// True once the bucket has refilled completely, i.e. it carries no state worth keeping.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Counts what a limit dropped and decides when it is time to report it, so a flood
+/// doesn't turn into a flood of reports.
+#[derive(Default)]
+pub struct DropReport {
+    dropped: u64,
+    last_report: Option<Instant>,
+}
+
+impl DropReport {
+    /// Counts one drop. Returns the number dropped since the last report when one is due.
+    pub fn dropped(&mut self) -> Option<u64> {
+        self.dropped += 1;
+        if self.last_report.is_some_and(|t| t.elapsed() < REPORT_INTERVAL) {
+            return None;
+        }
+        self.last_report = Some(Instant::now());
+        Some(std::mem::take(&mut self.dropped))
+    }
+}
+
+/// Per-connection message and byte buckets, checked by the reader for every frame.
+pub struct PeerLimiter {
+    /// Longest line, without its newline, the reader accepts before dropping the connection.
+    pub max_frame_size: usize,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    stats: Arc<Stats>,
+    pub report: DropReport,
+}
+
+impl PeerLimiter {
+    pub fn new(limits: &RateLimits, max_frame_size: usize, stats: Arc<Stats>) -> Self {
+        Self {
+            max_frame_size,
+            messages: TokenBucket::new(limits.msg_rate, limits.msg_burst),
+            bytes: TokenBucket::new(limits.byte_rate, limits.byte_burst),
+            stats,
+            report: DropReport::default(),
+        }
+    }
+
+    /// Returns the limit a frame of `len` bytes exceeds, if any, counting the drop.
+    pub fn check(&mut self, len: usize) -> Option<LimitKind> {
+        let exceeded = if !self.messages.take(1.0) {
+            LimitKind::Messages
+        } else if !self.bytes.take(len as f64) {
+            LimitKind::Bytes
+        } else {
+            return None;
+        };
+        self.stats.rate_limited(exceeded, 1);
+        Some(exceeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves the bucket's clock back instead of sleeping.
+    fn age(bucket: &mut TokenBucket, by: Duration) {
+        bucket.last -= by;
+    }
+
+    #[test]
+    fn starts_full_and_allows_a_burst() {
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+        assert!(bucket.is_full());
+        assert!(bucket.take(1.0));
+        assert!(bucket.take(2.0));
+        assert!(!bucket.take(1.0));
+    }
+
+    #[test]
+    fn refills_at_rate() {
+        let mut bucket = TokenBucket::new(10.0, 5.0);
+        assert!(bucket.take(5.0));
+        age(&mut bucket, Duration::from_millis(200));
+        assert!(bucket.take(2.0));
+        assert!(!bucket.take(1.0));
+    }
+
+    #[test]
+    fn refill_stops_at_capacity() {
+        let mut bucket = TokenBucket::new(100.0, 2.0);
+        assert!(bucket.take(2.0));
+        assert!(!bucket.is_full());
+        age(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.is_full());
+        assert!(bucket.take(2.0));
+        assert!(!bucket.take(1.0));
+    }
+
+    #[test]
+    fn failed_take_keeps_tokens() {
+        let mut bucket = TokenBucket::new(0.0, 4.0);
+        assert!(!bucket.take(5.0));
+        assert!(bucket.take(4.0));
+    }
+
+    #[test]
+    fn limiter_checks_messages_before_bytes() {
+        let limits = RateLimits { msg_rate: 0.0, msg_burst: 2.0, byte_rate: 0.0, byte_burst: 100.0, conn_rate: 0.0, conn_burst: 0.0 };
+        let stats = Arc::new(Stats::default());
+        let mut limiter = PeerLimiter::new(&limits, 1024, stats.clone());
+        assert_eq!(limiter.check(150), Some(LimitKind::Bytes));
+        assert_eq!(limiter.check(50), None);
+        assert_eq!(limiter.check(10), Some(LimitKind::Messages));
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.dropped_messages, snapshot.dropped_bytes_frames), (1, 1));
+    }
+
+    #[test]
+    fn drops_are_reported_once_per_interval() {
+        let mut report = DropReport::default();
+        assert_eq!(report.dropped(), Some(1));
+        assert_eq!(report.dropped(), None);
+        assert_eq!(report.dropped(), None);
+        report.last_report = Some(Instant::now() - REPORT_INTERVAL);
+        assert_eq!(report.dropped(), Some(3));
+    }
+}
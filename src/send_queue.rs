@@ -0,0 +1,335 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::stats::Stats;
+
+/// A frame without its newline, shared by every queue it is broadcast to.
+pub type Frame = Arc<str>;
+
+/// What happens to a new frame when a peer's send queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SendPolicy {
+    /// Wait for room. Every send waits behind the slowest peer.
+    Block,
+    /// Drop the oldest queued frame to make room.
+    DropOldest,
+    /// Treat the peer as too slow and close its connection.
+    Disconnect,
+}
+
+/// Per-peer queue figures shown in `GET /peers`.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub capacity: usize,
+    /// Deepest the queue has been.
+    pub high_water: usize,
+    /// Frames dropped by the drop-oldest policy.
+    pub dropped: u64,
+}
+
+pub enum Push {
+    Queued,
+    /// Queued after dropping the oldest frame.
+    DroppedOldest,
+    /// Full under the disconnect policy; the frame was not queued.
+    Full,
+    /// Full under the block policy for a sender that can't wait; the queue was closed
+    /// as with `Full`.
+    Stalled,
+    /// The writer is gone.
+    Closed,
+}
+
+struct Frames {
+    frames: VecDeque<Frame>,
+    closed: bool,
+    high_water: usize,
+    dropped: u64,
+}
+
+/// The only buffer between senders and a peer's writer task. Frames leave in the order
+/// they were pushed.
+pub struct SendQueue {
+    frames: Mutex<Frames>,
+    capacity: usize,
+    policy: SendPolicy,
+    /// Woken when a frame is pushed or the queue closes.
+    readable: Notify,
+    /// Woken when a frame is popped or the queue closes.
+    writable: Notify,
+    stats: Arc<Stats>,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: SendPolicy, stats: Arc<Stats>) -> Arc<Self> {
+        Arc::new(Self {
+            frames: Mutex::new(Frames { frames: VecDeque::new(), closed: false, high_water: 0, dropped: 0 }),
+            capacity: capacity.max(1),
+            policy,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            stats,
+        })
+    }
+
+    /// Queues `frame` under the queue's policy; with `Block` this waits for room.
+    pub async fn push(&self, frame: Frame) -> Push {
+        loop {
+            if let Some(pushed) = self.offer(&frame, self.policy) {
+                return pushed;
+            }
+            // Only blocking pushes get here; a pop stores a permit if it happens first.
+            self.writable.notified().await;
+        }
+    }
+
+    /// Queues `frame` without waiting, for senders that must never stall such as the peer
+    /// manager itself. A full queue under `Block` is handled like `Disconnect`: a peer that
+    /// can't take protocol frames has stalled.
+    pub fn try_push(&self, frame: Frame) -> Push {
+        match self.policy {
+            SendPolicy::Block => match self.offer(&frame, SendPolicy::Disconnect) {
+                Some(Push::Full) | None => Push::Stalled,
+                Some(pushed) => pushed,
+            },
+            policy => self.offer(&frame, policy).unwrap_or(Push::Full),
+        }
+    }
+
+    /// One attempt at queueing `frame`. None means `Block` found no room.
+    fn offer(&self, frame: &Frame, policy: SendPolicy) -> Option<Push> {
+        let mut q = self.frames.lock().unwrap();
+        if q.closed {
+            return Some(Push::Closed);
+        }
+
+        let pushed = if q.frames.len() < self.capacity {
+            Push::Queued
+        } else {
+            match policy {
+                SendPolicy::Block => return None,
+                SendPolicy::Disconnect => {
+                    // Later pushes see a closed queue; the writer still flushes what it has.
+                    q.closed = true;
+                    self.stats.slow_peer_disconnected();
+                    drop(q);
+                    self.readable.notify_one();
+                    return Some(Push::Full);
+                }
+                SendPolicy::DropOldest => {
+                    q.frames.pop_front();
+                    q.dropped += 1;
+                    self.stats.outbound_dropped();
+                    Push::DroppedOldest
+                }
+            }
+        };
+
+        q.frames.push_back(frame.clone());
+        q.high_water = q.high_water.max(q.frames.len());
+        drop(q);
+        self.readable.notify_one();
+        Some(pushed)
+    }
+
+    /// Next frame for the writer, or None once the queue is closed and empty.
+    pub async fn pop(&self) -> Option<Frame> {
+        loop {
+            {
+                let mut q = self.frames.lock().unwrap();
+                if let Some(frame) = q.frames.pop_front() {
+                    drop(q);
+                    self.writable.notify_one();
+                    return Some(frame);
+                }
+                if q.closed {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// Next frame if one is queued right now.
+    pub fn try_pop(&self) -> Option<Frame> {
+        let frame = self.frames.lock().unwrap().frames.pop_front();
+        if frame.is_some() {
+            self.writable.notify_one();
+        }
+        frame
+    }
+
+    /// Closes the queue and hands back what is still in it.
+    pub fn close(&self) -> VecDeque<Frame> {
+        let frames = {
+            let mut q = self.frames.lock().unwrap();
+            q.closed = true;
+            std::mem::take(&mut q.frames)
+        };
+        self.wake_all();
+        frames
+    }
+
+    /// Stops taking frames; the writer still gets what is queued.
+    pub fn finish(&self) {
+        self.frames.lock().unwrap().closed = true;
+        self.wake_all();
+    }
+
+    /// Wakes every blocked pusher and the writer once the queue is closed. The stored
+    /// permit covers a waiter that checked the queue but hasn't started waiting yet.
+    fn wake_all(&self) {
+        self.readable.notify_waiters();
+        self.writable.notify_waiters();
+        self.readable.notify_one();
+        self.writable.notify_one();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let q = self.frames.lock().unwrap();
+        QueueStats { depth: q.frames.len(), capacity: self.capacity, high_water: q.high_water, dropped: q.dropped }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+
+    fn queue(capacity: usize, policy: SendPolicy) -> (Arc<SendQueue>, Arc<Stats>) {
+        let stats = Arc::new(Stats::default());
+        (SendQueue::new(capacity, policy, stats.clone()), stats)
+    }
+
+    fn frame(s: &str) -> Frame {
+        Arc::from(s)
+    }
+
+    fn drain(q: &SendQueue) -> Vec<String> {
+        std::iter::from_fn(|| q.try_pop()).map(|f| f.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn frames_leave_in_order() {
+        let (q, _) = queue(4, SendPolicy::Block);
+        for s in ["a", "b", "c"] {
+            assert!(matches!(q.push(frame(s)).await, Push::Queued));
+        }
+        assert_eq!(q.pop().await.as_deref(), Some("a"));
+        assert_eq!(drain(&q), vec!["b", "c"]);
+        assert_eq!(q.stats().high_water, 3);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let (q, stats) = queue(2, SendPolicy::DropOldest);
+        q.push(frame("a")).await;
+        q.push(frame("b")).await;
+        assert!(matches!(q.push(frame("c")).await, Push::DroppedOldest));
+        assert!(matches!(q.try_push(frame("d")), Push::DroppedOldest));
+        assert_eq!(drain(&q), vec!["c", "d"]);
+        assert_eq!(q.stats().dropped, 2);
+        assert_eq!(stats.snapshot().dropped_outbound, 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_when_full() {
+        let (q, stats) = queue(1, SendPolicy::Disconnect);
+        q.push(frame("a")).await;
+        assert!(matches!(q.push(frame("b")).await, Push::Full));
+        assert!(matches!(q.push(frame("c")).await, Push::Closed));
+        assert_eq!(stats.snapshot().slow_peer_disconnects, 1);
+        // The writer still flushes what was queued before the close.
+        assert_eq!(q.pop().await.as_deref(), Some("a"));
+        assert_eq!(q.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (q, _) = queue(1, SendPolicy::Block);
+        q.push(frame("a")).await;
+
+        let pusher = tokio::spawn({
+            let q = q.clone();
+            async move { q.push(frame("b")).await }
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!pusher.is_finished());
+
+        assert_eq!(q.pop().await.as_deref(), Some("a"));
+        let pushed = time::timeout(Duration::from_secs(1), pusher).await.unwrap().unwrap();
+        assert!(matches!(pushed, Push::Queued));
+        assert_eq!(drain(&q), vec!["b"]);
+    }
+
+    async fn blocked_pushers(q: &Arc<SendQueue>, n: usize) -> Vec<tokio::task::JoinHandle<Push>> {
+        let pushers: Vec<_> = (0..n).map(|i| tokio::spawn({
+            let q = q.clone();
+            async move { q.push(frame(&i.to_string())).await }
+        })).collect();
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(pushers.iter().all(|p| !p.is_finished()));
+        pushers
+    }
+
+    async fn all_closed(pushers: Vec<tokio::task::JoinHandle<Push>>) {
+        for pusher in pushers {
+            let pushed = time::timeout(Duration::from_secs(1), pusher).await.expect("pusher still blocked").unwrap();
+            assert!(matches!(pushed, Push::Closed));
+        }
+    }
+
+    #[tokio::test]
+    async fn close_releases_every_blocked_pusher() {
+        let (q, _) = queue(1, SendPolicy::Block);
+        q.push(frame("a")).await;
+        let pushers = blocked_pushers(&q, 3).await;
+        q.close();
+        all_closed(pushers).await;
+    }
+
+    #[tokio::test]
+    async fn finish_releases_every_blocked_pusher() {
+        let (q, _) = queue(1, SendPolicy::Block);
+        q.push(frame("a")).await;
+        let pushers = blocked_pushers(&q, 3).await;
+        q.finish();
+        all_closed(pushers).await;
+        assert_eq!(q.pop().await.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn try_push_never_waits_under_block() {
+        let (q, _) = queue(1, SendPolicy::Block);
+        assert!(matches!(q.try_push(frame("a")), Push::Queued));
+        assert!(matches!(q.try_push(frame("b")), Push::Stalled));
+        assert!(matches!(q.try_push(frame("c")), Push::Closed));
+    }
+
+    #[tokio::test]
+    async fn close_hands_back_queued_frames() {
+        let (q, _) = queue(4, SendPolicy::Block);
+        q.push(frame("a")).await;
+        q.push(frame("b")).await;
+        assert_eq!(q.close().into_iter().map(|f| f.to_string()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(matches!(q.push(frame("c")).await, Push::Closed));
+        assert_eq!(q.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn finish_lets_the_writer_drain() {
+        let (q, _) = queue(4, SendPolicy::Block);
+        q.push(frame("a")).await;
+        q.finish();
+        assert!(matches!(q.try_push(frame("b")), Push::Closed));
+        assert_eq!(q.pop().await.as_deref(), Some("a"));
+        assert_eq!(q.pop().await, None);
+    }
+}
@@ -1,36 +1,107 @@
-use std::sync::Arc;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use anyhow::Context;
 
 use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
     net::{TcpListener},
+    sync::watch,
+    time,
 };
-use crate::{peer_manager::{PeerEntry, generate_unique_id}, tls_utils::{make_acceptor, make_server_config}};
+use crate::{peer_manager::{Admission, Direction, EventKind, PeerEntry, PeerEvent, generate_unique_id}, rate_limit::{DropReport, LimitKind, TokenBucket}, tls_utils::{make_acceptor, make_server_config}};
 use crate::peer_manager::{PeerSummary};
 use crate::{peer_manager::PeerManagerHandle};
-use crate::protocol::{send_join};
+use crate::protocol::{busy_payload, send_join};
 use tracing::{error, debug};
 
-pub async fn run(server_info: PeerSummary, pm: Arc<PeerManagerHandle>) -> anyhow::Result<()>{
+/// Source IPs whose connection attempts are tracked before idle ones are pruned.
+const MAX_TRACKED_IPS: usize = 1024;
+
+/// Turns a connection away with a BUSY frame listing peers to try instead.
+async fn reject<S>(mut socket: S, alternates: Vec<PeerSummary>)
+where
+    S: AsyncWrite + Unpin,
+{
+    match busy_payload(alternates) {
+        Ok(msg) => {
+            let _ = socket.write_all(format!("{}\n", msg).as_bytes()).await;
+        }
+        Err(e) => error!("Failed to encode BUSY: {}", e),
+    }
+    let _ = socket.shutdown().await;
+}
+
+/// Binds the peer listener, so a taken port fails startup instead of a background task.
+pub async fn bind(server_info: &PeerSummary) -> anyhow::Result<TcpListener> {
     let listen_addr = server_info.listen_addr_or_err(7)?;
-    let listener = TcpListener::bind(listen_addr.as_str()).await?;
+    let listener = TcpListener::bind(listen_addr.as_str()).await
+        .with_context(|| format!("Failed to bind peer listener on {}", listen_addr))?;
     debug!("Server listening on {}", listen_addr);
+    Ok(listener)
+}
+
+/// Accepts peers until `shutdown` flips to true.
+pub async fn run(listener: TcpListener, server_info: PeerSummary, pm: Arc<PeerManagerHandle>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()>{
+    let listen_addr = server_info.listen_addr_or_err(7)?;
 
 
     let maybe_acceptor = pm.tls_cert()
         .as_ref()
         .map(|c| make_acceptor(make_server_config(c).unwrap()));
 
+    let limits = pm.rate_limits();
+    let mut attempts: HashMap<IpAddr, (TokenBucket, DropReport)> = HashMap::new();
+
     loop {
-        let (socket, remote_addr) = listener.accept().await?;
+        let (socket, remote_addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = shutdown.wait_for(|stop| *stop) => {
+                debug!("Server stopped listening on {}", listen_addr);
+                return Ok(());
+            }
+        };
         debug!("New connection: {}", remote_addr);
+
+        let ip = remote_addr.ip();
+        let (bucket, report) = attempts.entry(ip)
+            .or_insert_with(|| (TokenBucket::new(limits.conn_rate, limits.conn_burst), DropReport::default()));
+        if !bucket.take(1.0) {
+            debug!("Dropping connection from {}: too many attempts", remote_addr);
+            pm.stats.rate_limited(LimitKind::Connections, 1);
+            if let Some(dropped) = report.dropped() {
+                let event = PeerEvent::RateLimited { conn_id: None, source: ip.to_string(), limit: LimitKind::Connections, dropped };
+                let _ = pm.events_tx().send(event).await;
+            }
+            continue;
+        }
+        // Buckets that have refilled carry no history worth keeping.
+        if attempts.len() > MAX_TRACKED_IPS {
+            attempts.retain(|_, (bucket, _)| !bucket.is_full());
+        }
+
+        if pm.bans.lock().unwrap().is_banned_ip(remote_addr.ip()) {
+            debug!("Dropping connection from banned address {}", remote_addr);
+            continue;
+        }
         
         let server_info_c = server_info.clone();
         let peer_manager = pm.clone();
         let acceptor = maybe_acceptor.clone();
 
         tokio::spawn(async move {
+            let admission = peer_manager.admit(false).await;
+
             if let Some(acceptor) = acceptor {
-                match acceptor.accept(socket).await {
+                // The admitted slot is held for the handshake, so a stalled client only gets so long.
+                let handshake = time::timeout(peer_manager.dial_config().tls_handshake_timeout, acceptor.accept(socket)).await
+                    .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out")));
+                match handshake {
                     Ok(tls_stream) => {
+                        if let Admission::Busy(alternates) = admission {
+                            debug!("Rejecting {}: no free slot", remote_addr);
+                            return reject(tls_stream, alternates).await;
+                        }
+
                         let summary = PeerSummary { 
                             remote_addr: Some(remote_addr.to_string()), 
                             listen_addr: None, 
@@ -39,7 +110,7 @@ pub async fn run(server_info: PeerSummary, pm: Arc<PeerManagerHandle>) -> anyhow
                         };
 
                         let conn_id = generate_unique_id();
-                        let entry = PeerEntry::new(conn_id.clone(), summary, tls_stream, peer_manager.events_tx());
+                        let entry = PeerEntry::new(conn_id.clone(), summary, tls_stream, false, peer_manager.peer_limiter(), peer_manager.send_queue(), peer_manager.events_tx());
                         
                         if let Err(e) = peer_manager.add_entry(conn_id.clone(), entry).await{
                             error!("Error during server run {}", e)
@@ -50,10 +121,16 @@ pub async fn run(server_info: PeerSummary, pm: Arc<PeerManagerHandle>) -> anyhow
                         };
                     }
                     Err(e) => {
-                        error!("TLS accept failed from {}: {}", remote_addr, e)
+                        error!("TLS accept failed from {}: {}", remote_addr, e);
+                        peer_manager.emit(EventKind::HandshakeFailed { addr: remote_addr.to_string(), direction: Direction::Inbound, error: e.to_string() });
                     }
                 }
             } else {
+                if let Admission::Busy(alternates) = admission {
+                    debug!("Rejecting {}: no free slot", remote_addr);
+                    return reject(socket, alternates).await;
+                }
+
                 let summary = PeerSummary { 
                     remote_addr: Some(remote_addr.to_string()), 
                     listen_addr: None, 
@@ -62,7 +139,7 @@ pub async fn run(server_info: PeerSummary, pm: Arc<PeerManagerHandle>) -> anyhow
                 };
                 let conn_id = generate_unique_id();
 
-                if let Err(e) = peer_manager.add_conn(conn_id.clone(), summary, socket).await{
+                if let Err(e) = peer_manager.add_conn(conn_id.clone(), summary, socket, false).await{
                     error!("Error during server run {}", e)
                 };
                 
@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::rate_limit::LimitKind;
+
+/// Node-wide counters exposed through `GET /stats`.
+#[derive(Default)]
+pub struct Stats {
+    dropped_messages: AtomicU64,
+    dropped_bytes_frames: AtomicU64,
+    rejected_connections: AtomicU64,
+    dropped_outbound: AtomicU64,
+    slow_peer_disconnects: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    /// Frames dropped because a peer exceeded its message rate.
+    pub dropped_messages: u64,
+    /// Frames dropped because a peer exceeded its byte rate.
+    pub dropped_bytes_frames: u64,
+    /// Connection attempts refused by the per-IP rate limit.
+    pub rejected_connections: u64,
+    /// Queued frames dropped by the drop-oldest send policy.
+    pub dropped_outbound: u64,
+    /// Peers disconnected by the disconnect send policy.
+    pub slow_peer_disconnects: u64,
+}
+
+impl Stats {
+    pub fn rate_limited(&self, kind: LimitKind, count: u64) {
+        let counter = match kind {
+            LimitKind::Messages => &self.dropped_messages,
+            LimitKind::Bytes => &self.dropped_bytes_frames,
+            LimitKind::Connections => &self.rejected_connections,
+        };
+        counter.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn outbound_dropped(&self) {
+        self.dropped_outbound.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slow_peer_disconnected(&self) {
+        self.slow_peer_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            dropped_messages: self.dropped_messages.load(Ordering::Relaxed),
+            dropped_bytes_frames: self.dropped_bytes_frames.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            dropped_outbound: self.dropped_outbound.load(Ordering::Relaxed),
+            slow_peer_disconnects: self.slow_peer_disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
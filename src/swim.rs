@@ -0,0 +1,485 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, time};
+use tracing::{debug, info, warn};
+
+use crate::peer_manager::{PeerManagerHandle, PeerSummary};
+
+/// Probe timings for the SWIM failure detector.
+#[derive(Clone, Debug)]
+pub struct SwimConfig {
+    pub probe_interval: Duration,
+    /// How long a direct (and then an indirect) probe waits for an ACK.
+    pub probe_timeout: Duration,
+    /// How long a member stays suspect before it is confirmed dead.
+    pub suspect_timeout: Duration,
+    /// Number of peers asked to probe on our behalf when a direct probe fails.
+    pub indirect_probes: usize,
+}
+
+/// How long confirmed-dead members stay listed before they are forgotten.
+const DEAD_RETENTION: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberUpdate {
+    pub node_id: String,
+    pub listen_addr: Option<String>,
+    pub state: MemberState,
+    pub incarnation: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Member {
+    pub node_id: String,
+    pub listen_addr: Option<String>,
+    pub state: MemberState,
+    pub incarnation: u64,
+    #[serde(skip)]
+    changed: Instant,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Ping {
+    seq: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PingReq {
+    seq: u64,
+    target: String,
+}
+
+/// What `on_registered` sends for a member that has just joined.
+#[derive(Debug)]
+enum Join {
+    /// A new member, alive, for everyone.
+    Announce(MemberUpdate),
+    /// Our view of a member we had as suspect or dead, for that member to refute.
+    Remind(MemberUpdate),
+}
+
+pub fn is_swim_msg(msg: &str) -> bool {
+    ["PING|", "ACK|", "PING_REQ|", "MEMBER|"].iter().any(|p| msg.starts_with(p))
+}
+
+/// SWIM-style failure detector: direct probes, indirect probes through random
+/// peers, a suspect state with a timeout, and dissemination of state changes.
+pub struct Swim {
+    cfg: SwimConfig,
+    incarnation: AtomicU64,
+    seq: AtomicU64,
+    members: Mutex<HashMap<String, Member>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+}
+
+impl Swim {
+    pub fn new(cfg: SwimConfig) -> Arc<Self> {
+        Arc::new(Self {
+            cfg,
+            incarnation: AtomicU64::new(0),
+            seq: AtomicU64::new(0),
+            members: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn members(&self) -> Vec<Member> {
+        self.members.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn member(&self, node_id: &str) -> Option<Member> {
+        self.members.lock().unwrap().get(node_id).cloned()
+    }
+
+    /// A node completed its JOIN with us. A new member is announced as alive. Only a member
+    /// may raise its own incarnation, so one we have as suspect or dead is instead told what we
+    /// know about it and revives itself by refuting that.
+    pub fn on_registered(&self, pm: &PeerManagerHandle, summary: &PeerSummary) {
+        let Some(node_id) = summary.node_id.clone() else { return };
+        match self.join(&node_id, summary.listen_addr.clone()) {
+            Some(Join::Announce(update)) => self.disseminate(pm, &update, None),
+            Some(Join::Remind(update)) => match serde_json::to_string(&update) {
+                Ok(json) => { let _ = pm.send_to(Some(node_id), None, format!("MEMBER|{}", json)); }
+                Err(e) => warn!("Failed to encode member update: {}", e),
+            },
+            None => {}
+        }
+    }
+
+    fn join(&self, node_id: &str, listen_addr: Option<String>) -> Option<Join> {
+        match self.member(node_id) {
+            None => {
+                let update = MemberUpdate { node_id: node_id.to_string(), listen_addr, state: MemberState::Alive, incarnation: 0 };
+                self.apply(&update).then_some(Join::Announce(update))
+            }
+            Some(m) if m.state == MemberState::Alive => None,
+            Some(m) => Some(Join::Remind(MemberUpdate {
+                node_id: m.node_id,
+                listen_addr: m.listen_addr,
+                state: m.state,
+                incarnation: m.incarnation,
+            })),
+        }
+    }
+
+    /// Applies `update` following SWIM precedence rules. Returns true if it changed anything.
+    fn apply(&self, update: &MemberUpdate) -> bool {
+        let mut members = self.members.lock().unwrap();
+        let Some(member) = members.get_mut(&update.node_id) else {
+            members.insert(update.node_id.clone(), Member {
+                node_id: update.node_id.clone(),
+                listen_addr: update.listen_addr.clone(),
+                state: update.state,
+                incarnation: update.incarnation,
+                changed: Instant::now(),
+            });
+            return true;
+        };
+
+        let newer = match (update.state, member.state) {
+            (MemberState::Alive, _) => update.incarnation > member.incarnation,
+            (MemberState::Suspect, MemberState::Alive) => update.incarnation >= member.incarnation,
+            (MemberState::Suspect, MemberState::Suspect) => update.incarnation > member.incarnation,
+            (MemberState::Suspect, MemberState::Dead) => false,
+            (MemberState::Dead, MemberState::Dead) => false,
+            (MemberState::Dead, _) => update.incarnation >= member.incarnation,
+        };
+        if !newer {
+            return false;
+        }
+
+        member.state = update.state;
+        member.incarnation = update.incarnation;
+        member.changed = Instant::now();
+        if update.listen_addr.is_some() {
+            member.listen_addr = update.listen_addr.clone();
+        }
+        true
+    }
+
+    fn disseminate(&self, pm: &PeerManagerHandle, update: &MemberUpdate, except: Option<String>) {
+        match serde_json::to_string(update) {
+            Ok(json) => pm.broadcast_except(format!("MEMBER|{}", json), except),
+            Err(e) => warn!("Failed to encode member update: {}", e),
+        }
+    }
+
+    /// Marks `node_id` with `state` at its current incarnation and tells everyone.
+    async fn declare(&self, pm: &PeerManagerHandle, node_id: &str, state: MemberState) {
+        let Some(member) = self.member(node_id) else { return };
+        let update = MemberUpdate {
+            node_id: node_id.to_string(),
+            listen_addr: member.listen_addr,
+            state,
+            incarnation: member.incarnation,
+        };
+        if self.apply(&update) {
+            info!("Member {} is now {:?}", node_id, state);
+            self.disseminate(pm, &update, None);
+            if state == MemberState::Dead {
+                pm.remove_node(node_id.to_string()).await;
+            }
+        }
+    }
+
+    /// The connection to `node_id` dropped without LEAVE. Nobody may be probing it now, so it
+    /// is suspect until it refutes that or registers again.
+    pub async fn on_disconnected(&self, pm: &PeerManagerHandle, node_id: &str) {
+        self.declare(pm, node_id, MemberState::Suspect).await;
+    }
+
+    /// `node_id` announced it is leaving; it is dead without going through suspicion.
+    pub async fn on_left(&self, pm: &PeerManagerHandle, node_id: &str) {
+        self.declare(pm, node_id, MemberState::Dead).await;
+    }
+
+    pub async fn handle_msg(self: Arc<Self>, pm: Arc<PeerManagerHandle>, conn_id: String, node_id: String, msg: String) -> anyhow::Result<()> {
+        let (kind, payload) = msg.split_once('|')
+            .ok_or_else(|| anyhow::anyhow!("Invalid SWIM message format"))?;
+
+        match kind {
+            "PING" => {
+                let ping: Ping = serde_json::from_str(payload)?;
+                let ack = serde_json::to_string(&Ping { seq: ping.seq })?;
+                pm.send_to(None, Some(conn_id), format!("ACK|{}", ack))
+            }
+            "ACK" => {
+                let ack: Ping = serde_json::from_str(payload)?;
+                if let Some(tx) = self.pending.lock().unwrap().remove(&ack.seq) {
+                    let _ = tx.send(());
+                }
+                Ok(())
+            }
+            "PING_REQ" => {
+                let req: PingReq = serde_json::from_str(payload)?;
+                // Probing can take a full timeout, so don't hold up the event loop.
+                tokio::spawn(async move {
+                    if self.ping(&pm, &req.target).await
+                        && let Ok(ack) = serde_json::to_string(&Ping { seq: req.seq }) {
+                        let _ = pm.send_to(None, Some(conn_id), format!("ACK|{}", ack));
+                    }
+                });
+                Ok(())
+            }
+            "MEMBER" => {
+                let update: MemberUpdate = serde_json::from_str(payload)?;
+                self.handle_update(&pm, node_id, update).await;
+                Ok(())
+            }
+            _ => anyhow::bail!("Unknown SWIM message {}", kind),
+        }
+    }
+
+    /// Someone suspects us or has us as dead: the answer is Alive at a higher incarnation.
+    fn refute(&self, update: &MemberUpdate, listen_addr: Option<String>) -> Option<MemberUpdate> {
+        if update.state == MemberState::Alive || update.incarnation < self.incarnation.load(Ordering::SeqCst) {
+            return None;
+        }
+        let incarnation = update.incarnation + 1;
+        self.incarnation.store(incarnation, Ordering::SeqCst);
+        Some(MemberUpdate { node_id: update.node_id.clone(), listen_addr, state: MemberState::Alive, incarnation })
+    }
+
+    async fn handle_update(&self, pm: &PeerManagerHandle, from: String, update: MemberUpdate) {
+        let self_id = pm.self_peer_info.node_id.clone().unwrap_or_default();
+
+        if update.node_id == self_id {
+            if let Some(refute) = self.refute(&update, pm.self_peer_info.listen_addr.clone()) {
+                info!("Refuting {:?} about ourselves with incarnation {}", update.state, refute.incarnation);
+                self.disseminate(pm, &refute, None);
+            }
+            return;
+        }
+
+        if self.apply(&update) {
+            debug!("Member {} -> {:?} ({})", update.node_id, update.state, update.incarnation);
+            if update.state == MemberState::Dead {
+                pm.remove_node(update.node_id.clone()).await;
+            }
+            self.disseminate(pm, &update, Some(from));
+        }
+    }
+
+    /// Sends PING to a connected node and waits for the ACK.
+    async fn ping(&self, pm: &PeerManagerHandle, node_id: &str) -> bool {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(seq, tx);
+
+        let msg = match serde_json::to_string(&Ping { seq }) {
+            Ok(json) => format!("PING|{}", json),
+            Err(_) => return false,
+        };
+        let acked = match pm.send_to(Some(node_id.to_string()), None, msg) {
+            Ok(()) => matches!(time::timeout(self.cfg.probe_timeout, rx).await, Ok(Ok(()))),
+            Err(_) => false,
+        };
+        self.pending.lock().unwrap().remove(&seq);
+        acked
+    }
+
+    /// Asks up to k random peers to probe `target` for us. A helper only answers if it is
+    /// connected to `target` itself.
+    async fn ping_indirect(&self, pm: &PeerManagerHandle, target: &str) -> bool {
+        let helpers: Vec<String> = pm.get_peers()
+            .into_iter()
+            .filter_map(|p| p.node_id)
+            .filter(|id| id != target)
+            .choose_multiple(&mut rand::rng(), self.cfg.indirect_probes);
+        if helpers.is_empty() {
+            return false;
+        }
+
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(seq, tx);
+
+        if let Ok(json) = serde_json::to_string(&PingReq { seq, target: target.to_string() }) {
+            for helper in helpers {
+                let _ = pm.send_to(Some(helper), None, format!("PING_REQ|{}", json));
+            }
+        }
+
+        // Helpers need a full probe timeout of their own before they can answer.
+        let acked = matches!(time::timeout(self.cfg.probe_timeout * 2, rx).await, Ok(Ok(())));
+        self.pending.lock().unwrap().remove(&seq);
+        acked
+    }
+
+    async fn probe(&self, pm: &PeerManagerHandle, target: &str) {
+        // Disconnected since it was picked; on_disconnected has dealt with it.
+        if pm.get_peer(target.to_string()).is_none() {
+            return;
+        }
+        if self.ping(pm, target).await {
+            return;
+        }
+        if self.ping_indirect(pm, target).await {
+            return;
+        }
+        debug!("Probe of {} failed", target);
+        self.declare(pm, target, MemberState::Suspect).await;
+    }
+
+    pub async fn probe_loop(self: Arc<Self>, pm: Arc<PeerManagerHandle>) {
+        let mut ticker = time::interval(self.cfg.probe_interval);
+
+        loop {
+            ticker.tick().await;
+
+            let (target, expired) = {
+                let mut members = self.members.lock().unwrap();
+                members.retain(|_, m| m.state != MemberState::Dead || m.changed.elapsed() < DEAD_RETENTION);
+
+                let expired: Vec<String> = members.values()
+                    .filter(|m| m.state == MemberState::Suspect && m.changed.elapsed() >= self.cfg.suspect_timeout)
+                    .map(|m| m.node_id.clone())
+                    .collect();
+                // Only members we hold a connection to are ours to probe; the others are
+                // probed by their own neighbours, whose updates reach us as MEMBER frames.
+                let target = members.values()
+                    .filter(|m| m.state != MemberState::Dead && pm.get_peer(m.node_id.clone()).is_some())
+                    .map(|m| m.node_id.clone())
+                    .choose(&mut rand::rng());
+                (target, expired)
+            };
+
+            for node_id in expired {
+                self.declare(&pm, &node_id, MemberState::Dead).await;
+            }
+
+            if let Some(target) = target {
+                let swim = self.clone();
+                let pm = pm.clone();
+                tokio::spawn(async move { swim.probe(&pm, &target).await });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn swim() -> Arc<Swim> {
+        Swim::new(SwimConfig {
+            probe_interval: Duration::from_secs(1),
+            probe_timeout: Duration::from_millis(500),
+            suspect_timeout: Duration::from_secs(5),
+            indirect_probes: 3,
+        })
+    }
+
+    fn update(state: MemberState, incarnation: u64) -> MemberUpdate {
+        MemberUpdate { node_id: "b".to_string(), listen_addr: None, state, incarnation }
+    }
+
+    fn state(swim: &Swim) -> (MemberState, u64) {
+        let m = swim.member("b").unwrap();
+        (m.state, m.incarnation)
+    }
+
+    #[test]
+    fn first_update_adds_member() {
+        let swim = swim();
+        assert!(swim.apply(&update(MemberState::Suspect, 3)));
+        assert_eq!(state(&swim), (MemberState::Suspect, 3));
+    }
+
+    #[test]
+    fn suspect_overrides_alive_at_same_incarnation() {
+        let swim = swim();
+        swim.apply(&update(MemberState::Alive, 2));
+        assert!(swim.apply(&update(MemberState::Suspect, 2)));
+        assert!(!swim.apply(&update(MemberState::Suspect, 2)));
+        assert!(!swim.apply(&update(MemberState::Suspect, 1)));
+        assert_eq!(state(&swim), (MemberState::Suspect, 2));
+    }
+
+    #[test]
+    fn alive_needs_higher_incarnation() {
+        let swim = swim();
+        swim.apply(&update(MemberState::Suspect, 2));
+        assert!(!swim.apply(&update(MemberState::Alive, 2)));
+        assert!(swim.apply(&update(MemberState::Alive, 3)));
+        assert_eq!(state(&swim), (MemberState::Alive, 3));
+    }
+
+    #[test]
+    fn dead_is_final_at_its_incarnation() {
+        let swim = swim();
+        swim.apply(&update(MemberState::Alive, 1));
+        assert!(swim.apply(&update(MemberState::Dead, 1)));
+        assert!(!swim.apply(&update(MemberState::Suspect, 5)));
+        assert!(!swim.apply(&update(MemberState::Dead, 2)));
+        assert!(!swim.apply(&update(MemberState::Alive, 1)));
+        // What the member sends to refute it.
+        assert!(swim.apply(&update(MemberState::Alive, 2)));
+        assert_eq!(state(&swim), (MemberState::Alive, 2));
+    }
+
+    #[test]
+    fn listen_addr_is_kept_when_missing() {
+        let swim = swim();
+        swim.apply(&MemberUpdate { listen_addr: Some("127.0.0.1:8000".to_string()), ..update(MemberState::Alive, 0) });
+        swim.apply(&update(MemberState::Suspect, 0));
+        assert_eq!(swim.member("b").unwrap().listen_addr.as_deref(), Some("127.0.0.1:8000"));
+    }
+
+    #[test]
+    fn join_announces_a_new_member() {
+        let swim = swim();
+        assert!(matches!(swim.join("b", None), Some(Join::Announce(u)) if u.state == MemberState::Alive && u.incarnation == 0));
+        assert_eq!(state(&swim), (MemberState::Alive, 0));
+        assert!(swim.join("b", None).is_none());
+    }
+
+    #[test]
+    fn rejoin_keeps_the_known_incarnation() {
+        let swim = swim();
+        swim.apply(&update(MemberState::Suspect, 4));
+        let Some(Join::Remind(remind)) = swim.join("b", None) else { panic!("suspect member not reminded") };
+        assert_eq!((remind.state, remind.incarnation), (MemberState::Suspect, 4));
+        // Nothing changes here until the member refutes.
+        assert_eq!(state(&swim), (MemberState::Suspect, 4));
+    }
+
+    #[test]
+    fn stale_rejoin_does_not_override_a_newer_update() {
+        let swim = swim();
+        swim.apply(&update(MemberState::Alive, 2));
+        // A newer suspicion raised elsewhere arrives, then the member reconnects.
+        swim.apply(&update(MemberState::Suspect, 3));
+        swim.join("b", None);
+        assert_eq!(state(&swim), (MemberState::Suspect, 3));
+    }
+
+    #[test]
+    fn member_refutes_with_a_higher_incarnation() {
+        let b = swim();
+        let suspected = update(MemberState::Dead, 5);
+        let refute = b.refute(&suspected, Some("127.0.0.1:8000".to_string())).unwrap();
+        assert_eq!((refute.state, refute.incarnation), (MemberState::Alive, 6));
+        assert_eq!(refute.listen_addr.as_deref(), Some("127.0.0.1:8000"));
+
+        // Older news and alive updates need no answer.
+        assert!(b.refute(&update(MemberState::Suspect, 4), None).is_none());
+        assert!(b.refute(&update(MemberState::Alive, 9), None).is_none());
+
+        // The refutation revives the member everywhere.
+        let a = swim();
+        a.apply(&suspected);
+        assert!(a.apply(&refute));
+        assert_eq!(state(&a), (MemberState::Alive, 6));
+    }
+}
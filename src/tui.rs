@@ -0,0 +1,426 @@
+use std::{collections::VecDeque, io, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::JoinHandle, time::Duration};
+
+use anyhow::Context;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use tokio::sync::mpsc;
+use tracing_subscriber::{filter::{LevelFilter, Targets}, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{peer_manager::{EventKind, FrontendEvent, PeerInfo, PeerManagerHandle, PeerSummary}, ui::{self, Output}};
+
+/// Lines kept in the message and log panes.
+const SCROLLBACK: usize = 1000;
+
+/// How often the screen is redrawn when no key is pressed.
+const TICK: Duration = Duration::from_millis(100);
+
+fn push_line(lines: &mut VecDeque<String>, line: String) {
+    if lines.len() == SCROLLBACK {
+        lines.pop_front();
+    }
+    lines.push_back(line);
+}
+
+/// Collects log lines for the log pane, so tracing never writes over the screen.
+#[derive(Clone, Default)]
+pub struct LogPane {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl io::Write for LogPane {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        let mut lines = self.lines.lock().unwrap();
+        for line in text.lines() {
+            push_line(&mut lines, line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends tracing output to `pane`. `RUST_LOG` is read the same way `fmt::init` reads it.
+pub fn init_logging(pane: LogPane) {
+    let targets = std::env::var("RUST_LOG").ok()
+        .and_then(|var| var.parse::<Targets>().ok())
+        .unwrap_or_else(|| Targets::new().with_default(LevelFilter::INFO));
+    tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::TRACE)
+        .with_ansi(false)
+        .with_writer(move || pane.clone())
+        .finish()
+        .with(targets)
+        .init();
+}
+
+/// A connected peer as the peer pane shows it.
+struct PeerRow {
+    summary: PeerSummary,
+    outbound: bool,
+    connected_for: Duration,
+    queue_depth: usize,
+    state: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    messages: VecDeque<String>,
+    peers: Vec<PeerRow>,
+    input: String,
+    history: Vec<String>,
+    /// Position in `history` while browsing it with Up and Down.
+    recalled: Option<usize>,
+}
+
+impl State {
+    fn on_event(&mut self, event: FrontendEvent) {
+        let name = |peer: &PeerInfo| ui::name_or_id(&peer.uname, &peer.node_id);
+        let line = match &event.kind {
+            EventKind::PeerJoined { peer } => Some(format!("* {} joined", name(peer))),
+            EventKind::PeerReconnected { peer } => Some(format!("* {} reconnected", name(peer))),
+            EventKind::PeerDisconnected { peer, .. } => Some(format!("* {} disconnected", name(peer))),
+            EventKind::PeerLeft { peer, .. } => Some(format!("* {} left", name(peer))),
+            _ => ui::event_line(&event),
+        };
+        if let Some(line) = line {
+            push_line(&mut self.messages, line);
+        }
+    }
+
+    fn refresh_peers(&mut self, pm: &PeerManagerHandle) {
+        let mut peers = pm.get_peers();
+        peers.sort_by(|a, b| a.uname.cmp(&b.uname));
+        self.peers = peers.into_iter().filter_map(|summary| {
+            let node_id = summary.node_id.clone()?;
+            let conn = pm.peer_conn(node_id.clone())?;
+            let state = pm.swim.as_ref().and_then(|swim| swim.member(&node_id)).map(|m| format!("{:?}", m.state));
+            Some(PeerRow { summary, outbound: conn.outbound, connected_for: conn.connected_for, queue_depth: conn.queue.depth, state })
+        }).collect();
+    }
+
+    fn complete(&mut self, pm: &PeerManagerHandle) {
+        let (start, found) = ui::complete(pm, &self.input);
+        match found.as_slice() {
+            [] => {}
+            [only] => {
+                self.input.truncate(start);
+                self.input.push_str(only);
+                self.input.push(' ');
+            }
+            _ => {
+                let common = found.iter().skip(1).fold(found[0].as_str(), |common, name| {
+                    let len = common.chars().zip(name.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+                    &common[..len]
+                });
+                if common.len() > self.input.len() - start {
+                    let common = common.to_string();
+                    self.input.truncate(start);
+                    self.input.push_str(&common);
+                } else {
+                    push_line(&mut self.messages, found.join("  "));
+                }
+            }
+        }
+    }
+
+    fn recall(&mut self, older: bool) {
+        let next = match (self.recalled, older) {
+            (None, true) => self.history.len().checked_sub(1),
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.history.len() => Some(i + 1),
+            _ => None,
+        };
+        self.recalled = next;
+        self.input = next.map(|i| self.history[i].clone()).unwrap_or_default();
+    }
+}
+
+/// The last lines of `lines` that fit into `area` inside a border.
+fn tail<'a>(lines: impl ExactSizeIterator<Item = &'a String>, area: Rect) -> Vec<Line<'a>> {
+    let skip = lines.len().saturating_sub(area.height.saturating_sub(2) as usize);
+    lines.skip(skip).map(|line| Line::raw(line.as_str())).collect()
+}
+
+fn draw(frame: &mut Frame, state: &State, logs: &VecDeque<String>) {
+    let [top, log_area, input_area] = Layout::vertical([Constraint::Min(6), Constraint::Length(10), Constraint::Length(3)])
+        .areas(frame.area());
+    let [message_area, peer_area] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .areas(top);
+
+    let messages = Paragraph::new(tail(state.messages.iter(), message_area))
+        .block(Block::bordered().title(" Messages "));
+    frame.render_widget(messages, message_area);
+
+    let rows = state.peers.iter().map(|p| Row::new([
+        p.summary.uname.clone().unwrap_or("?".to_string()),
+        p.summary.node_id.as_deref().unwrap_or("?").chars().take(8).collect(),
+        p.summary.listen_addr.clone().unwrap_or("?".to_string()),
+        if p.outbound { "out" } else { "in" }.to_string(),
+        format!("{}s", p.connected_for.as_secs()),
+        p.queue_depth.to_string(),
+        p.state.clone().unwrap_or_default(),
+    ]));
+    let widths = [
+        Constraint::Min(6), Constraint::Length(8), Constraint::Length(15), Constraint::Length(3),
+        Constraint::Length(6), Constraint::Length(5), Constraint::Length(7),
+    ];
+    let peers = Table::new(rows, widths)
+        .header(Row::new(["name", "node", "listen", "dir", "up", "queue", "swim"]).style(Style::new().bold()))
+        .block(Block::bordered().title(format!(" Peers ({}) ", state.peers.len())));
+    frame.render_widget(peers, peer_area);
+
+    let logs = Paragraph::new(tail(logs.iter(), log_area))
+        .block(Block::bordered().title(" Log "))
+        .dim();
+    frame.render_widget(logs, log_area);
+
+    // Keep the end of a long line and the cursor in view.
+    let width = input_area.width.saturating_sub(3) as usize;
+    let typed = state.input.chars().count();
+    let scroll = typed.saturating_sub(width);
+    let input = Paragraph::new(state.input.as_str())
+        .scroll((0, scroll as u16))
+        .block(Block::bordered().title(" Tab completes, /help lists commands, Ctrl-C quits "));
+    frame.render_widget(input, input_area);
+    frame.set_cursor_position((input_area.x + 1 + (typed - scroll) as u16, input_area.y + 1));
+}
+
+/// Full-screen terminal UI: messages, peers, log and an input line.
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Tui {
+    /// Takes over the terminal. Entered lines come out of the returned receiver like console
+    /// lines; command output sent to the returned `Output` lands in the message pane.
+    pub fn start(pm: Arc<PeerManagerHandle>, events: mpsc::Receiver<FrontendEvent>, logs: LogPane)
+        -> anyhow::Result<(Self, mpsc::Receiver<String>, Output)> {
+        let terminal = ratatui::try_init().context("Failed to start the terminal UI")?;
+        let (lines_tx, lines_rx) = mpsc::channel(64);
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = stop.clone();
+        // Drawing and key reads block, so they get a thread of their own.
+        let thread = std::thread::spawn(move || {
+            run(terminal, pm, events, out_rx, lines_tx, logs, thread_stop);
+            ratatui::restore();
+        });
+        Ok((Self { stop, thread }, lines_rx, Output::Pane(out_tx)))
+    }
+
+    /// Gives the terminal back.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.thread.join();
+    }
+}
+
+fn run(
+    mut terminal: DefaultTerminal,
+    pm: Arc<PeerManagerHandle>,
+    mut events: mpsc::Receiver<FrontendEvent>,
+    mut output: mpsc::UnboundedReceiver<String>,
+    lines: mpsc::Sender<String>,
+    logs: LogPane,
+    stop: Arc<AtomicBool>,
+) {
+    let mut state = State::default();
+
+    while !stop.load(Ordering::SeqCst) {
+        while let Ok(event) = events.try_recv() {
+            state.on_event(event);
+        }
+        while let Ok(line) = output.try_recv() {
+            for line in line.lines() {
+                push_line(&mut state.messages, line.to_string());
+            }
+        }
+        state.refresh_peers(&pm);
+
+        let drawn = {
+            let logs = logs.lines.lock().unwrap();
+            terminal.draw(|frame| draw(frame, &state, &logs))
+        };
+        if drawn.is_err() {
+            break;
+        }
+
+        if !event::poll(TICK).unwrap_or(false) {
+            continue;
+        }
+        let Ok(Event::Key(key)) = event::read() else { continue };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let line = match key.code {
+            // Raw mode swallows SIGINT, so Ctrl-C is a key like any other here.
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => "/quit".to_string(),
+            KeyCode::Char(c) => {
+                state.input.push(c);
+                continue;
+            }
+            KeyCode::Backspace => {
+                state.input.pop();
+                continue;
+            }
+            KeyCode::Esc => {
+                state.input.clear();
+                continue;
+            }
+            KeyCode::Tab => {
+                state.complete(&pm);
+                continue;
+            }
+            KeyCode::Up | KeyCode::Down => {
+                state.recall(key.code == KeyCode::Up);
+                continue;
+            }
+            KeyCode::Enter => std::mem::take(&mut state.input),
+            _ => continue,
+        };
+
+        state.recalled = None;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        // Chat shows up once it was sent, from its message_sent event.
+        if trimmed.starts_with('/') {
+            push_line(&mut state.messages, format!("> {}", trimmed));
+        }
+        if state.history.last().map(String::as_str) != Some(trimmed) {
+            state.history.push(trimmed.to_string());
+        }
+        if lines.blocking_send(line).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use ratatui::{Terminal, backend::TestBackend};
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::peer_manager::{Direction, tests::{HIGH, LOW, Raw, attach, node, until}};
+
+    fn peer(uname: Option<&str>) -> PeerInfo {
+        PeerInfo { node_id: HIGH.to_string(), uname: uname.map(str::to_string), listen_addr: None, ip: None, direction: Direction::Inbound }
+    }
+
+    fn screen(state: &State, logs: &VecDeque<String>) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(160, 30)).unwrap();
+        terminal.draw(|frame| draw(frame, state, logs)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer.content().chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn panes_keep_the_last_lines() {
+        let mut lines = VecDeque::new();
+        for i in 0..SCROLLBACK + 5 {
+            push_line(&mut lines, i.to_string());
+        }
+        assert_eq!(lines.len(), SCROLLBACK);
+        assert_eq!(lines.front().map(String::as_str), Some("5"));
+    }
+
+    #[test]
+    fn log_pane_splits_writes_into_lines() {
+        let mut pane = LogPane::default();
+        pane.write_all(b"first\nsecond\n").unwrap();
+        pane.write_all(b"third").unwrap();
+        assert_eq!(*pane.lines.lock().unwrap(), ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn events_become_message_lines() {
+        let mut state = State::default();
+        state.on_event(EventKind::PeerJoined { peer: peer(Some("bob")) }.into());
+        state.on_event(EventKind::PeerLeft { peer: peer(None), connected_secs: 3 }.into());
+        state.on_event(EventKind::MessageSent { message_id: "m".into(), to: None, to_uname: None, content: "hi".into() }.into());
+        assert_eq!(state.messages, ["* bob joined", "* ffffffff left", "You: hi"]);
+    }
+
+    #[test]
+    fn history_is_browsed_with_up_and_down() {
+        let mut state = State { history: vec!["one".into(), "two".into()], ..State::default() };
+        state.recall(true);
+        assert_eq!(state.input, "two");
+        state.recall(true);
+        state.recall(true);
+        assert_eq!(state.input, "one");
+        state.recall(false);
+        assert_eq!(state.input, "two");
+        state.recall(false);
+        assert_eq!((state.input.as_str(), state.recalled), ("", None));
+    }
+
+    #[tokio::test]
+    async fn tab_completes_commands_and_peers() {
+        let (pm, _events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        attach(&pm, ours, false).await;
+        let mut raw = Raw::new(theirs);
+        raw.join(HIGH).await;
+        until(|| pm.get_peer(HIGH.to_string()).is_some()).await;
+        raw.send("NICK|bob").await;
+        until(|| pm.get_peer(HIGH.to_string()).and_then(|p| p.uname).is_some()).await;
+
+        let mut state = State { input: "/wh".into(), ..State::default() };
+        state.complete(&pm);
+        assert_eq!(state.input, "/whois ");
+        state.input.push('b');
+        state.complete(&pm);
+        assert_eq!(state.input, "/whois bob ");
+
+        state.input = "/msg ffff".into();
+        state.complete(&pm);
+        assert_eq!(state.input, format!("/msg {} ", HIGH));
+
+        // Several matches and nothing more in common: they are listed instead.
+        state.input = "/".into();
+        state.complete(&pm);
+        assert_eq!(state.input, "/");
+        assert!(state.messages.back().unwrap().contains("/disconnect"));
+    }
+
+    #[tokio::test]
+    async fn screen_shows_messages_peers_logs_and_input() {
+        let (pm, _events) = node(LOW);
+        let (ours, theirs) = duplex(64 * 1024);
+        attach(&pm, ours, false).await;
+        let mut raw = Raw::new(theirs);
+        raw.join(HIGH).await;
+        until(|| pm.get_peer(HIGH.to_string()).is_some()).await;
+
+        let mut state = State { input: "typing".into(), ..State::default() };
+        state.on_event(EventKind::PeerJoined { peer: peer(None) }.into());
+        state.refresh_peers(&pm);
+        let logs = VecDeque::from(["a log line".to_string()]);
+
+        let screen = screen(&state, &logs);
+        assert!(screen.contains("* ffffffff joined"));
+        assert!(screen.contains("Peers (1)"));
+        assert!(screen.contains("10.0.0.9:4000"));
+        assert!(screen.contains("a log line"));
+        assert!(screen.contains("typing"));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{sync::Arc, time::Duration};
+
+use rustyline::{completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter, history::DefaultHistory, validate::Validator, Context, Editor, Helper};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::{client, gossip, peer_manager::{EventKind, FrontendEvent, PeerManagerHandle, PeerSummary}};
+
+const COMMANDS: [&str; 9] = ["/peers", "/connect", "/disconnect", "/msg", "/nick", "/whois", "/stats", "/quit", "/help"];
+
+/// Commands whose first argument is a peer.
+const NODE_COMMANDS: [&str; 3] = ["/disconnect", "/msg", "/whois"];
+
+const HELP: &str = "\
+/peers                 list connected peers
+/connect <addr>        dial a peer at host:port
+/disconnect <node>     drop a peer
+/msg <node> <text>     send a private message
+/nick <name>           change your name
+/whois <node>          show a peer's connection
+/stats                 show node counters
+/quit                  shut down
+Any other line is sent to everyone. <node> is a name, a node id or an id prefix.";
+
+/// Where command output goes: stdout for the line console, the message pane for the TUI.
+#[derive(Clone)]
+pub enum Output {
+    Stdout,
+    Pane(mpsc::UnboundedSender<String>),
+}
+
+impl Output {
+    pub fn line(&self, text: impl Into<String>) {
+        match self {
+            Output::Stdout => println!("{}", text.into()),
+            Output::Pane(tx) => { let _ = tx.send(text.into()); }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Peers,
+    Connect(String),
+    Disconnect(String),
+    Msg(String, String),
+    Nick(String),
+    Whois(String),
+    Stats,
+    Quit,
+    Help,
+}
+
+/// Parses a console line without its leading `/`.
+pub fn parse_command(line: &str) -> anyhow::Result<Command> {
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    let arg = |usage: &str| {
+        if rest.is_empty() {
+            anyhow::bail!("usage: /{} {}", name, usage);
+        }
+        Ok(rest.to_string())
+    };
+
+    Ok(match name {
+        "peers" => Command::Peers,
+        "connect" => Command::Connect(arg("<addr>")?),
+        "disconnect" => Command::Disconnect(arg("<node>")?),
+        "msg" => {
+            let (node, text) = rest.split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow::anyhow!("usage: /msg <node> <text>"))?;
+            Command::Msg(node.to_string(), text.trim().to_string())
+        }
+        "nick" => Command::Nick(arg("<name>")?),
+        "whois" => Command::Whois(arg("<node>")?),
+        "stats" => Command::Stats,
+        "quit" | "exit" => Command::Quit,
+        "help" | "?" => Command::Help,
+        _ => anyhow::bail!("unknown command /{}, try /help", name),
+    })
+}
+
+/// Runs everything but `/quit`, which the caller handles.
+pub async fn run_command(cmd: Command, pm: Arc<PeerManagerHandle>, out: &Output) -> anyhow::Result<()> {
+    match cmd {
+        Command::Peers => {
+            let mut peers = pm.get_peers();
+            if peers.is_empty() {
+                out.line("No peers connected");
+            }
+            peers.sort_by(|a, b| a.uname.cmp(&b.uname));
+            for peer in peers {
+                out.line(format!("{:<16} {:<36} {}",
+                    peer.uname.as_deref().unwrap_or("?"),
+                    peer.node_id.as_deref().unwrap_or("?"),
+                    peer.listen_addr.as_deref().unwrap_or("?")));
+            }
+        }
+        Command::Connect(addr) => {
+            out.line(format!("Connecting to {}", addr));
+            // Dialing can take a full connect timeout; the console stays usable meanwhile.
+            let out = out.clone();
+            tokio::spawn(async move {
+                match client::connect_node(pm.clone(), addr.clone()).await {
+                    Ok(node_id) => {
+                        let peer = pm.get_peer(node_id.clone());
+                        let name = peer.and_then(|p| p.uname).unwrap_or_else(|| "?".to_string());
+                        out.line(format!("Connected to {} ({}) at {}", name, node_id, addr));
+                    }
+                    Err(e) => out.line(format!("Could not connect to {}: {}", addr, e)),
+                }
+            });
+        }
+        Command::Disconnect(node) => {
+            let peer = resolve(&pm, &node)?;
+            pm.remove_node(peer.node_id.clone().unwrap_or_default()).await;
+            out.line(format!("Disconnected {}", display_name(&peer)));
+        }
+        Command::Msg(node, text) => {
+            let peer = resolve(&pm, &node)?;
+            gossip::send_direct(&pm, peer.node_id.clone().unwrap_or_default(), &text).await?;
+        }
+        Command::Nick(name) => {
+            pm.set_uname(name.clone()).await?;
+            out.line(format!("You are now known as {}", name));
+        }
+        Command::Whois(node) => {
+            let peer = resolve(&pm, &node)?;
+            let conn = pm.peer_conn(peer.node_id.clone().unwrap_or_default())
+                .ok_or_else(|| anyhow::anyhow!("{} just disconnected", display_name(&peer)))?;
+            let s = &conn.summary;
+            out.line(format!("name:        {}", s.uname.as_deref().unwrap_or("?")));
+            out.line(format!("node id:     {}", s.node_id.as_deref().unwrap_or("?")));
+            out.line(format!("listen addr: {}", s.listen_addr.as_deref().unwrap_or("?")));
+            out.line(format!("remote addr: {}", s.remote_addr.as_deref().unwrap_or("?")));
+            out.line(format!("direction:   {}", if conn.outbound { "outbound" } else { "inbound" }));
+            out.line(format!("connected:   {:?}", Duration::from_secs(conn.connected_for.as_secs())));
+            out.line(format!("score:       {}", conn.score));
+            out.line(format!("send queue:  {}/{} (high water {}, dropped {})",
+                conn.queue.depth, conn.queue.capacity, conn.queue.high_water, conn.queue.dropped));
+            if let Some(member) = pm.swim.as_ref().and_then(|swim| swim.member(s.node_id.as_deref().unwrap_or_default())) {
+                out.line(format!("swim:        {:?} (incarnation {})", member.state, member.incarnation));
+            }
+        }
+        Command::Stats => {
+            out.line(format!("{:<22} {}", "peers", pm.get_peers().len()));
+            if let serde_json::Value::Object(stats) = serde_json::to_value(pm.stats.snapshot())? {
+                for (name, value) in stats {
+                    out.line(format!("{:<22} {}", name, value));
+                }
+            }
+        }
+        Command::Help => out.line(HELP),
+        Command::Quit => {}
+    }
+    Ok(())
+}
+
+/// A peer's name, or the start of its node id when it never sent one.
+pub fn name_or_id(uname: &Option<String>, node_id: &str) -> String {
+    uname.clone().unwrap_or_else(|| node_id.chars().take(8).collect())
+}
+
+/// Chat line for a frontend event, or None for events only the log shows.
+pub fn event_line(event: &FrontendEvent) -> Option<String> {
+    match &event.kind {
+        EventKind::MessageReceived { from, uname, content, .. } => Some(format!("{}: {}", name_or_id(uname, from), content)),
+        EventKind::DirectMessage { from, uname, content, .. } => Some(format!("{} (private): {}", name_or_id(uname, from), content)),
+        EventKind::MessageSent { to: None, content, .. } => Some(format!("You: {}", content)),
+        EventKind::MessageSent { to: Some(to), to_uname, content, .. } => Some(format!("You -> {} (private): {}", name_or_id(to_uname, to), content)),
+        EventKind::PeerRenamed { node_id, old, uname } => Some(format!("* {} is now known as {}", name_or_id(old, node_id), uname)),
+        _ => None,
+    }
+}
+
+/// Prints chat from the frontend event stream for the line console. Chat typed here is
+/// already on screen, so only private messages and chat sent through the API are echoed.
+pub fn print_events(mut events: mpsc::Receiver<FrontendEvent>) {
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let EventKind::MessageSent { to: None, .. } = &event.kind {
+                continue;
+            }
+            if let Some(line) = event_line(&event) {
+                println!("{}", line);
+            }
+        }
+    });
+}
+
+fn display_name(peer: &PeerSummary) -> String {
+    peer.uname.clone().or_else(|| peer.node_id.clone()).unwrap_or_default()
+}
+
+/// Finds a connected peer by name, node id or unambiguous node id prefix.
+fn resolve(pm: &PeerManagerHandle, node: &str) -> anyhow::Result<PeerSummary> {
+    let peers = pm.get_peers();
+    let by = |matches: &dyn Fn(&PeerSummary) -> bool| -> Vec<&PeerSummary> { peers.iter().filter(|p| matches(p)).collect() };
+
+    let mut found = by(&|p| p.node_id.as_deref() == Some(node));
+    if found.is_empty() {
+        found = by(&|p| p.uname.as_deref() == Some(node));
+    }
+    if found.is_empty() {
+        found = by(&|p| p.node_id.as_deref().is_some_and(|id| id.starts_with(node)));
+    }
+
+    match found.as_slice() {
+        [peer] => Ok((*peer).clone()),
+        [] => anyhow::bail!("no connected peer called {}", node),
+        _ => anyhow::bail!("{} matches {} peers, use a node id", node, found.len()),
+    }
+}
+
+/// Completes command names and, after commands that take one, peer names and node ids.
+struct ConsoleHelper {
+    pm: Arc<PeerManagerHandle>,
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(&self.pm, &line[..pos]))
+    }
+}
+
+/// Candidates for the word ending `line`, and the byte offset that word starts at.
+pub fn complete(pm: &PeerManagerHandle, line: &str) -> (usize, Vec<String>) {
+    let Some((cmd, arg)) = line.split_once(' ') else {
+        if !line.starts_with('/') {
+            return (0, vec![]);
+        }
+        let found = COMMANDS.iter().filter(|c| c.starts_with(line)).map(|c| c.to_string()).collect();
+        return (0, found);
+    };
+    // Only the first argument names a peer.
+    if !NODE_COMMANDS.contains(&cmd) || arg.contains(' ') {
+        return (line.len(), vec![]);
+    }
+
+    let peers = pm.get_peers();
+    let mut found: Vec<String> = peers.into_iter()
+        .flat_map(|p| [p.uname, p.node_id])
+        .flatten()
+        .filter(|name| name.starts_with(arg) && !name.contains(' '))
+        .collect();
+    found.sort();
+    found.dedup();
+    (cmd.len() + 1, found)
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+/// Reads console lines on a plain thread: a blocking read left in Tokio's pool would keep
+/// the runtime from shutting down.
+/// Ctrl-C while editing a line arrives as `/quit`, since the terminal doesn't raise SIGINT then.
+pub fn console_lines(pm: Arc<PeerManagerHandle>) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(64);
+    std::thread::spawn(move || {
+        let mut editor = match Editor::<ConsoleHelper, DefaultHistory>::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                warn!("Console unavailable: {}", e);
+                return;
+            }
+        };
+        editor.set_helper(Some(ConsoleHelper { pm }));
+
+        loop {
+            let line = match editor.readline("") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => "/quit".to_string(),
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    debug!("Console read failed: {}", e);
+                    break;
+                }
+            };
+            if !line.trim().is_empty() {
+                let _ = editor.add_history_entry(line.as_str());
+            }
+            if tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err(line: &str) -> String {
+        parse_command(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(parse_command("peers").unwrap(), Command::Peers);
+        assert_eq!(parse_command("stats").unwrap(), Command::Stats);
+        assert_eq!(parse_command("exit").unwrap(), Command::Quit);
+        assert_eq!(parse_command("?").unwrap(), Command::Help);
+    }
+
+    #[test]
+    fn argument_is_trimmed() {
+        assert_eq!(parse_command("connect   127.0.0.1:8000  ").unwrap(), Command::Connect("127.0.0.1:8000".to_string()));
+        assert_eq!(parse_command("nick Alice Smith").unwrap(), Command::Nick("Alice Smith".to_string()));
+    }
+
+    #[test]
+    fn missing_argument_shows_usage() {
+        assert_eq!(err("connect"), "usage: /connect <addr>");
+        assert_eq!(err("whois   "), "usage: /whois <node>");
+    }
+
+    #[test]
+    fn msg_splits_node_from_text() {
+        assert_eq!(parse_command("msg bob  hi there ").unwrap(), Command::Msg("bob".to_string(), "hi there".to_string()));
+        assert_eq!(err("msg bob"), "usage: /msg <node> <text>");
+        assert_eq!(err("msg"), "usage: /msg <node> <text>");
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert_eq!(err("frobnicate now"), "unknown command /frobnicate, try /help");
+    }
+}
@@ -1,69 +1,491 @@
 use axum::{
-    extract::{State, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::{get, post},
+    extract::{Extension, Path, Query, Request, State, WebSocketUpgrade},
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
-use std::sync::{Arc, Mutex};
-use axum::extract::ws::{Message, WebSocket};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
 use futures::{stream::StreamExt, SinkExt};
-use tokio::{task, sync::mpsc};
-use tracing::debug;
+use serde_json::Value;
+use tokio::{net::{TcpListener, TcpStream}, task, sync::{Notify, mpsc, watch}, time};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, warn};
 use tower_http::services::fs::ServeDir;
-use crate::peer_manager::{FrontendEvent, PeerManagerHandle};
+use crate::{auth::{ApiKeys, Scope}, client, gossip, peer_manager::{EventKind, FrontendEvent, PeerManagerHandle, PeerSummary}, protocol::{FrameTooLarge, LineBreak}, send_queue::QueueStats, swim::MemberState};
+
+/// Frames queued for one WebSocket. A subscriber that lets it fill is disconnected.
+const WS_QUEUE: usize = 1024;
+
+/// A WebSocket that asked for events.
+pub struct Subscriber {
+    tx: mpsc::Sender<Message>,
+    /// Told when the socket fell too far behind and is being closed.
+    lagged: Arc<Notify>,
+}
 
 #[derive(Clone)]
 pub struct ApiState {
     pub peer_manager: Arc<PeerManagerHandle>,
-    pub clients: Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>,
+    pub subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    /// None when authentication is turned off with `--no-auth`.
+    pub keys: Option<Arc<ApiKeys>>,
+    /// Where this API was bound, as reported by `/node`.
+    pub api_url: Option<String>,
 }
 
 pub fn router(state: ApiState, web_api_rx: mpsc::Receiver<FrontendEvent>) -> Router {
     start_event_forwarder(state.clone(), web_api_rx);
 
     Router::new()
-        .route("/peers", get(get_peers))
+        .route("/node", get(get_node))
+        .route("/peers", get(get_peers).post(connect_peer))
+        .route("/peers/{node_id}", get(get_peer).delete(disconnect_peer))
         .route("/send", post(send_message))
+        .route("/dht/{node_id}", get(dht_lookup))
+        .route("/membership", get(get_membership))
+        .route("/stats", get(get_stats))
+        .route("/bans", get(get_bans).delete(clear_bans))
+        .route("/bans/{key}", delete(remove_ban))
+        .route("/bans/reload", post(reload_bans))
         .route("/ws", get(ws_handler))
+        // Only the routes above; the static frontend stays reachable so it can ask for a token.
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
         .with_state(state)
         .fallback_service(ServeDir::new("frontend"))
 }
 
+/// Serves `router` until `shutdown` flips to true, over TLS when an acceptor is given.
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    tls: Option<TlsAcceptor>,
+    handshake_timeout: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    debug!("Web API listening on {}", listener.local_addr()?);
+    let stop = async move { let _ = shutdown.wait_for(|stop| *stop).await; };
+    match tls {
+        Some(acceptor) => {
+            let listener = TlsListener::new(listener, acceptor, handshake_timeout)?;
+            axum::serve(listener, router).with_graceful_shutdown(stop).await?
+        }
+        None => axum::serve(listener, router).with_graceful_shutdown(stop).await?,
+    }
+    Ok(())
+}
+
+/// Hands axum the connections that finished a TLS handshake. Handshakes run in their own
+/// tasks so a client that stalls one doesn't hold up the others.
+struct TlsListener {
+    conns: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    fn new(listener: TcpListener, acceptor: TlsAcceptor, handshake_timeout: Duration) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, conns) = mpsc::channel(64);
+        // Stops accepting once axum drops the listener.
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            warn!("API accept failed: {}", e);
+                            time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => break,
+                };
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => { let _ = tx.send((stream, addr)).await; }
+                        Ok(Err(e)) => debug!("API TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => debug!("API TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(Self { conns, local_addr })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.conns.recv().await {
+            Some(conn) => conn,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// The request's token: a bearer `Authorization` header, or a `token` query parameter on `/ws`
+/// since browsers can't set headers on a WebSocket upgrade.
+fn token(req: &Request) -> Option<String> {
+    let bearer = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    if bearer.is_some() || req.uri().path() != "/ws" {
+        return bearer;
+    }
+    Query::<HashMap<String, String>>::try_from_uri(req.uri()).ok()
+        .and_then(|Query(mut query)| query.remove("token"))
+}
+
+/// Reads need `Read`, everything else `Full`. A DHT lookup is a GET but dials the contacts it
+/// learns about, so a read-only token must not be able to make the node connect anywhere.
+fn required_scope(method: &Method, path: &str) -> Scope {
+    let read_only = (method == Method::GET || method == Method::HEAD) && !path.starts_with("/dht/");
+    if read_only { Scope::Read } else { Scope::Full }
+}
+
+/// Lets reads through with a read token and everything else only with a full one.
+/// The granted scope is passed on to handlers as an extension.
+async fn authorize(State(state): State<ApiState>, mut req: Request, next: Next) -> Response {
+    let scope = match &state.keys {
+        None => Scope::Full,
+        Some(keys) => match token(&req).and_then(|token| keys.scope_of(&token)) {
+            Some(scope) => scope,
+            None => return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], "missing or unknown API token").into_response(),
+        },
+    };
+    if scope < required_scope(req.method(), req.uri().path()) {
+        return (StatusCode::FORBIDDEN, "this API token is read-only").into_response();
+    }
+    req.extensions_mut().insert(scope);
+    next.run(req).await
+}
+
+#[derive(serde::Serialize)]
+pub struct PeerView {
+    #[serde(flatten)]
+    summary: PeerSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<MemberState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incarnation: Option<u64>,
+    /// Outbound queue of a connected peer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue: Option<QueueStats>,
+}
+
+/// This node and the addresses its listeners are bound to, which `--port 0` leaves to the OS.
+#[derive(serde::Serialize)]
+pub struct NodeView {
+    node_id: Option<String>,
+    uname: Option<String>,
+    peer_addr: Option<String>,
+    peer_tls: bool,
+    /// None when the API is turned off.
+    api_url: Option<String>,
+}
+
+pub fn node_view(pm: &PeerManagerHandle, api_url: Option<String>) -> NodeView {
+    let me = pm.self_summary();
+    NodeView { node_id: me.node_id, uname: me.uname, peer_addr: me.listen_addr, peer_tls: pm.tls_enabled(), api_url }
+}
+
+/// Connected peers, plus the failure detector's view of every known member when SWIM runs.
+pub fn peer_views(pm: &PeerManagerHandle) -> Vec<PeerView> {
+    let peers = pm.get_peers();
+    let mut queues = pm.queue_stats();
+    let mut queue_of = |summary: &PeerSummary| summary.node_id.as_ref().and_then(|id| queues.remove(id));
+    let Some(swim) = &pm.swim else {
+        return peers.into_iter()
+            .map(|summary| PeerView { queue: queue_of(&summary), summary, state: None, incarnation: None })
+            .collect();
+    };
+
+    let mut members = swim.members();
+    let mut views: Vec<PeerView> = peers.into_iter().map(|summary| {
+        let member = summary.node_id.as_ref()
+            .and_then(|id| members.iter().position(|m| &m.node_id == id))
+            .map(|i| members.swap_remove(i));
+        PeerView {
+            queue: queue_of(&summary),
+            summary,
+            state: member.as_ref().map(|m| m.state),
+            incarnation: member.map(|m| m.incarnation),
+        }
+    }).collect();
+
+    views.extend(members.into_iter().map(|m| PeerView {
+        summary: PeerSummary {
+            remote_addr: None,
+            listen_addr: m.listen_addr,
+            node_id: Some(m.node_id),
+            uname: None,
+        },
+        state: Some(m.state),
+        incarnation: Some(m.incarnation),
+        queue: None,
+    }));
+    views
+}
+
 async fn get_peers(State(state): State<ApiState>) -> impl IntoResponse {
-    let peers = state.peer_manager.get_peers().await;
-    Json(peers)
+    Json(peer_views(&state.peer_manager))
+}
+
+#[derive(serde::Deserialize)]
+struct ConnectPayload {
+    addr: String,
+}
+
+/// Dials `addr` and answers with the peer's node id once it has sent JOIN.
+async fn connect_peer(
+    State(state): State<ApiState>,
+    Json(payload): Json<ConnectPayload>,
+) -> impl IntoResponse {
+    match client::connect_node(state.peer_manager, payload.addr).await {
+        Ok(node_id) => Json(serde_json::json!({ "node_id": node_id })).into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    }
+}
+
+/// Everything known about one connected peer.
+#[derive(serde::Serialize)]
+struct PeerDetail {
+    #[serde(flatten)]
+    summary: PeerSummary,
+    outbound: bool,
+    ip: Option<IpAddr>,
+    connected_secs: u64,
+    score: i64,
+    queue: QueueStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<MemberState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incarnation: Option<u64>,
+}
+
+async fn get_peer(
+    State(state): State<ApiState>,
+    Path(node_id): Path<String>,
+) -> impl IntoResponse {
+    let pm = &state.peer_manager;
+    let Some(conn) = pm.peer_conn(node_id.clone()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let member = pm.swim.as_ref().and_then(|swim| swim.member(&node_id));
+    Json(PeerDetail {
+        summary: conn.summary,
+        outbound: conn.outbound,
+        ip: conn.ip,
+        connected_secs: conn.connected_for.as_secs(),
+        score: conn.score,
+        queue: conn.queue,
+        state: member.as_ref().map(|m| m.state),
+        incarnation: member.map(|m| m.incarnation),
+    }).into_response()
+}
+
+#[derive(serde::Deserialize)]
+struct DisconnectQuery {
+    /// Also ban the peer's node id and IP for the configured ban duration.
+    #[serde(default)]
+    ban: bool,
+    reason: Option<String>,
+}
+
+async fn disconnect_peer(
+    State(state): State<ApiState>,
+    Path(node_id): Path<String>,
+    Query(query): Query<DisconnectQuery>,
+) -> impl IntoResponse {
+    let pm = &state.peer_manager;
+    let Some(conn) = pm.peer_conn(node_id.clone()) else {
+        return StatusCode::NOT_FOUND;
+    };
+    if query.ban {
+        let reason = query.reason.as_deref().unwrap_or("banned through the API");
+        let ban = pm.bans.lock().unwrap().ban(Some(node_id.clone()), conn.ip, reason);
+        if let Some(ban) = ban {
+            pm.emit(EventKind::PeerBanned(ban));
+        }
+    }
+    pm.remove_node(node_id).await;
+    StatusCode::NO_CONTENT
 }
 
 #[derive(serde::Deserialize)]
 struct SendPayload {
     msg: String,
+    /// Node id to send the message to privately instead of to everyone.
+    to: Option<String>,
 }
 
 async fn send_message(
     State(state): State<ApiState>,
     Json(payload): Json<SendPayload>,
 ) -> impl IntoResponse {
-    state
-        .peer_manager
-        .broadcast(format!("MSG|{}\n", payload.msg))
-        .await;
-    "sent"
+    let pm = &state.peer_manager;
+    let sent = match payload.to {
+        Some(to) if pm.get_peer(to.clone()).is_none() => return (StatusCode::NOT_FOUND, format!("{} is not connected", to)).into_response(),
+        Some(to) => gossip::send_direct(pm, to, &payload.msg).await,
+        None => gossip::send_chat(pm, &payload.msg).await,
+    };
+    match sent {
+        Ok(()) => "sent".into_response(),
+        Err(e) if e.is::<FrameTooLarge>() => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()).into_response(),
+        Err(e) if e.is::<LineBreak>() => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn get_membership(State(state): State<ApiState>) -> impl IntoResponse {
+    match state.peer_manager.overlay.membership() {
+        Some(membership) => Json(membership.views()).into_response(),
+        None => (StatusCode::NOT_FOUND, "HyParView overlay is not enabled").into_response(),
+    }
+}
+
+async fn get_node(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(node_view(&state.peer_manager, state.api_url.clone()))
+}
+
+async fn get_stats(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(state.peer_manager.stats.snapshot())
+}
+
+async fn get_bans(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(state.peer_manager.bans.lock().unwrap().list())
+}
+
+async fn clear_bans(State(state): State<ApiState>) -> impl IntoResponse {
+    state.peer_manager.bans.lock().unwrap().clear();
+    StatusCode::NO_CONTENT
+}
+
+/// Re-reads the ban list file, e.g. after editing it by hand, and drops newly banned peers.
+async fn reload_bans(State(state): State<ApiState>) -> impl IntoResponse {
+    let pm = &state.peer_manager;
+    let loaded = pm.bans.lock().unwrap().reload();
+    let bans = match loaded {
+        Ok(bans) => bans,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+    };
+    for node_id in pm.get_peers().into_iter().filter_map(|p| p.node_id) {
+        if pm.bans.lock().unwrap().is_banned_node(&node_id) {
+            pm.remove_node(node_id).await;
+        }
+    }
+    Json(serde_json::json!({ "bans": bans })).into_response()
+}
+
+/// Lifts bans by node id or IP.
+async fn remove_ban(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    match state.peer_manager.bans.lock().unwrap().unban(&key) {
+        0 => StatusCode::NOT_FOUND,
+        _ => StatusCode::NO_CONTENT,
+    }
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<ApiState>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+async fn dht_lookup(
+    State(state): State<ApiState>,
+    Path(node_id): Path<String>,
+) -> impl IntoResponse {
+    let Some(dht) = state.peer_manager.overlay.dht() else {
+        return (StatusCode::NOT_FOUND, "DHT overlay is not enabled").into_response();
+    };
+
+    match dht.lookup(state.peer_manager.clone(), &node_id).await {
+        Ok(Some(contact)) => Json(contact).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "node not found").into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<ApiState>, Extension(scope): Extension<Scope>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state, scope))
+}
+
+/// A request frame on `/ws`. `id` is echoed in the response so clients can match them up.
+#[derive(serde::Deserialize)]
+struct WsRequest {
+    id: Option<Value>,
+    #[serde(flatten)]
+    command: WsCommand,
 }
 
-async fn handle_socket(socket: WebSocket, state: ApiState) {
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsCommand {
+    /// Chat to every peer.
+    Send { msg: String },
+    /// Chat privately to one connected peer.
+    Dm { to: String, msg: String },
+    Peers,
+    /// Dial a peer; answered once it has sent JOIN.
+    Connect { addr: String },
+    /// Start receiving `event` frames.
+    Subscribe,
+    Unsubscribe,
+}
+
+impl WsCommand {
+    fn scope(&self) -> Scope {
+        match self {
+            WsCommand::Send { .. } | WsCommand::Dm { .. } | WsCommand::Connect { .. } => Scope::Full,
+            WsCommand::Peers | WsCommand::Subscribe | WsCommand::Unsubscribe => Scope::Read,
+        }
+    }
+}
+
+/// Everything the server sends on `/ws`, told apart by `type`.
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsFrame<'a> {
+    Response { id: Value, result: Value },
+    /// A failed request; `id` is null when the frame couldn't be parsed.
+    Error { id: Value, error: String },
+    Event { event: &'a FrontendEvent },
+}
+
+impl WsFrame<'_> {
+    fn message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap_or_default().into())
+    }
+}
+
+/// Answers requests until the client goes away. A read-only client can list peers and
+/// subscribe to events but can't chat or dial.
+async fn handle_socket(socket: WebSocket, state: ApiState, scope: Scope) {
     debug!("WebSocket connected");
 
     let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
-    let tx_c = tx.clone();
-    state.clients.lock().unwrap().push(tx.clone());
+    let (tx, mut rx) = mpsc::channel::<Message>(WS_QUEUE);
+    let lagged = Arc::new(Notify::new());
 
+    let send_lagged = lagged.clone();
     let send_task = task::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = send_lagged.notified() => {
+                    let reason = format!("fell {} frames behind", WS_QUEUE);
+                    let _ = sender.send(Message::Close(Some(CloseFrame { code: close_code::POLICY, reason: reason.into() }))).await;
+                    None
+                }
+            };
+            let Some(msg) = msg else { break };
             if sender.send(msg).await.is_err() {
                 break;
             }
@@ -71,20 +493,30 @@ async fn handle_socket(socket: WebSocket, state: ApiState) {
     });
 
     let recv_state = state.clone();
+    let recv_tx = tx.clone();
     let recv_task = task::spawn(async move {
         while let Some(Ok(msg)) = receiver.next().await {
-            if let Message::Text(text) = msg {
-                if text.starts_with("/peers") {
-                    let peers = recv_state.peer_manager.get_peers().await;
-                    let json = serde_json::to_string(&peers).unwrap();
-                    let _ = tx.send(Message::Text(json.into())); 
-                } else {
-                    recv_state
-                        .peer_manager
-                        .broadcast(format!("MSG|{}\n", text))
-                        .await;
+            let Message::Text(text) = msg else { continue };
+            let req = match serde_json::from_str::<WsRequest>(&text) {
+                Ok(req) => req,
+                Err(e) => {
+                    let id = serde_json::from_str::<Value>(&text).ok()
+                        .and_then(|req| req.get("id").cloned())
+                        .unwrap_or_default();
+                    let _ = recv_tx.send(WsFrame::Error { id, error: format!("invalid request: {}", e) }.message()).await;
+                    continue;
                 }
-            }
+            };
+            // A dial can take a while; later requests on the socket don't wait for it.
+            let (state, tx, lagged) = (recv_state.clone(), recv_tx.clone(), lagged.clone());
+            task::spawn(async move {
+                let id = req.id.unwrap_or_default();
+                let frame = match ws_call(req.command, scope, &state, &tx, &lagged).await {
+                    Ok(result) => WsFrame::Response { id, result },
+                    Err(e) => WsFrame::Error { id, error: format!("{:#}", e) },
+                };
+                let _ = tx.send(frame.message()).await;
+            });
         }
     });
 
@@ -93,22 +525,293 @@ async fn handle_socket(socket: WebSocket, state: ApiState) {
         _ = recv_task => (),
     }
 
-    let mut clients = state.clients.lock().unwrap();
-    clients.retain(|c| !c.same_channel(&tx_c));
+    state.subscribers.lock().unwrap().retain(|sub| !sub.tx.same_channel(&tx));
     debug!("WebSocket disconnected");
 }
 
+async fn ws_call(command: WsCommand, scope: Scope, state: &ApiState, tx: &mpsc::Sender<Message>, lagged: &Arc<Notify>) -> anyhow::Result<Value> {
+    if scope < command.scope() {
+        anyhow::bail!("this API token is read-only");
+    }
+    let pm = &state.peer_manager;
+    match command {
+        WsCommand::Send { msg } => gossip::send_chat(pm, &msg).await?,
+        WsCommand::Dm { to, msg } => {
+            if pm.get_peer(to.clone()).is_none() {
+                anyhow::bail!("{} is not connected", to);
+            }
+            gossip::send_direct(pm, to, &msg).await?
+        }
+        WsCommand::Peers => return Ok(serde_json::to_value(peer_views(pm))?),
+        WsCommand::Connect { addr } => {
+            let node_id = client::connect_node(pm.clone(), addr).await?;
+            return Ok(serde_json::json!({ "node_id": node_id }));
+        }
+        WsCommand::Subscribe => {
+            let mut subs = state.subscribers.lock().unwrap();
+            if !subs.iter().any(|sub| sub.tx.same_channel(tx)) {
+                subs.push(Subscriber { tx: tx.clone(), lagged: lagged.clone() });
+            }
+        }
+        WsCommand::Unsubscribe => state.subscribers.lock().unwrap().retain(|sub| !sub.tx.same_channel(tx)),
+    }
+    Ok(Value::Null)
+}
+
 fn start_event_forwarder(state: ApiState, mut web_api_rx: mpsc::Receiver<FrontendEvent>) {
     tokio::spawn(async move {
         while let Some(event) = web_api_rx.recv().await {
-            println!("{:?}", serde_json::to_string(&event));
-            let msg = match serde_json::to_string(&event) {
-                Ok(json) => Message::Text(json.into()),
-                Err(_) => continue,
-            };
-            let mut clients = state.clients.lock().unwrap();
-            clients.retain(|client| client.send(msg.clone()).is_ok());
+            let msg = WsFrame::Event { event: &event }.message();
+            debug!("Frontend event {}", msg.to_text().unwrap_or_default());
+            let mut subscribers = state.subscribers.lock().unwrap();
+            subscribers.retain(|sub| match sub.tx.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("WebSocket fell {} frames behind, disconnecting it", WS_QUEUE);
+                    sub.lagged.notify_one();
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
         }
     });
 }
 
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::peer_manager::tests::{HIGH, LOW, Raw, attach, node, remove_bans_file, until};
+
+    /// A node with its API on an ephemeral port.
+    struct Api {
+        pm: Arc<PeerManagerHandle>,
+        base: String,
+        client: reqwest::Client,
+        _shutdown: watch::Sender<bool>,
+    }
+
+    impl Api {
+        async fn start(keys: Option<ApiKeys>) -> Self {
+            let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+            let (pm, events) = node(LOW);
+            let state = ApiState { peer_manager: pm.clone(), subscribers: Arc::default(), keys: keys.map(Arc::new), api_url: None };
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let (shutdown, shutdown_rx) = watch::channel(false);
+            tokio::spawn(serve(listener, router(state, events), None, Duration::from_secs(1), shutdown_rx));
+            Self { pm, base, client: reqwest::Client::new(), _shutdown: shutdown }
+        }
+
+        fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+            let method = reqwest::Method::from_bytes(method.as_str().as_bytes()).unwrap();
+            self.client.request(method, format!("{}{}", self.base, path))
+        }
+
+        async fn status(&self, req: reqwest::RequestBuilder) -> u16 {
+            req.send().await.unwrap().status().as_u16()
+        }
+
+        /// Registers a peer with node id HIGH over an in-memory stream.
+        async fn peer(&self) -> Raw {
+            let (ours, theirs) = duplex(64 * 1024);
+            attach(&self.pm, ours, false).await;
+            let mut raw = Raw::new(theirs);
+            raw.join(HIGH).await;
+            until(|| self.pm.get_peer(HIGH.to_string()).is_some()).await;
+            raw
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_endpoints_find_and_drop_connected_peers() {
+        let api = Api::start(None).await;
+        assert_eq!(api.status(api.request(Method::GET, &format!("/peers/{}", HIGH))).await, 404);
+        assert_eq!(api.status(api.request(Method::DELETE, &format!("/peers/{}", HIGH))).await, 404);
+
+        let mut raw = api.peer().await;
+        let peer: Value = api.request(Method::GET, &format!("/peers/{}", HIGH)).send().await.unwrap().json().await.unwrap();
+        assert_eq!(peer["node_id"], HIGH);
+        assert_eq!(peer["outbound"], false);
+
+        assert_eq!(api.status(api.request(Method::DELETE, &format!("/peers/{}", HIGH))).await, 204);
+        assert!(raw.at_eof().await);
+        until(|| api.pm.get_peer(HIGH.to_string()).is_none()).await;
+        assert!(!api.pm.bans.lock().unwrap().is_banned_node(HIGH));
+    }
+
+    #[tokio::test]
+    async fn delete_with_ban_bans_the_peer() {
+        let api = Api::start(None).await;
+        let mut raw = api.peer().await;
+        let req = api.request(Method::DELETE, &format!("/peers/{}?ban=true&reason=spam", HIGH));
+        assert_eq!(api.status(req).await, 204);
+        assert!(raw.at_eof().await);
+
+        let bans: Value = api.request(Method::GET, "/bans").send().await.unwrap().json().await.unwrap();
+        assert_eq!(bans[0]["node_id"], HIGH);
+        assert_eq!(bans[0]["reason"], "spam");
+        assert_eq!(api.status(api.request(Method::DELETE, &format!("/bans/{}", HIGH))).await, 204);
+        assert_eq!(api.status(api.request(Method::DELETE, &format!("/bans/{}", HIGH))).await, 404);
+        remove_bans_file(&api.pm).await;
+    }
+
+    #[tokio::test]
+    async fn failed_dial_is_a_bad_gateway() {
+        let api = Api::start(None).await;
+        // Nothing listens on a port we just bound and released.
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let req = api.request(Method::POST, "/peers").json(&serde_json::json!({ "addr": addr.to_string() }));
+        assert_eq!(api.status(req).await, 502);
+    }
+
+    #[tokio::test]
+    async fn send_refuses_frames_peers_would_reject() {
+        let api = Api::start(None).await;
+        let send = |msg: String| api.request(Method::POST, "/send").json(&serde_json::json!({ "msg": msg }));
+        assert_eq!(api.status(send("x".repeat(64 * 1024))).await, 413);
+        assert_eq!(api.status(send("hi\nLEAVE|".to_string())).await, 400);
+        assert_eq!(api.status(send("hi\r".to_string())).await, 400);
+        assert_eq!(api.status(send("hi".to_string())).await, 200);
+    }
+
+    fn keys() -> ApiKeys {
+        ApiKeys { read: "read-token".to_string(), full: "full-token".to_string() }
+    }
+
+    #[tokio::test]
+    async fn tokens_are_required() {
+        let api = Api::start(Some(keys())).await;
+        assert_eq!(api.status(api.request(Method::GET, "/peers")).await, 401);
+        assert_eq!(api.status(api.request(Method::GET, "/peers").bearer_auth("wrong")).await, 401);
+        assert_eq!(api.status(api.request(Method::GET, "/peers").header("Authorization", "full-token")).await, 401);
+        // Only /ws takes the token as a query parameter.
+        assert_eq!(api.status(api.request(Method::GET, "/peers?token=full-token")).await, 401);
+        assert_eq!(api.status(api.request(Method::GET, "/peers").bearer_auth("full-token")).await, 200);
+    }
+
+    #[tokio::test]
+    async fn read_tokens_only_read() {
+        let api = Api::start(Some(keys())).await;
+        let read = |method: Method, path: &str| api.request(method, path).bearer_auth("read-token");
+        assert_eq!(api.status(read(Method::GET, "/peers")).await, 200);
+        assert_eq!(api.status(read(Method::GET, "/stats")).await, 200);
+        assert_eq!(api.status(read(Method::GET, "/bans")).await, 200);
+        assert_eq!(api.status(read(Method::POST, "/send").json(&serde_json::json!({ "msg": "hi" }))).await, 403);
+        assert_eq!(api.status(read(Method::DELETE, "/bans")).await, 403);
+        assert_eq!(api.status(read(Method::POST, "/bans/reload")).await, 403);
+        assert_eq!(api.status(read(Method::GET, "/dht/1234")).await, 403);
+
+        let full = |method: Method, path: &str| api.request(method, path).bearer_auth("full-token");
+        assert_eq!(api.status(full(Method::POST, "/send").json(&serde_json::json!({ "msg": "hi" }))).await, 200);
+        assert_eq!(api.status(full(Method::DELETE, "/bans")).await, 204);
+        // Past authorization; the node just has no DHT.
+        assert_eq!(api.status(full(Method::GET, "/dht/1234")).await, 404);
+        remove_bans_file(&api.pm).await;
+    }
+
+    #[tokio::test]
+    async fn no_keys_means_full_access() {
+        let api = Api::start(None).await;
+        assert_eq!(api.status(api.request(Method::DELETE, "/bans")).await, 204);
+        remove_bans_file(&api.pm).await;
+    }
+
+    #[tokio::test]
+    async fn websocket_takes_the_token_from_the_query() {
+        let api = Api::start(Some(keys())).await;
+        let ws = api.base.replacen("http", "ws", 1);
+        assert!(tokio_tungstenite::connect_async(format!("{}/ws", ws)).await.is_err());
+        assert!(tokio_tungstenite::connect_async(format!("{}/ws?token=wrong", ws)).await.is_err());
+        assert!(tokio_tungstenite::connect_async(format!("{}/ws?token=read-token", ws)).await.is_ok());
+    }
+
+    type Ws = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+    async fn ws(api: &Api, token: &str) -> Ws {
+        let url = format!("{}/ws?token={}", api.base.replacen("http", "ws", 1), token);
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    /// Sends `request` and returns the next frame.
+    async fn ws_call(ws: &mut Ws, request: &str) -> Value {
+        use tokio_tungstenite::tungstenite::Message;
+        ws.send(Message::text(request)).await.unwrap();
+        ws_next(ws).await
+    }
+
+    async fn ws_next(ws: &mut Ws) -> Value {
+        use tokio_tungstenite::tungstenite::Message;
+        loop {
+            let msg = time::timeout(Duration::from_secs(2), ws.next()).await.expect("no frame in time").unwrap().unwrap();
+            if let Message::Text(text) = msg {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ws_requests_get_responses_with_their_id() {
+        let api = Api::start(Some(keys())).await;
+        let mut ws = ws(&api, "full-token").await;
+
+        let frame = ws_call(&mut ws, r#"{"type":"peers","id":1}"#).await;
+        assert_eq!(frame, serde_json::json!({ "type": "response", "id": 1, "result": [] }));
+
+        let frame = ws_call(&mut ws, r#"{"type":"send","msg":"hi","id":"a"}"#).await;
+        assert_eq!(frame, serde_json::json!({ "type": "response", "id": "a", "result": null }));
+
+        // No id given, none echoed.
+        let frame = ws_call(&mut ws, r#"{"type":"unsubscribe"}"#).await;
+        assert_eq!(frame, serde_json::json!({ "type": "response", "id": null, "result": null }));
+
+        let frame = ws_call(&mut ws, &format!(r#"{{"type":"dm","to":"{}","msg":"hi","id":2}}"#, HIGH)).await;
+        assert_eq!((&frame["type"], &frame["id"]), (&serde_json::json!("error"), &serde_json::json!(2)));
+        assert!(frame["error"].as_str().unwrap().contains("not connected"));
+
+        let frame = ws_call(&mut ws, r#"{"type":"send","msg":"hi\nLEAVE|","id":3}"#).await;
+        assert_eq!(frame, serde_json::json!({ "type": "error", "id": 3, "error": "message contains a line break" }));
+    }
+
+    #[tokio::test]
+    async fn ws_rejects_malformed_requests() {
+        let api = Api::start(Some(keys())).await;
+        let mut ws = ws(&api, "full-token").await;
+
+        let frame = ws_call(&mut ws, r#"{"type":"reboot","id":3}"#).await;
+        assert_eq!((&frame["type"], &frame["id"]), (&serde_json::json!("error"), &serde_json::json!(3)));
+        assert!(frame["error"].as_str().unwrap().starts_with("invalid request"));
+
+        let frame = ws_call(&mut ws, "not json").await;
+        assert_eq!((&frame["type"], &frame["id"]), (&serde_json::json!("error"), &Value::Null));
+
+        let frame = ws_call(&mut ws, r#"{"type":"send","id":4}"#).await;
+        assert_eq!((&frame["type"], &frame["id"]), (&serde_json::json!("error"), &serde_json::json!(4)));
+    }
+
+    #[tokio::test]
+    async fn ws_read_token_can_subscribe_but_not_send() {
+        let api = Api::start(Some(keys())).await;
+        let mut ws = ws(&api, "read-token").await;
+
+        let frame = ws_call(&mut ws, r#"{"type":"send","msg":"hi","id":1}"#).await;
+        assert_eq!(frame, serde_json::json!({ "type": "error", "id": 1, "error": "this API token is read-only" }));
+        let frame = ws_call(&mut ws, r#"{"type":"connect","addr":"127.0.0.1:1","id":2}"#).await;
+        assert_eq!(frame["error"], "this API token is read-only");
+
+        let frame = ws_call(&mut ws, r#"{"type":"subscribe","id":3}"#).await;
+        assert_eq!(frame["type"], "response");
+        api.pm.emit(EventKind::PeerRenamed { node_id: HIGH.to_string(), old: None, uname: "bob".to_string() });
+        let frame = ws_next(&mut ws).await;
+        assert_eq!(frame["type"], "event");
+        assert_eq!(frame["event"]["kind"], "peer_renamed");
+        assert_eq!(frame["event"]["uname"], "bob");
+    }
+
+    #[test]
+    fn dht_lookups_need_full_scope() {
+        assert_eq!(required_scope(&Method::GET, "/peers"), Scope::Read);
+        assert_eq!(required_scope(&Method::GET, "/dht/1234"), Scope::Full);
+    }
+}